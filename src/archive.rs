@@ -0,0 +1,774 @@
+use crate::attributes;
+use crate::error::ArchiveError;
+use crate::journal;
+use crate::metadata::{self, Compression};
+use crate::package::Package;
+use crate::signature;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use miette::Result;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+/// The bytes identifying a Gany package archive
+pub const MAGIC: &[u8; 8] = b"GANYPKG\0";
+
+/// The version of the package archive layout written by this version of Gany
+pub const FORMAT_VERSION: u16 = 1;
+
+/// The kind of filesystem entry held within a package archive
+#[derive(Eq, PartialEq, Hash, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// A filesystem entry held within a package archive
+#[derive(Eq, PartialEq, Hash, Clone, Debug, Serialize, Deserialize)]
+pub struct FileEntry {
+    /// The path the entry is installed to
+    pub path: PathBuf,
+    /// The kind of filesystem entry
+    pub kind: EntryKind,
+    /// The size of the entry's contents, in bytes
+    #[serde(default)]
+    pub size: u64,
+    /// The SHA3-256 hash of the entry's contents, if it is a file
+    #[serde(default)]
+    pub digest: Option<String>,
+    /// The path a symbolic link points to
+    #[serde(default)]
+    pub target: Option<PathBuf>,
+    /// The permission bits of the entry, if known
+    #[serde(default)]
+    pub mode: Option<u32>,
+    /// The numeric user ID owning the entry, if the package specifies one
+    #[serde(default)]
+    pub uid: Option<u32>,
+    /// The numeric group ID owning the entry, if the package specifies one
+    #[serde(default)]
+    pub gid: Option<u32>,
+}
+
+/// The header of a package archive, describing its contents
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct ArchiveHeader {
+    /// The manifest of the packaged software
+    pub package: Package,
+    /// The filesystem entries held within the archive
+    pub entries: Vec<FileEntry>,
+    /// The SHA3-256 hash of the LZ4-compressed payload
+    pub payload_keccak: String,
+}
+
+/// A package archive, consisting of a header, an optional signature of the header, and an LZ4-compressed tarball
+///
+/// The header is encoded in Gany's metadata format, and holds the hash of the payload; signing the header therefore signs the whole archive.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct PackageArchive {
+    /// The header of the archive
+    pub header: ArchiveHeader,
+    /// The encoded header, as it was signed
+    pub header_data: Vec<u8>,
+    /// The detached signature of the encoded header
+    pub signature: Option<Vec<u8>>,
+    /// The LZ4-compressed tarball holding the packaged files
+    pub payload: Vec<u8>,
+}
+
+/// Computes the SHA3-256 hash of some data, as a hexadecimal string
+///
+/// # Arguments
+///
+/// * `data` - The data to be hashed (required)
+pub fn hash_data(data: &[u8]) -> String {
+    hex::encode(Sha3_256::digest(data))
+}
+
+/// Determines the path an entry of a package is installed to, given its path relative to the package's root
+fn installed_path(relative_path: &Path) -> PathBuf {
+    Path::new("/").join(relative_path)
+}
+
+/// Collects the filesystem entries beneath a directory, in a stable order
+///
+/// # Arguments
+///
+/// * `root` - The directory the packaged files are laid out in (required)
+///
+/// * `directory` - The directory currently being collected (required)
+///
+/// * `entries` - The entries collected so far (required)
+fn collect_entries(root: &Path, directory: &Path, entries: &mut Vec<FileEntry>) -> Result<()> {
+    let mut children: Vec<PathBuf> = fs::read_dir(directory)
+        .map_err(|_| ArchiveError::UnableToReadSource(directory.to_path_buf()))?
+        .filter_map(|child| child.ok().map(|child| child.path()))
+        .collect();
+    children.sort();
+    for child in children {
+        let relative_path = child.strip_prefix(root).unwrap().to_path_buf();
+        let metadata = fs::symlink_metadata(&child)
+            .map_err(|_| ArchiveError::UnableToReadSource(child.clone()))?;
+        let file_type = metadata.file_type();
+        // Ownership on the machine a package is built on means nothing elsewhere, so only the mode is recorded
+        let mode = entry_mode(&metadata);
+        if file_type.is_symlink() {
+            let target = fs::read_link(&child)
+                .map_err(|_| ArchiveError::UnableToReadSource(child.clone()))?;
+            entries.push(FileEntry {
+                path: installed_path(&relative_path),
+                kind: EntryKind::Symlink,
+                size: 0,
+                digest: None,
+                target: Some(target),
+                mode: None,
+                uid: None,
+                gid: None,
+            });
+        } else if file_type.is_dir() {
+            entries.push(FileEntry {
+                path: installed_path(&relative_path),
+                kind: EntryKind::Directory,
+                size: 0,
+                digest: None,
+                target: None,
+                mode,
+                uid: None,
+                gid: None,
+            });
+            collect_entries(root, &child, entries)?;
+        } else {
+            let contents =
+                fs::read(&child).map_err(|_| ArchiveError::UnableToReadSource(child.clone()))?;
+            entries.push(FileEntry {
+                path: installed_path(&relative_path),
+                kind: EntryKind::File,
+                size: contents.len() as u64,
+                digest: Some(hash_data(&contents)),
+                target: None,
+                mode,
+                uid: None,
+                gid: None,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Creates a package archive from a directory of files laid out as they are to be installed
+///
+/// The files owned by the package are determined from the contents of the directory, along with any ghost files in its manifest.
+/// Ghost files are owned by the package but never shipped, so they are left out of the archive even if present in the directory.
+///
+/// # Arguments
+///
+/// * `source` - The directory the packaged files are laid out in (required)
+///
+/// * `package` - The manifest of the packaged software (required)
+///
+/// * `signing_key` - A key to sign the archive with (optional)
+pub fn create_archive(
+    source: &Path,
+    mut package: Package,
+    signing_key: Option<&SigningKey>,
+) -> Result<Vec<u8>> {
+    let mut entries: Vec<FileEntry> = Vec::new();
+    collect_entries(source, source, &mut entries)?;
+    if let Some(ghost_files) = &package.ghost_files {
+        entries.retain(|entry| {
+            !ghost_files
+                .iter()
+                .any(|ghost_file| entry.path.starts_with(ghost_file))
+        });
+    }
+    attributes::check_attributes(&package, &entries)?;
+    package.files = entries.iter().map(|entry| entry.path.clone()).collect();
+    let mut ghost_files: Vec<PathBuf> = package
+        .ghost_files
+        .iter()
+        .flatten()
+        .filter(|ghost_file| !package.files.contains(ghost_file))
+        .cloned()
+        .collect();
+    ghost_files.sort();
+    package.files.extend(ghost_files);
+    package.installed_size = Some(entries.iter().map(|entry| entry.size).sum());
+    // An archive cannot hold its own hash or size, which are recorded by the repository instead
+    package.keccak = None;
+    package.download_size = None;
+
+    let mut tarball = tar::Builder::new(Vec::new());
+    tarball.follow_symlinks(false);
+    // Only the listed entries are appended, so that nothing left out of the header is shipped
+    for entry in &entries {
+        let relative_path = entry.path.strip_prefix("/").unwrap_or(&entry.path);
+        let source_path = source.join(relative_path);
+        tarball
+            .append_path_with_name(&source_path, relative_path)
+            .map_err(|_| ArchiveError::UnableToReadSource(source_path.clone()))?;
+    }
+    let tar_data = tarball
+        .into_inner()
+        .map_err(|_| ArchiveError::UnableToReadSource(source.to_path_buf()))?;
+    let payload = lz4_flex::compress_prepend_size(&tar_data);
+
+    let header = ArchiveHeader {
+        package,
+        entries,
+        payload_keccak: hash_data(&payload),
+    };
+    let header_data = metadata::encode(&header, Compression::None)?;
+    let signature = signing_key.map(|signing_key| signature::sign(&header_data, signing_key));
+
+    let mut archive_data = Vec::new();
+    archive_data.extend_from_slice(MAGIC);
+    archive_data.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    archive_data.extend_from_slice(&(header_data.len() as u32).to_le_bytes());
+    archive_data.extend_from_slice(&header_data);
+    let signature = signature.unwrap_or_default();
+    archive_data.extend_from_slice(&(signature.len() as u16).to_le_bytes());
+    archive_data.extend_from_slice(&signature);
+    archive_data.extend_from_slice(&payload);
+    Ok(archive_data)
+}
+
+/// Packages a directory of files, writing the package archive to another directory
+///
+/// # Arguments
+///
+/// * `source` - The directory the packaged files are laid out in (required)
+///
+/// * `manifest_path` - The path to a YAML manifest of the packaged software (required)
+///
+/// * `output` - The directory to write the package archive to (required)
+///
+/// * `signing_key` - The path to a key to sign the archive with (optional)
+pub fn package_directory(
+    source: &Path,
+    manifest_path: &Path,
+    output: &Path,
+    signing_key: Option<&Path>,
+) -> Result<PathBuf> {
+    let manifest = fs::read(manifest_path)
+        .map_err(|_| ArchiveError::UnableToReadManifest(manifest_path.to_path_buf()))?;
+    let package: Package = serde_yaml::from_slice(&manifest)
+        .map_err(|_| ArchiveError::UnableToReadManifest(manifest_path.to_path_buf()))?;
+    let signing_key = match signing_key {
+        Some(signing_key) => Some(signature::read_signing_key(signing_key)?),
+        None => None,
+    };
+    let archive_path = output.join(package.archive_name());
+    let archive_data = create_archive(source, package, signing_key.as_ref())?;
+    fs::write(&archive_path, archive_data)
+        .map_err(|_| ArchiveError::UnableToWriteArchive(archive_path.clone()))?;
+    Ok(archive_path)
+}
+
+/// Takes a number of bytes from the front of some data, advancing past them
+fn take<'a>(data: &mut &'a [u8], length: usize) -> Result<&'a [u8]> {
+    if data.len() < length {
+        return Err(ArchiveError::Truncated.into());
+    }
+    let (taken, rest) = data.split_at(length);
+    *data = rest;
+    Ok(taken)
+}
+
+/// Reads a package archive
+///
+/// # Arguments
+///
+/// * `archive_data` - The contents of a package archive (required)
+pub fn read_archive(archive_data: &[u8]) -> Result<PackageArchive> {
+    let mut data = archive_data;
+    if take(&mut data, MAGIC.len())? != MAGIC {
+        return Err(ArchiveError::NotAnArchive.into());
+    }
+    let format_version = u16::from_le_bytes(take(&mut data, 2)?.try_into().unwrap());
+    if format_version > FORMAT_VERSION {
+        return Err(ArchiveError::UnsupportedFormatVersion(format_version).into());
+    }
+    let header_length = u32::from_le_bytes(take(&mut data, 4)?.try_into().unwrap()) as usize;
+    let header_data = take(&mut data, header_length)?.to_vec();
+    let header: ArchiveHeader =
+        metadata::decode(&header_data).map_err(|_| ArchiveError::UnableToDeserialiseManifest)?;
+    let signature_length = u16::from_le_bytes(take(&mut data, 2)?.try_into().unwrap()) as usize;
+    let signature = take(&mut data, signature_length)?.to_vec();
+    Ok(PackageArchive {
+        header,
+        header_data,
+        signature: if signature.is_empty() {
+            None
+        } else {
+            Some(signature)
+        },
+        payload: data.to_vec(),
+    })
+}
+
+/// Reads a package archive on the filesystem
+///
+/// # Arguments
+///
+/// * `path` - The path to a package archive (required)
+pub fn read_archive_file(path: &Path) -> Result<PackageArchive> {
+    let archive_data =
+        fs::read(path).map_err(|_| ArchiveError::UnableToReadArchive(path.to_path_buf()))?;
+    read_archive(&archive_data)
+}
+
+/// Reads the package manifest held within a package archive
+///
+/// # Arguments
+///
+/// * `archive_data` - The contents of a package archive (required)
+pub fn read_package(archive_data: &[u8]) -> Result<Package> {
+    Ok(read_archive(archive_data)?.header.package)
+}
+
+/// Reads the package manifest held within a package archive on the filesystem
+///
+/// # Arguments
+///
+/// * `path` - The path to a package archive (required)
+pub fn read_package_file(path: &Path) -> Result<Package> {
+    Ok(read_archive_file(path)?.header.package)
+}
+
+/// Verifies the integrity of a package archive, and that it was signed by a trusted key
+///
+/// # Arguments
+///
+/// * `archive` - The package archive to be verified (required)
+///
+/// * `trusted_keys` - The public keys trusted to sign packages; if empty, a signature is not required (required)
+pub fn verify_archive(archive: &PackageArchive, trusted_keys: &[VerifyingKey]) -> Result<()> {
+    if hash_data(&archive.payload) != archive.header.payload_keccak {
+        return Err(ArchiveError::PayloadHashMismatch.into());
+    }
+    if !trusted_keys.is_empty() {
+        let signature = archive
+            .signature
+            .as_ref()
+            .ok_or(ArchiveError::MissingSignature)?;
+        signature::verify(&archive.header_data, signature, trusted_keys)?;
+    }
+    Ok(())
+}
+
+/// Extracts the files held within a package archive, verifying each against the archive's header
+///
+/// Only the modes of the files are kept; the ownership, capabilities and extended attributes the package specifies are applied when it is installed.
+///
+/// # Arguments
+///
+/// * `archive` - The package archive to be extracted (required)
+///
+/// * `root` - The directory to extract the files into (required)
+pub fn extract_archive(archive: &PackageArchive, root: &Path) -> Result<()> {
+    unpack_archive(archive, root, None, None)
+}
+
+/// Stages the files held within a package archive beside the paths they are installed to, verifying each against the archive's header
+///
+/// Directories are created in place, while every other entry is written to its staged path, to be renamed into place once its transaction is committed.
+/// Each staged entry is given the ownership, mode, capabilities and extended attributes the package specifies for it.
+/// Directories are given theirs when the transaction is committed, as they may already exist and be shared with the rest of the system.
+///
+/// # Arguments
+///
+/// * `archive` - The package archive to be staged (required)
+///
+/// * `root` - The directory packages are installed into (required)
+///
+/// * `redirects` - Paths to install entries to instead of their own, by the paths of the entries (required)
+///
+/// * `entries` - The entries of the archive, with their attributes resolved for this system (required)
+pub fn stage_archive(
+    archive: &PackageArchive,
+    root: &Path,
+    redirects: &HashMap<PathBuf, PathBuf>,
+    entries: &[FileEntry],
+) -> Result<()> {
+    unpack_archive(archive, root, Some(redirects), Some(entries))
+}
+
+/// Ensures that no entry of a package archive lies beneath a symbolic link held in the same archive
+///
+/// Such an entry would be written wherever the link points once the link was unpacked, escaping the directory being unpacked into.
+///
+/// # Arguments
+///
+/// * `entries` - The entries listed in the archive's header (required)
+fn check_entry_paths(entries: &[FileEntry]) -> Result<()> {
+    let symlink_paths: HashSet<&Path> = entries
+        .iter()
+        .filter(|entry| entry.kind == EntryKind::Symlink)
+        .map(|entry| entry.path.as_path())
+        .collect();
+    for entry in entries {
+        if entry
+            .path
+            .ancestors()
+            .skip(1)
+            .any(|ancestor| symlink_paths.contains(ancestor))
+        {
+            return Err(ArchiveError::UnsafePath(entry.path.clone()).into());
+        }
+    }
+    Ok(())
+}
+
+/// Determines whether an entry of a tarball is the kind of filesystem entry its archive's header lists it as
+///
+/// # Arguments
+///
+/// * `tar_entry` - An entry of the tarball held within a package archive (required)
+///
+/// * `file_entry` - The entry as listed in the archive's header (required)
+fn is_listed_kind<R: Read>(tar_entry: &tar::Entry<R>, file_entry: &FileEntry) -> bool {
+    let entry_type = tar_entry.header().entry_type();
+    match file_entry.kind {
+        EntryKind::File => entry_type.is_file(),
+        EntryKind::Directory => entry_type.is_dir(),
+        EntryKind::Symlink => {
+            entry_type.is_symlink()
+                && tar_entry.link_name().ok().flatten().as_deref() == file_entry.target.as_deref()
+        }
+    }
+}
+
+/// Unpacks the files held within a package archive, verifying each against the archive's header
+///
+/// Every entry listed in the header must be present in the tarball exactly once, and be of the kind it is listed as.
+///
+/// # Arguments
+///
+/// * `archive` - The package archive to be unpacked (required)
+///
+/// * `root` - The directory to unpack the files into (required)
+///
+/// * `redirects` - If entries other than directories are staged, the paths to install entries to instead of their own (optional)
+///
+/// * `resolved_entries` - The entries of the archive with their attributes resolved, if those attributes are applied (optional)
+fn unpack_archive(
+    archive: &PackageArchive,
+    root: &Path,
+    redirects: Option<&HashMap<PathBuf, PathBuf>>,
+    resolved_entries: Option<&[FileEntry]>,
+) -> Result<()> {
+    if hash_data(&archive.payload) != archive.header.payload_keccak {
+        return Err(ArchiveError::PayloadHashMismatch.into());
+    }
+    check_entry_paths(&archive.header.entries)?;
+    let tar_data = lz4_flex::decompress_size_prepended(&archive.payload)
+        .map_err(|_| ArchiveError::UnableToDecompressArchive)?;
+    let mut tarball = tar::Archive::new(tar_data.as_slice());
+    tarball.set_preserve_permissions(true);
+    let tar_entries = tarball
+        .entries()
+        .map_err(|_| ArchiveError::UnableToReadArchiveEntries)?;
+    let listed_entries: HashMap<&Path, &FileEntry> = archive
+        .header
+        .entries
+        .iter()
+        .map(|entry| (entry.path.as_path(), entry))
+        .collect();
+    let resolved_entries: Option<HashMap<&Path, &FileEntry>> =
+        resolved_entries.map(|resolved_entries| {
+            resolved_entries
+                .iter()
+                .map(|entry| (entry.path.as_path(), entry))
+                .collect()
+        });
+    let staged = redirects.is_some();
+    let mut unpacked_paths: HashSet<PathBuf> = HashSet::new();
+    for tar_entry in tar_entries {
+        let mut tar_entry = tar_entry.map_err(|_| ArchiveError::UnableToReadArchiveEntries)?;
+        let relative_path = tar_entry
+            .path()
+            .map_err(|_| ArchiveError::UnableToReadArchiveEntries)?
+            .into_owned();
+        // Refuse entries that would escape the root, rather than silently skipping them
+        if relative_path
+            .components()
+            .any(|component| matches!(component, Component::ParentDir | Component::RootDir))
+        {
+            return Err(ArchiveError::UnsafePath(relative_path).into());
+        }
+        let relative_path: PathBuf = relative_path
+            .components()
+            .filter(|component| !matches!(component, Component::CurDir))
+            .collect();
+        if relative_path.as_os_str().is_empty() {
+            continue;
+        }
+        let installed_path = installed_path(&relative_path);
+        let file_entry = *listed_entries
+            .get(installed_path.as_path())
+            .ok_or_else(|| ArchiveError::UnlistedEntry(installed_path.clone()))?;
+        if !unpacked_paths.insert(installed_path.clone()) {
+            return Err(ArchiveError::DuplicateEntry(installed_path).into());
+        }
+        if !is_listed_kind(&tar_entry, file_entry) {
+            return Err(ArchiveError::EntryKindMismatch(installed_path).into());
+        }
+        let resolved_entry = resolved_entries
+            .as_ref()
+            .and_then(|resolved_entries| resolved_entries.get(installed_path.as_path()).copied());
+        let file_attributes = attributes::file_attributes(&archive.header.package, &installed_path);
+        let destination = root.join(&relative_path);
+        if file_entry.kind == EntryKind::Directory {
+            fs::create_dir_all(&destination)
+                .map_err(|_| ArchiveError::UnableToExtract(destination.clone()))?;
+            // Directories may already exist, so staging leaves their attributes to be applied once the transaction is committed
+            if staged {
+                continue;
+            }
+            if let Ok(mode) = tar_entry.header().mode() {
+                set_mode(&destination, mode)?;
+            }
+            if let Some(resolved_entry) = resolved_entry {
+                attributes::apply_attributes(&destination, resolved_entry, file_attributes)?;
+            }
+            continue;
+        }
+        let destination = if let Some(redirects) = redirects {
+            let destination = redirects
+                .get(&installed_path)
+                .map(|redirect| root.join(redirect.strip_prefix("/").unwrap_or(redirect)))
+                .unwrap_or(destination);
+            let staged_path = journal::staged_path(&destination);
+            // Anything left staged by an abandoned transaction is replaced
+            let _ = fs::remove_file(&staged_path);
+            staged_path
+        } else {
+            destination
+        };
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|_| ArchiveError::UnableToExtract(destination.clone()))?;
+        }
+        if let Some(expected_digest) = &file_entry.digest {
+            let mut contents = Vec::new();
+            tar_entry
+                .read_to_end(&mut contents)
+                .map_err(|_| ArchiveError::UnableToReadArchiveEntries)?;
+            if hash_data(&contents) != *expected_digest {
+                return Err(ArchiveError::FileHashMismatch(installed_path).into());
+            }
+            fs::write(&destination, &contents)
+                .map_err(|_| ArchiveError::UnableToExtract(destination.clone()))?;
+            if let Ok(mode) = tar_entry.header().mode() {
+                set_mode(&destination, mode)?;
+            }
+            if let Some(resolved_entry) = resolved_entry {
+                attributes::apply_attributes(&destination, resolved_entry, file_attributes)?;
+            }
+            // Staged files must reach the disk before their transaction is committed
+            if staged {
+                fs::File::open(&destination)
+                    .and_then(|staged_file| staged_file.sync_all())
+                    .map_err(|_| ArchiveError::UnableToExtract(destination.clone()))?;
+            }
+        } else {
+            tar_entry
+                .unpack(&destination)
+                .map_err(|_| ArchiveError::UnableToExtract(destination.clone()))?;
+            if let Some(resolved_entry) = resolved_entry {
+                attributes::apply_attributes(&destination, resolved_entry, file_attributes)?;
+            }
+        }
+    }
+    if let Some(missing_entry) = archive
+        .header
+        .entries
+        .iter()
+        .find(|entry| !unpacked_paths.contains(&entry.path))
+    {
+        return Err(ArchiveError::MissingEntry(missing_entry.path.clone()).into());
+    }
+    Ok(())
+}
+
+/// Determines the permission bits of a filesystem entry
+#[cfg(unix)]
+pub fn entry_mode(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+/// Determines the permission bits of a filesystem entry
+#[cfg(not(unix))]
+pub fn entry_mode(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Sets the permissions of an extracted file
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .map_err(|_| ArchiveError::UnableToExtract(path.to_path_buf()))?;
+    Ok(())
+}
+
+/// Sets the permissions of an extracted file
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds an archive holding a tarball, listing the given entries in its header
+    fn build_archive(tarball: tar::Builder<Vec<u8>>, entries: Vec<FileEntry>) -> PackageArchive {
+        let payload = lz4_flex::compress_prepend_size(&tarball.into_inner().unwrap());
+        let header = ArchiveHeader {
+            package: serde_yaml::from_str(
+                "{arch: x86_64, name: escape, description: '', version: 1.0.0}",
+            )
+            .unwrap(),
+            entries,
+            payload_keccak: hash_data(&payload),
+        };
+        PackageArchive {
+            header_data: metadata::encode(&header, Compression::None).unwrap(),
+            header,
+            signature: None,
+            payload,
+        }
+    }
+
+    fn entry(path: &str, kind: EntryKind, contents: &[u8], target: Option<&str>) -> FileEntry {
+        FileEntry {
+            path: PathBuf::from(path),
+            kind,
+            size: contents.len() as u64,
+            digest: (kind == EntryKind::File).then(|| hash_data(contents)),
+            target: target.map(PathBuf::from),
+            mode: None,
+            uid: None,
+            gid: None,
+        }
+    }
+
+    fn append_file(tarball: &mut tar::Builder<Vec<u8>>, path: &str, contents: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        tarball.append_data(&mut header, path, contents).unwrap();
+    }
+
+    fn append_symlink(tarball: &mut tar::Builder<Vec<u8>>, path: &str, target: &str) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        tarball.append_link(&mut header, path, target).unwrap();
+    }
+
+    fn append_directory(tarball: &mut tar::Builder<Vec<u8>>, path: &str) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        header.set_mode(0o755);
+        tarball.append_data(&mut header, path, &[][..]).unwrap();
+    }
+
+    #[test]
+    fn refuses_entries_beneath_symlinks_in_the_archive() {
+        let outside = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let mut tarball = tar::Builder::new(Vec::new());
+        append_symlink(&mut tarball, "etc", &outside.path().to_string_lossy());
+        append_file(&mut tarball, "etc/passwd", b"escaped");
+        let archive = build_archive(
+            tarball,
+            vec![
+                entry(
+                    "/etc",
+                    EntryKind::Symlink,
+                    b"",
+                    Some(&outside.path().to_string_lossy()),
+                ),
+                entry("/etc/passwd", EntryKind::File, b"escaped", None),
+            ],
+        );
+        assert!(extract_archive(&archive, root.path()).is_err());
+        assert!(!outside.path().join("passwd").exists());
+    }
+
+    #[test]
+    fn refuses_entries_of_another_kind_than_listed() {
+        let root = tempfile::tempdir().unwrap();
+        let mut tarball = tar::Builder::new(Vec::new());
+        append_symlink(&mut tarball, "hello", "/etc/shadow");
+        let archive = build_archive(
+            tarball,
+            vec![entry("/hello", EntryKind::File, b"hello", None)],
+        );
+        assert!(extract_archive(&archive, root.path()).is_err());
+        assert!(fs::symlink_metadata(root.path().join("hello")).is_err());
+    }
+
+    #[test]
+    fn refuses_archives_missing_listed_entries() {
+        let root = tempfile::tempdir().unwrap();
+        let mut tarball = tar::Builder::new(Vec::new());
+        append_file(&mut tarball, "hello", b"hello");
+        let archive = build_archive(
+            tarball,
+            vec![
+                entry("/hello", EntryKind::File, b"hello", None),
+                entry("/goodbye", EntryKind::File, b"goodbye", None),
+            ],
+        );
+        assert!(extract_archive(&archive, root.path()).is_err());
+    }
+
+    #[test]
+    fn extracts_listed_entries() {
+        let root = tempfile::tempdir().unwrap();
+        let mut tarball = tar::Builder::new(Vec::new());
+        append_file(&mut tarball, "hello", b"hello");
+        append_symlink(&mut tarball, "greeting", "hello");
+        let archive = build_archive(
+            tarball,
+            vec![
+                entry("/hello", EntryKind::File, b"hello", None),
+                entry("/greeting", EntryKind::Symlink, b"", Some("hello")),
+            ],
+        );
+        extract_archive(&archive, root.path()).unwrap();
+        assert_eq!(fs::read(root.path().join("greeting")).unwrap(), b"hello");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn staging_leaves_existing_directories_unchanged() {
+        use std::os::unix::fs::PermissionsExt;
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("etc")).unwrap();
+        fs::set_permissions(root.path().join("etc"), fs::Permissions::from_mode(0o700)).unwrap();
+        let mut tarball = tar::Builder::new(Vec::new());
+        append_directory(&mut tarball, "etc");
+        let mut directory = entry("/etc", EntryKind::Directory, b"", None);
+        directory.mode = Some(0o755);
+        let archive = build_archive(tarball, vec![directory.clone()]);
+        stage_archive(&archive, root.path(), &HashMap::new(), &[directory]).unwrap();
+        let mode = fs::metadata(root.path().join("etc"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o7777, 0o700);
+    }
+}
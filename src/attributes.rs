@@ -0,0 +1,485 @@
+use crate::archive::{EntryKind, FileEntry};
+use crate::error::AttributeError;
+use crate::package::Package;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use miette::Result;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// The extended attribute holding the file capabilities of an executable
+pub const CAPABILITY_XATTR: &str = "security.capability";
+
+/// The names of the Linux capabilities, in the order of their numbers
+const CAPABILITY_NAMES: [&str; 41] = [
+    "cap_chown",
+    "cap_dac_override",
+    "cap_dac_read_search",
+    "cap_fowner",
+    "cap_fsetid",
+    "cap_kill",
+    "cap_setgid",
+    "cap_setuid",
+    "cap_setpcap",
+    "cap_linux_immutable",
+    "cap_net_bind_service",
+    "cap_net_broadcast",
+    "cap_net_admin",
+    "cap_net_raw",
+    "cap_ipc_lock",
+    "cap_ipc_owner",
+    "cap_sys_module",
+    "cap_sys_rawio",
+    "cap_sys_chroot",
+    "cap_sys_ptrace",
+    "cap_sys_pacct",
+    "cap_sys_admin",
+    "cap_sys_boot",
+    "cap_sys_nice",
+    "cap_sys_resource",
+    "cap_sys_time",
+    "cap_sys_tty_config",
+    "cap_mknod",
+    "cap_lease",
+    "cap_audit_write",
+    "cap_audit_control",
+    "cap_setfcap",
+    "cap_mac_override",
+    "cap_mac_admin",
+    "cap_syslog",
+    "cap_wake_alarm",
+    "cap_block_suspend",
+    "cap_audit_read",
+    "cap_perfmon",
+    "cap_bpf",
+    "cap_checkpoint_restore",
+];
+
+/// The revision of the file capability layout written by this version of Gany
+const VFS_CAP_REVISION_2: u32 = 0x0200_0000;
+
+/// The flag marking every permitted capability of a file as effective
+const VFS_CAP_FLAGS_EFFECTIVE: u32 = 0x0000_0001;
+
+/// The prefix marking the value of an extended attribute as base64-encoded bytes, as `getfattr -e base64` writes them
+pub const BASE64_PREFIX: &str = "0s";
+
+/// The value of an extended attribute
+///
+/// Values are written as text, or as base64-encoded bytes prefixed with `0s`, so that values which are not text can be given.
+#[derive(Eq, PartialEq, Hash, Clone, Debug, Default)]
+pub struct XattrValue(pub Vec<u8>);
+
+impl Serialize for XattrValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match std::str::from_utf8(&self.0) {
+            Ok(text) if !text.starts_with(BASE64_PREFIX) => serializer.serialize_str(text),
+            _ => {
+                serializer.serialize_str(&format!("{}{}", BASE64_PREFIX, STANDARD.encode(&self.0)))
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for XattrValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        match text.strip_prefix(BASE64_PREFIX) {
+            Some(encoded) => STANDARD
+                .decode(encoded)
+                .map(XattrValue)
+                .map_err(serde::de::Error::custom),
+            None => Ok(XattrValue(text.into_bytes())),
+        }
+    }
+}
+
+/// The metadata a package specifies for one of its files, applied when the file is installed
+#[derive(Eq, PartialEq, Hash, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct FileAttributes {
+    /// The permission bits of the file, including the setuid, setgid and sticky bits
+    #[serde(default)]
+    pub mode: Option<u32>,
+    /// The name of the user owning the file
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// The numeric user ID owning the file, used if the owner does not exist on this system
+    #[serde(default)]
+    pub uid: Option<u32>,
+    /// The name of the group owning the file
+    #[serde(default)]
+    pub group: Option<String>,
+    /// The numeric group ID owning the file, used if the group does not exist on this system
+    #[serde(default)]
+    pub gid: Option<u32>,
+    /// The file capabilities of the file, in the textual form used by `setcap`, such as `cap_net_bind_service=ep`
+    #[serde(default)]
+    pub capabilities: Option<String>,
+    /// Extended attributes of the file, by name
+    #[serde(default)]
+    pub xattrs: Option<BTreeMap<String, XattrValue>>,
+}
+
+/// Encodes file capabilities into the value of the `security.capability` extended attribute
+///
+/// Capabilities are given as whitespace-separated clauses, each a comma-separated list of capability names (or `all`), an operator (`=`, `+` or `-`), and the flags (`e`, `i` and `p`) it applies to.
+///
+/// # Arguments
+///
+/// * `capabilities` - File capabilities in the textual form used by `setcap` (required)
+pub fn encode_capabilities(capabilities: &str) -> Result<Vec<u8>> {
+    let invalid = || AttributeError::InvalidCapabilities(capabilities.to_owned());
+    let mut effective: u64 = 0;
+    let mut permitted: u64 = 0;
+    let mut inheritable: u64 = 0;
+    for clause in capabilities.split_whitespace() {
+        let operator_index = clause.find(['=', '+', '-']).ok_or_else(invalid)?;
+        let (names, flags) = clause.split_at(operator_index);
+        let all_capabilities: u64 = (1 << CAPABILITY_NAMES.len()) - 1;
+        // A clause without names applies to every capability, as it does for `setcap`
+        let mut capability_set: u64 = if names.is_empty() {
+            all_capabilities
+        } else {
+            0
+        };
+        for name in names.split(',').filter(|name| !name.is_empty()) {
+            let name = name.to_lowercase();
+            if name == "all" {
+                capability_set |= all_capabilities;
+                continue;
+            }
+            let number = CAPABILITY_NAMES
+                .iter()
+                .position(|capability_name| *capability_name == name)
+                .ok_or_else(invalid)?;
+            capability_set |= 1 << number;
+        }
+        let mut operator = ' ';
+        for character in flags.chars() {
+            match character {
+                '=' => {
+                    effective &= !capability_set;
+                    permitted &= !capability_set;
+                    inheritable &= !capability_set;
+                    operator = '+';
+                }
+                '+' | '-' => operator = character,
+                'e' | 'i' | 'p' => {
+                    let flag_set = match character {
+                        'e' => &mut effective,
+                        'i' => &mut inheritable,
+                        _ => &mut permitted,
+                    };
+                    match operator {
+                        '+' => *flag_set |= capability_set,
+                        '-' => *flag_set &= !capability_set,
+                        _ => return Err(invalid().into()),
+                    }
+                }
+                _ => return Err(invalid().into()),
+            }
+        }
+    }
+    // Files carry a single effective flag, raising every permitted or inherited capability
+    if effective != 0 && effective != (permitted | inheritable) {
+        return Err(invalid().into());
+    }
+    let magic = if effective != 0 {
+        VFS_CAP_REVISION_2 | VFS_CAP_FLAGS_EFFECTIVE
+    } else {
+        VFS_CAP_REVISION_2
+    };
+    let mut encoded: Vec<u8> = Vec::with_capacity(20);
+    encoded.extend_from_slice(&magic.to_le_bytes());
+    for half in [
+        permitted & 0xffff_ffff,
+        inheritable & 0xffff_ffff,
+        permitted >> 32,
+        inheritable >> 32,
+    ] {
+        encoded.extend_from_slice(&(half as u32).to_le_bytes());
+    }
+    Ok(encoded)
+}
+
+/// Looks up the numeric ID of a user or group in one of the account databases of a system
+///
+/// # Arguments
+///
+/// * `database` - The path to `/etc/passwd` or `/etc/group` (required)
+///
+/// * `name` - The name of the user or group (required)
+fn lookup_id(database: &Path, name: &str) -> Option<u32> {
+    let database = fs::read_to_string(database).ok()?;
+    database.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        if fields.next()? != name {
+            return None;
+        }
+        fields.nth(1)?.parse().ok()
+    })
+}
+
+/// Resolves the owner of a file to a numeric ID
+///
+/// Names are looked up in the account databases of the system packages are installed into, falling back to the numeric ID the package gives.
+///
+/// # Arguments
+///
+/// * `name` - The name of the user or group (optional)
+///
+/// * `fallback` - The numeric ID used if the name does not exist (optional)
+///
+/// * `database` - The path to `/etc/passwd` or `/etc/group` (required)
+fn resolve_id(
+    name: Option<&String>,
+    fallback: Option<u32>,
+    database: &Path,
+) -> Option<Option<u32>> {
+    match name {
+        Some(name) => lookup_id(database, name)
+            .or_else(|| name.parse().ok())
+            .or(fallback)
+            .map(Some),
+        None => Some(fallback),
+    }
+}
+
+/// Checks that the attributes a package specifies are for files it ships, and that their capabilities are valid
+///
+/// # Arguments
+///
+/// * `package` - The package specifying the attributes (required)
+///
+/// * `entries` - The filesystem entries of the package (required)
+pub fn check_attributes(package: &Package, entries: &[FileEntry]) -> Result<()> {
+    for (path, attributes) in package.file_attributes.iter().flatten() {
+        if !entries.iter().any(|entry| entry.path == *path) {
+            return Err(AttributeError::UnknownPath(path.clone()).into());
+        }
+        if let Some(capabilities) = &attributes.capabilities {
+            encode_capabilities(capabilities)?;
+        }
+    }
+    Ok(())
+}
+
+/// Resolves the attributes a package specifies for its files onto their filesystem entries
+///
+/// The modes and ownership given by the package replace those recorded when it was built.
+///
+/// # Arguments
+///
+/// * `package` - The package owning the entries (required)
+///
+/// * `entries` - The filesystem entries of the package (required)
+///
+/// * `root` - The directory packages are installed into (required)
+pub fn resolve_entries(
+    package: &Package,
+    entries: &[FileEntry],
+    root: &Path,
+) -> Result<Vec<FileEntry>> {
+    let file_attributes = match &package.file_attributes {
+        Some(file_attributes) => file_attributes,
+        None => return Ok(entries.to_vec()),
+    };
+    check_attributes(package, entries)?;
+    let passwd = root.join("etc/passwd");
+    let group = root.join("etc/group");
+    let mut resolved_entries: Vec<FileEntry> = entries.to_vec();
+    for entry in &mut resolved_entries {
+        let attributes = match file_attributes.get(&entry.path) {
+            Some(attributes) => attributes,
+            None => continue,
+        };
+        if entry.kind != EntryKind::Symlink && attributes.mode.is_some() {
+            entry.mode = attributes.mode.map(|mode| mode & 0o7777);
+        }
+        entry.uid =
+            resolve_id(attributes.owner.as_ref(), attributes.uid, &passwd).ok_or_else(|| {
+                AttributeError::UnknownUser(
+                    attributes.owner.clone().unwrap_or_default(),
+                    entry.path.clone(),
+                )
+            })?;
+        entry.gid =
+            resolve_id(attributes.group.as_ref(), attributes.gid, &group).ok_or_else(|| {
+                AttributeError::UnknownGroup(
+                    attributes.group.clone().unwrap_or_default(),
+                    entry.path.clone(),
+                )
+            })?;
+    }
+    Ok(resolved_entries)
+}
+
+/// Reads an extended attribute of a file, if it has it
+///
+/// # Arguments
+///
+/// * `path` - The path of the file (required)
+///
+/// * `name` - The name of the extended attribute (required)
+#[cfg(unix)]
+pub fn read_xattr(path: &Path, name: &str) -> Option<Vec<u8>> {
+    xattr::get(path, name).ok().flatten()
+}
+
+/// Reads an extended attribute of a file, if it has it
+#[cfg(not(unix))]
+pub fn read_xattr(_path: &Path, _name: &str) -> Option<Vec<u8>> {
+    None
+}
+
+/// Applies the ownership, mode, extended attributes and capabilities of an installed file
+///
+/// Changing the owner of a file clears its setuid and setgid bits and its capabilities, so they are applied in that order.
+///
+/// # Arguments
+///
+/// * `path` - The path the file was written to (required)
+///
+/// * `entry` - The filesystem entry, with its attributes resolved (required)
+///
+/// * `attributes` - The attributes the package specifies for the file (optional)
+#[cfg(unix)]
+pub fn apply_attributes(
+    path: &Path,
+    entry: &FileEntry,
+    attributes: Option<&FileAttributes>,
+) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let unable_to_apply = || AttributeError::UnableToApply(entry.path.clone());
+    if entry.uid.is_some() || entry.gid.is_some() {
+        std::os::unix::fs::lchown(path, entry.uid, entry.gid).map_err(|_| unable_to_apply())?;
+    }
+    // Links have no permissions or attributes of their own
+    if entry.kind == EntryKind::Symlink {
+        return Ok(());
+    }
+    if let Some(mode) = entry.mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .map_err(|_| unable_to_apply())?;
+    }
+    let attributes = match attributes {
+        Some(attributes) => attributes,
+        None => return Ok(()),
+    };
+    for (name, value) in attributes.xattrs.iter().flatten() {
+        xattr::set(path, name, &value.0).map_err(|_| unable_to_apply())?;
+    }
+    if let Some(capabilities) = &attributes.capabilities {
+        xattr::set(path, CAPABILITY_XATTR, &encode_capabilities(capabilities)?)
+            .map_err(|_| unable_to_apply())?;
+    }
+    Ok(())
+}
+
+/// Applies the ownership, mode, extended attributes and capabilities of an installed file
+#[cfg(not(unix))]
+pub fn apply_attributes(
+    _path: &Path,
+    _entry: &FileEntry,
+    _attributes: Option<&FileAttributes>,
+) -> Result<()> {
+    Ok(())
+}
+
+/// Finds the attributes a package specifies for one of its files
+///
+/// # Arguments
+///
+/// * `package` - The package owning the file (required)
+///
+/// * `path` - The installed path of the file (required)
+pub fn file_attributes<'a>(package: &'a Package, path: &Path) -> Option<&'a FileAttributes> {
+    package
+        .file_attributes
+        .as_ref()
+        .and_then(|file_attributes| file_attributes.get(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes file capabilities as hexadecimal, as `getfattr -e hex` shows them
+    fn encode_hex(capabilities: &str) -> String {
+        hex::encode(encode_capabilities(capabilities).unwrap())
+    }
+
+    // Each expected value is the `security.capability` attribute written by `setcap` for the same text
+
+    #[test]
+    fn capabilities_added_as_effective_and_permitted() {
+        assert_eq!(
+            encode_hex("cap_net_bind_service+ep"),
+            "0100000200040000000000000000000000000000"
+        );
+    }
+
+    #[test]
+    fn capabilities_assigned_with_every_flag() {
+        assert_eq!(
+            encode_hex("cap_chown=eip"),
+            "0100000201000000010000000000000000000000"
+        );
+        assert_eq!(encode_hex("="), "0000000200000000000000000000000000000000");
+        assert_eq!(
+            encode_hex("all=ep"),
+            "01000002ffffffff00000000ff01000000000000"
+        );
+    }
+
+    #[test]
+    fn capabilities_removed_from_earlier_clauses() {
+        assert_eq!(
+            encode_hex("cap_net_raw,cap_net_admin+p cap_net_admin-p"),
+            "0000000200200000000000000000000000000000"
+        );
+        assert_eq!(
+            encode_hex("cap_net_raw+ep cap_net_raw-e"),
+            "0000000200200000000000000000000000000000"
+        );
+    }
+
+    #[test]
+    fn high_numbered_capabilities() {
+        assert_eq!(
+            encode_hex("cap_bpf,cap_checkpoint_restore+ep"),
+            "0100000200000000000000008001000000000000"
+        );
+        assert_eq!(
+            encode_hex("cap_perfmon+p"),
+            "0000000200000000000000004000000000000000"
+        );
+    }
+
+    #[test]
+    fn invalid_capabilities_are_refused() {
+        assert!(encode_capabilities("cap_unknown+ep").is_err());
+        assert!(encode_capabilities("cap_chown").is_err());
+        // Files cannot raise only some of their permitted capabilities
+        assert!(encode_capabilities("cap_chown,cap_kill+p cap_chown+e").is_err());
+    }
+
+    #[test]
+    fn xattr_values_are_text_or_base64() {
+        let file_attributes: FileAttributes = serde_yaml::from_str(
+            "{xattrs: {user.text: plain, user.bytes: 0sAP8Q, user.prefixed: 0sMHNvdGhlcg==}}",
+        )
+        .unwrap();
+        let xattrs = file_attributes.xattrs.unwrap();
+        assert_eq!(xattrs["user.text"], XattrValue(b"plain".to_vec()));
+        assert_eq!(xattrs["user.bytes"], XattrValue(vec![0x00, 0xff, 0x10]));
+        assert_eq!(xattrs["user.prefixed"], XattrValue(b"0sother".to_vec()));
+        // Values that are not text, or could be mistaken for base64, are written as base64
+        assert_eq!(
+            serde_yaml::to_string(&xattrs).unwrap(),
+            "user.bytes: 0sAP8Q\nuser.prefixed: 0sMHNvdGhlcg==\nuser.text: plain\n"
+        );
+    }
+}
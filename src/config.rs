@@ -0,0 +1,364 @@
+use crate::archive::{self, EntryKind, FileEntry, PackageArchive};
+use crate::database::{self, InstalledPackage};
+use crate::error::QueryError;
+use crate::package::Package;
+use miette::Result;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The suffix given to new versions of configuration files written beside modified ones
+pub const CONFIG_NEW_SUFFIX: &str = ".ganynew";
+
+/// Determines where the new version of a modified configuration file is written
+///
+/// # Arguments
+///
+/// * `path` - The installed path of the configuration file (required)
+pub fn new_config_path(path: &Path) -> PathBuf {
+    let mut new_config_path: OsString = path.as_os_str().to_owned();
+    new_config_path.push(CONFIG_NEW_SUFFIX);
+    PathBuf::from(new_config_path)
+}
+
+/// Maps an installed path onto the directory packages are installed into
+///
+/// # Arguments
+///
+/// * `path` - An installed path (required)
+///
+/// * `root` - The directory packages are installed into (required)
+fn rooted_path(path: &Path, root: &Path) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// Hashes a file on disk, if it can be read
+///
+/// # Arguments
+///
+/// * `path` - The path of the file (required)
+fn hash_file(path: &Path) -> Option<String> {
+    fs::read(path)
+        .ok()
+        .map(|contents| archive::hash_data(&contents))
+}
+
+/// Determines whether an installed configuration file has been modified since it was installed
+///
+/// # Arguments
+///
+/// * `installed_package` - The installed package owning the file (required)
+///
+/// * `path` - The installed path of the file (required)
+///
+/// * `root` - The directory packages are installed into (required)
+fn is_modified(installed_package: &InstalledPackage, path: &Path, root: &Path) -> bool {
+    let on_disk_digest = match hash_file(&rooted_path(path, root)) {
+        Some(on_disk_digest) => on_disk_digest,
+        None => return false,
+    };
+    let installed_digest = installed_package
+        .entries
+        .iter()
+        .find(|entry| entry.path == path)
+        .and_then(|entry| entry.digest.as_ref());
+    installed_digest != Some(&on_disk_digest)
+}
+
+/// Finds the configuration files of an installed package that have been modified since they were installed
+///
+/// # Arguments
+///
+/// * `installed_package` - An installed package (required)
+///
+/// * `root` - The directory packages are installed into (required)
+pub fn modified_config_files(
+    installed_package: &InstalledPackage,
+    root: &Path,
+) -> HashSet<PathBuf> {
+    installed_package
+        .package
+        .config_files
+        .iter()
+        .flatten()
+        .filter(|path| is_modified(installed_package, path, root))
+        .cloned()
+        .collect()
+}
+
+/// Finds the configuration files of a package that must not be overwritten, as they were modified on disk
+///
+/// A configuration file is preserved if it exists on disk, differs from the new version, and differs from the version originally installed.
+/// Configuration files not installed by any package are preserved if they differ from the new version.
+///
+/// Returns the paths to write the new versions to instead, by the installed paths of the configuration files.
+///
+/// # Arguments
+///
+/// * `package` - The package being installed (required)
+///
+/// * `archive` - The archive of the package being installed (required)
+///
+/// * `installed_package` - The installed package being replaced (optional)
+///
+/// * `root` - The directory packages are installed into (required)
+pub fn preserved_config_files(
+    package: &Package,
+    archive: &PackageArchive,
+    installed_package: Option<&InstalledPackage>,
+    root: &Path,
+) -> HashMap<PathBuf, PathBuf> {
+    let config_files = match &package.config_files {
+        Some(config_files) => config_files,
+        None => return HashMap::new(),
+    };
+    let mut redirects: HashMap<PathBuf, PathBuf> = HashMap::new();
+    for entry in archive
+        .header
+        .entries
+        .iter()
+        .filter(|entry| entry.kind == EntryKind::File && config_files.contains(&entry.path))
+    {
+        let on_disk_digest = match hash_file(&rooted_path(&entry.path, root)) {
+            Some(on_disk_digest) => on_disk_digest,
+            None => continue,
+        };
+        if entry.digest.as_ref() == Some(&on_disk_digest) {
+            continue;
+        }
+        let modified = installed_package
+            .map(|installed_package| is_modified(installed_package, &entry.path, root))
+            .unwrap_or(true);
+        if modified {
+            redirects.insert(entry.path.clone(), new_config_path(&entry.path));
+        }
+    }
+    redirects
+}
+
+/// Records the modified configuration files kept on disk as they are, rather than as the new versions written beside them
+///
+/// A kept file is recorded as it was installed by the package it replaces, so that it is still found to be modified.
+/// Files not installed by any package are recorded by their size and mode on disk alone.
+///
+/// # Arguments
+///
+/// * `entries` - The filesystem entries of the package being installed (required)
+///
+/// * `redirects` - The paths the new versions of kept configuration files are written to, by their installed paths (required)
+///
+/// * `installed_package` - The installed package being replaced (optional)
+///
+/// * `root` - The directory packages are installed into (required)
+pub fn kept_config_entries(
+    entries: &[FileEntry],
+    redirects: &HashMap<PathBuf, PathBuf>,
+    installed_package: Option<&InstalledPackage>,
+    root: &Path,
+) -> Vec<FileEntry> {
+    entries
+        .iter()
+        .map(|entry| {
+            if !redirects.contains_key(&entry.path) {
+                return entry.clone();
+            }
+            let installed_entry = installed_package.and_then(|installed_package| {
+                installed_package
+                    .entries
+                    .iter()
+                    .find(|installed_entry| installed_entry.path == entry.path)
+            });
+            if let Some(installed_entry) = installed_entry {
+                return installed_entry.clone();
+            }
+            let metadata = fs::symlink_metadata(rooted_path(&entry.path, root)).ok();
+            FileEntry {
+                path: entry.path.clone(),
+                kind: EntryKind::File,
+                size: metadata
+                    .as_ref()
+                    .map(|metadata| metadata.len())
+                    .unwrap_or_default(),
+                digest: None,
+                target: None,
+                mode: metadata.as_ref().and_then(archive::entry_mode),
+                uid: None,
+                gid: None,
+            }
+        })
+        .collect()
+}
+
+/// Describes the new versions of configuration files written beside an installed package's modified ones, so that they can be removed
+///
+/// # Arguments
+///
+/// * `installed_package` - An installed package (required)
+///
+/// * `root` - The directory packages are installed into (required)
+pub fn new_config_entries(installed_package: &InstalledPackage, root: &Path) -> Vec<FileEntry> {
+    installed_package
+        .new_config_files
+        .iter()
+        .filter(|path| fs::symlink_metadata(rooted_path(path, root)).is_ok())
+        .map(|path| FileEntry {
+            path: path.clone(),
+            kind: EntryKind::File,
+            size: 0,
+            digest: None,
+            target: None,
+            mode: None,
+            uid: None,
+            gid: None,
+        })
+        .collect()
+}
+
+/// The differences between a modified configuration file and the new version written beside it
+#[derive(Eq, PartialEq, Clone, Debug, Serialize)]
+pub struct ConfigDiff {
+    /// The name of the package owning the configuration file
+    pub package: String,
+    /// The installed path of the configuration file
+    pub path: PathBuf,
+    /// The path the new version was written to
+    pub new_path: PathBuf,
+    /// The differences between the two, as a unified diff
+    pub diff: String,
+}
+
+/// Compares the modified configuration files of installed packages with the new versions written beside them
+///
+/// # Arguments
+///
+/// * `name` - The name of an installed package, to only compare its configuration files (optional)
+///
+/// * `root` - The directory packages are installed into (required)
+pub fn config_diffs(name: Option<&str>, root: &Path) -> Result<Vec<ConfigDiff>> {
+    let database = database::read_database()?;
+    if let Some(name) = name {
+        if !database.packages.contains_key(name) {
+            return Err(QueryError::NotInstalled(name.to_owned()).into());
+        }
+    }
+    let mut config_diffs: Vec<ConfigDiff> = Vec::new();
+    for installed_package in database
+        .packages
+        .values()
+        .filter(|installed_package| name.is_none_or(|name| installed_package.package.name == name))
+    {
+        let mut config_files: Vec<&PathBuf> = installed_package
+            .package
+            .config_files
+            .iter()
+            .flatten()
+            .collect();
+        config_files.sort();
+        for path in config_files {
+            let new_path = new_config_path(path);
+            let new_contents = match fs::read(rooted_path(&new_path, root)) {
+                Ok(new_contents) => new_contents,
+                Err(_) => continue,
+            };
+            let contents = fs::read(rooted_path(path, root)).unwrap_or_default();
+            let contents = String::from_utf8_lossy(&contents);
+            let new_contents = String::from_utf8_lossy(&new_contents);
+            let diff = similar::TextDiff::from_lines(contents.as_ref(), new_contents.as_ref())
+                .unified_diff()
+                .header(&path.to_string_lossy(), &new_path.to_string_lossy())
+                .to_string();
+            config_diffs.push(ConfigDiff {
+                package: installed_package.package.name.clone(),
+                path: path.clone(),
+                new_path,
+                diff,
+            });
+        }
+    }
+    Ok(config_diffs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_entry(path: &str, contents: &[u8]) -> FileEntry {
+        FileEntry {
+            path: PathBuf::from(path),
+            kind: EntryKind::File,
+            size: contents.len() as u64,
+            digest: Some(archive::hash_data(contents)),
+            target: None,
+            mode: None,
+            uid: None,
+            gid: None,
+        }
+    }
+
+    fn install(entries: Vec<FileEntry>, new_config_files: Vec<PathBuf>) -> InstalledPackage {
+        InstalledPackage {
+            package: serde_yaml::from_str(
+                "{arch: x86_64, name: example, description: '', version: 1.0.0, config_files: [/etc/example.conf]}",
+            )
+            .unwrap(),
+            explicit: true,
+            entries,
+            installed_at: 0,
+            new_config_files,
+        }
+    }
+
+    #[test]
+    fn kept_config_files_are_recorded_as_installed() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("etc")).unwrap();
+        fs::write(root.path().join("etc/example.conf"), "modified").unwrap();
+        let installed_package = install(
+            vec![file_entry("/etc/example.conf", b"original")],
+            Vec::new(),
+        );
+        let entries = vec![
+            file_entry("/etc/example.conf", b"new"),
+            file_entry("/usr/bin/example", b"binary"),
+        ];
+        let redirects: HashMap<PathBuf, PathBuf> = vec![(
+            PathBuf::from("/etc/example.conf"),
+            new_config_path(Path::new("/etc/example.conf")),
+        )]
+        .into_iter()
+        .collect();
+        let recorded_entries =
+            kept_config_entries(&entries, &redirects, Some(&installed_package), root.path());
+        assert_eq!(
+            recorded_entries[0],
+            file_entry("/etc/example.conf", b"original")
+        );
+        assert_eq!(recorded_entries[1], entries[1]);
+        // The kept file is still found to be modified, so a later upgrade keeps it too
+        let upgraded_package = install(recorded_entries, Vec::new());
+        assert!(modified_config_files(&upgraded_package, root.path())
+            .contains(Path::new("/etc/example.conf")));
+    }
+
+    #[test]
+    fn new_config_files_on_disk_are_removable() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("etc")).unwrap();
+        fs::write(root.path().join("etc/example.conf.ganynew"), "new").unwrap();
+        let installed_package = install(
+            Vec::new(),
+            vec![
+                PathBuf::from("/etc/example.conf.ganynew"),
+                PathBuf::from("/etc/merged.conf.ganynew"),
+            ],
+        );
+        let new_config_entries = new_config_entries(&installed_package, root.path());
+        assert_eq!(new_config_entries.len(), 1);
+        assert_eq!(
+            new_config_entries[0].path,
+            PathBuf::from("/etc/example.conf.ganynew")
+        );
+    }
+}
@@ -0,0 +1,256 @@
+use crate::archive::FileEntry;
+use crate::error::DatabaseError;
+use crate::journal;
+use crate::metadata::{self, Compression};
+use crate::package::{Package, PackageRequirement};
+use miette::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The file recording the packages installed on this system
+pub const DATABASE_FILE: &str = "/etc/gany/gany-installed.bin";
+
+/// A package installed on this system
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct InstalledPackage {
+    /// The manifest of the installed package
+    pub package: Package,
+    /// Whether the package was requested explicitly, rather than installed as a dependency
+    pub explicit: bool,
+    /// The filesystem entries installed by the package
+    #[serde(default)]
+    pub entries: Vec<FileEntry>,
+    /// When the package was installed, in seconds since the Unix epoch
+    #[serde(default)]
+    pub installed_at: u64,
+    /// The new versions of configuration files written beside the modified ones they did not replace
+    #[serde(default)]
+    pub new_config_files: Vec<PathBuf>,
+}
+
+impl InstalledPackage {
+    /// The total size of the files installed by the package, in bytes
+    pub fn installed_size(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.size).sum()
+    }
+}
+
+/// The record of packages installed on this system
+#[derive(Eq, PartialEq, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Database {
+    /// The installed packages, by name
+    #[serde(default)]
+    pub packages: BTreeMap<String, InstalledPackage>,
+}
+
+impl Database {
+    /// Records a package as installed, replacing any other version of it
+    ///
+    /// A package that was previously requested explicitly remains so.
+    ///
+    /// # Arguments
+    ///
+    /// * `package` - The installed package (required)
+    ///
+    /// * `explicit` - Whether the package was requested explicitly (required)
+    ///
+    /// * `entries` - The filesystem entries installed by the package (required)
+    ///
+    /// * `new_config_files` - The new versions of configuration files written beside modified ones (required)
+    pub fn record_installation(
+        &mut self,
+        package: Package,
+        explicit: bool,
+        entries: Vec<FileEntry>,
+        new_config_files: Vec<PathBuf>,
+    ) {
+        let explicit = explicit
+            || self
+                .packages
+                .get(&package.name)
+                .map(|installed_package| installed_package.explicit)
+                .unwrap_or(false);
+        let installed_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        self.packages.insert(
+            package.name.clone(),
+            InstalledPackage {
+                package,
+                explicit,
+                entries,
+                installed_at,
+                new_config_files,
+            },
+        );
+    }
+
+    /// Builds an index of the packages owning each installed path
+    ///
+    /// Directories may be owned by more than one package.
+    pub fn file_index(&self) -> BTreeMap<PathBuf, Vec<&InstalledPackage>> {
+        let mut file_index: BTreeMap<PathBuf, Vec<&InstalledPackage>> = BTreeMap::new();
+        for installed_package in self.packages.values() {
+            let paths: BTreeSet<&PathBuf> = installed_package
+                .entries
+                .iter()
+                .map(|entry| &entry.path)
+                .chain(installed_package.package.files.iter())
+                .collect();
+            for path in paths {
+                file_index
+                    .entry(path.clone())
+                    .or_default()
+                    .push(installed_package);
+            }
+        }
+        file_index
+    }
+
+    /// Finds the installed packages fulfilling a requirement
+    ///
+    /// # Arguments
+    ///
+    /// * `requirement` - A requirement for a package (required)
+    pub fn fulfilling(&self, requirement: &PackageRequirement) -> Vec<&InstalledPackage> {
+        self.packages
+            .values()
+            .filter(|installed_package| requirement.is_fulfilled_by(&installed_package.package))
+            .collect()
+    }
+
+    /// Determines the names of the installed packages required, directly or indirectly, by explicitly installed packages
+    pub fn required_packages(&self) -> BTreeSet<String> {
+        let mut required_packages: BTreeSet<String> = BTreeSet::new();
+        let mut pending: Vec<&InstalledPackage> = self
+            .packages
+            .values()
+            .filter(|installed_package| installed_package.explicit)
+            .collect();
+        while let Some(installed_package) = pending.pop() {
+            if !required_packages.insert(installed_package.package.name.clone()) {
+                continue;
+            }
+            for dependency in installed_package.package.dependencies.iter().flatten() {
+                pending.extend(self.fulfilling(dependency));
+            }
+        }
+        required_packages
+    }
+
+    /// Finds the packages installed as dependencies that are no longer required by any explicitly installed package
+    pub fn orphans(&self) -> Vec<&InstalledPackage> {
+        let required_packages = self.required_packages();
+        self.packages
+            .values()
+            .filter(|installed_package| {
+                !required_packages.contains(&installed_package.package.name)
+            })
+            .collect()
+    }
+
+    /// Determines whether a particular build of a package is installed
+    ///
+    /// # Arguments
+    ///
+    /// * `package` - A package which may be installed (required)
+    pub fn is_installed(&self, package: &Package) -> bool {
+        self.packages
+            .get(&package.name)
+            .map(|installed_package| installed_package.package.is_same_build(package))
+            .unwrap_or(false)
+    }
+
+    /// Lists the installed packages
+    pub fn installed_packages(&self) -> HashSet<&Package> {
+        self.packages
+            .values()
+            .map(|installed_package| &installed_package.package)
+            .collect()
+    }
+}
+
+/// Reads the record of installed packages from the filesystem
+///
+/// If no packages have been installed yet, an empty record is returned.
+pub fn read_database() -> Result<Database> {
+    let database_file = match fs::read(DATABASE_FILE) {
+        Ok(database_file) => database_file,
+        Err(_) => return Ok(Database::default()),
+    };
+    Ok(metadata::decode(&database_file).map_err(|_| DatabaseError::UnableToDeserialiseDatabase)?)
+}
+
+/// Writes the record of installed packages to the filesystem
+///
+/// # Arguments
+///
+/// * `database` - The record of installed packages (required)
+///
+/// * `root` - The directory packages are installed into, beneath which the record is kept (required)
+pub fn write_database(database: &Database, root: &Path) -> Result<()> {
+    let database_data = metadata::encode(database, Compression::Lz4)?;
+    journal::write_atomically(
+        &journal::rooted_path(Path::new(DATABASE_FILE), root),
+        &database_data,
+    )
+    .map_err(|_| DatabaseError::UnableToWriteDatabase)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn install(database: &mut Database, manifest: &str, explicit: bool) {
+        let package: Package = serde_yaml::from_str(manifest).unwrap();
+        database.packages.insert(
+            package.name.clone(),
+            InstalledPackage {
+                package,
+                explicit,
+                entries: Vec::new(),
+                installed_at: 0,
+                new_config_files: Vec::new(),
+            },
+        );
+    }
+
+    #[test]
+    fn dependencies_with_their_own_requirements_are_not_orphans() {
+        let mut database = Database::default();
+        install(
+            &mut database,
+            "{arch: x86_64, name: app, description: '', version: 1.0.0,
+              dependencies: [{arch: x86_64, name: library, version: '^1', dependencies: null, conflicts: null}]}",
+            true,
+        );
+        install(
+            &mut database,
+            "{arch: x86_64, name: library, description: '', version: 1.2.0,
+              dependencies: [{arch: x86_64, name: runtime, version: '*', dependencies: null, conflicts: null}],
+              conflicts: [{arch: x86_64, name: legacy, version: '*', dependencies: null, conflicts: null}]}",
+            false,
+        );
+        install(
+            &mut database,
+            "{arch: x86_64, name: runtime, description: '', version: 3.0.0}",
+            false,
+        );
+        install(
+            &mut database,
+            "{arch: x86_64, name: leftover, description: '', version: 0.1.0}",
+            false,
+        );
+        let orphans: Vec<&String> = database
+            .orphans()
+            .into_iter()
+            .map(|installed_package| &installed_package.package.name)
+            .collect();
+        assert_eq!(orphans, vec!["leftover"]);
+    }
+}
@@ -1,4 +1,9 @@
+// Variants are named in the same words as the messages they are shown to users with
+#![allow(clippy::enum_variant_names)]
+
+use crate::scripts::ScriptFailure;
 use miette::Diagnostic;
+use std::path::PathBuf;
 use thiserror::Error;
 use url::Url;
 
@@ -13,6 +18,9 @@ pub enum FetchRepositoriesError {
     #[error("Unable to download repository data from {0}.")]
     #[diagnostic(code(fetch_repositories::unable_to_download_repository_data))]
     UnableToDownloadRepositoryData(Url),
+    #[error("Unable to read repository data from {0}.")]
+    #[diagnostic(code(fetch_repositories::unable_to_read_local_repository_data))]
+    UnableToReadLocalRepositoryData(Url),
     #[error("Unable to read repository data from the filesystem.")]
     #[diagnostic(code(fetch_repositories::unable_to_read_repository_data))]
     UnableToReadRepositoryData,
@@ -22,6 +30,28 @@ pub enum FetchRepositoriesError {
     #[error("Unable to deserialise repository data from {0}.")]
     #[diagnostic(code(fetch_repositories::unable_to_deserialise_repository_data_internet))]
     UnableToDeserialiseRepositoryDataInternet(Url),
+    #[error("Unable to deserialise repository data read from {0}.")]
+    #[diagnostic(code(fetch_repositories::unable_to_deserialise_local_repository_data))]
+    UnableToDeserialiseLocalRepositoryData(Url),
+    #[error("Unable to synchronise repository data as '{0}' in the repository list is neither a URL nor an absolute path.")]
+    #[diagnostic(code(fetch_repositories::invalid_repository_entry))]
+    InvalidRepositoryEntry(String),
+    #[error("Unable to verify that repository data from {0} was signed by a trusted key.")]
+    #[diagnostic(code(fetch_repositories::unable_to_verify_repository_data))]
+    UnableToVerifyRepositoryData(Url),
+    #[error("Unable to write repository data to the filesystem.")]
+    #[diagnostic(code(fetch_repositories::unable_to_write_repository_data))]
+    UnableToWriteRepositoryData,
+}
+
+#[derive(Error, Diagnostic, Debug)]
+pub enum FetchPackageError {
+    #[error("Unable to locate package archives as the repository address '{0}' is invalid.")]
+    #[diagnostic(code(fetch_package::invalid_repository_address))]
+    InvalidRepositoryAddress(String),
+    #[error("Unable to fetch package archive from {0}.")]
+    #[diagnostic(code(fetch_package::unable_to_fetch_package_archive))]
+    UnableToFetchPackageArchive(Url),
 }
 
 #[derive(Error, Diagnostic, Debug)]
@@ -30,3 +60,282 @@ pub enum PackageInstallationError {
     #[diagnostic(code(package_installation::unable_to_solve_transaction))]
     UnableToSolveTransaction,
 }
+
+#[derive(Error, Diagnostic, Debug)]
+pub enum TransactionError {
+    #[error("Unable to find a package named '{0}'.")]
+    #[diagnostic(code(transaction::package_not_found))]
+    PackageNotFound(String),
+    #[error("Unable to obtain the archive of '{0}'.")]
+    #[diagnostic(code(transaction::unable_to_obtain_archive))]
+    UnableToObtainArchive(String),
+    #[error("The archive of '{0}' does not match the hash recorded by its repository.")]
+    #[diagnostic(code(transaction::archive_hash_mismatch))]
+    ArchiveHashMismatch(String),
+    #[error("The archive of '{0}' holds a different package than expected.")]
+    #[diagnostic(code(transaction::unexpected_archive_contents))]
+    UnexpectedArchiveContents(String),
+    #[error(
+        "Unable to proceed as packages claim the same files:\n{}",
+        .0.join("\n")
+    )]
+    #[diagnostic(code(transaction::file_conflicts))]
+    FileConflicts(Vec<String>),
+    #[error("'{0}' is not installed.")]
+    #[diagnostic(code(transaction::package_not_installed))]
+    PackageNotInstalled(String),
+    #[error("Unable to drop '{0}', as the installed '{1}' depends on it.")]
+    #[diagnostic(code(transaction::required_by_installed))]
+    RequiredByInstalled(String, String),
+    #[error("Unable to find version {1} of '{0}' in the archive cache or any repository.")]
+    #[diagnostic(code(transaction::previous_version_unavailable))]
+    PreviousVersionUnavailable(String, semver::Version),
+    #[error("Unable to roll back, as no transactions have been committed.")]
+    #[diagnostic(code(transaction::nothing_to_roll_back))]
+    NothingToRollBack,
+}
+
+#[derive(Error, Diagnostic, Debug)]
+pub enum DatabaseError {
+    #[error("Unable to deserialise the record of installed packages.")]
+    #[diagnostic(code(database::unable_to_deserialise_database))]
+    UnableToDeserialiseDatabase,
+    #[error("Unable to write the record of installed packages.")]
+    #[diagnostic(code(database::unable_to_write_database))]
+    UnableToWriteDatabase,
+}
+
+#[derive(Error, Diagnostic, Debug)]
+pub enum JournalError {
+    #[error("Unable to write the journal of the transaction.")]
+    #[diagnostic(code(journal::unable_to_write_journal))]
+    UnableToWriteJournal,
+    #[error("Unable to deserialise the journal of an interrupted transaction.")]
+    #[diagnostic(code(journal::unable_to_deserialise_journal))]
+    UnableToDeserialiseJournal,
+    #[error("Unable to move {0} into place.")]
+    #[diagnostic(code(journal::unable_to_commit))]
+    UnableToCommit(PathBuf),
+}
+
+#[derive(Error, Diagnostic, Debug)]
+pub enum HistoryError {
+    #[error("Unable to deserialise the record of committed transactions.")]
+    #[diagnostic(code(history::unable_to_deserialise_history))]
+    UnableToDeserialiseHistory,
+    #[error("Unable to write the record of committed transactions.")]
+    #[diagnostic(code(history::unable_to_write_history))]
+    UnableToWriteHistory,
+    #[error("Unable to find a transaction with the identifier {0}.")]
+    #[diagnostic(code(history::transaction_not_found))]
+    TransactionNotFound(u64),
+}
+
+#[derive(Error, Diagnostic, Debug)]
+pub enum SnapshotError {
+    #[error("Unable to deserialise the snapshot configuration.")]
+    #[diagnostic(code(snapshot::unable_to_deserialise_config))]
+    UnableToDeserialiseConfig,
+    #[error("Unable to take a snapshot of {0}.")]
+    #[diagnostic(code(snapshot::unable_to_snapshot))]
+    UnableToSnapshot(PathBuf),
+    #[error("Unable to take a btrfs snapshot: {0}")]
+    #[diagnostic(code(snapshot::btrfs_snapshot_failed))]
+    BtrfsSnapshotFailed(String),
+    #[error("Unable to take btrfs snapshots, as the `btrfs` tool is unavailable.")]
+    #[diagnostic(code(snapshot::btrfs_unavailable))]
+    BtrfsUnavailable,
+}
+
+#[derive(Error, Diagnostic, Debug)]
+pub enum ScriptError {
+    #[error("Unable to proceed, as {0}.")]
+    #[diagnostic(code(scripts::script_failed))]
+    ScriptFailed(ScriptFailure),
+}
+
+#[derive(Error, Diagnostic, Debug)]
+pub enum TriggerError {
+    #[error("Unable to read the trigger at {0}.")]
+    #[diagnostic(code(triggers::unable_to_read_trigger))]
+    UnableToReadTrigger(PathBuf),
+    #[error("Unable to deserialise the trigger at {0}.")]
+    #[diagnostic(code(triggers::unable_to_deserialise_trigger))]
+    UnableToDeserialiseTrigger(PathBuf),
+    #[error("The '{0}' trigger has an invalid path pattern: '{1}'.")]
+    #[diagnostic(code(triggers::invalid_pattern))]
+    InvalidPattern(String, String),
+}
+
+#[derive(Error, Diagnostic, Debug)]
+pub enum RepositoryBuildError {
+    #[error("Unable to build repository as the directory {0} is unable to be read.")]
+    #[diagnostic(code(repository_build::unable_to_read_directory))]
+    UnableToReadDirectory(PathBuf),
+    #[error("Unable to build repository as the package archive {0} is unable to be read.")]
+    #[diagnostic(code(repository_build::unable_to_read_archive))]
+    UnableToReadArchive(PathBuf),
+    #[error("Unable to build repository as the package archive {0} should be named '{1}'.")]
+    #[diagnostic(code(repository_build::misnamed_archive))]
+    MisnamedArchive(PathBuf, String),
+    #[error("Unable to write repository data to {0}.")]
+    #[diagnostic(code(repository_build::unable_to_write_repository))]
+    UnableToWriteRepository(PathBuf),
+}
+
+#[derive(Error, Diagnostic, Debug)]
+pub enum ArchiveError {
+    #[error("Unable to read package archive {0}.")]
+    #[diagnostic(code(archive::unable_to_read_archive))]
+    UnableToReadArchive(PathBuf),
+    #[error("Unable to read {0} while creating a package archive.")]
+    #[diagnostic(code(archive::unable_to_read_source))]
+    UnableToReadSource(PathBuf),
+    #[error("Unable to read package archive as it is not a Gany package archive.")]
+    #[diagnostic(code(archive::not_an_archive))]
+    NotAnArchive,
+    #[error("Unable to read package archive as it ends prematurely.")]
+    #[diagnostic(code(archive::truncated))]
+    Truncated,
+    #[error("Unable to read package archive of format version {0}; a newer version of Gany is required.")]
+    #[diagnostic(code(archive::unsupported_format_version))]
+    UnsupportedFormatVersion(u16),
+    #[error("Unable to decompress package archive.")]
+    #[diagnostic(code(archive::unable_to_decompress_archive))]
+    UnableToDecompressArchive,
+    #[error("Unable to read the entries of a package archive.")]
+    #[diagnostic(code(archive::unable_to_read_archive_entries))]
+    UnableToReadArchiveEntries,
+    #[error("Unable to deserialise the manifest within a package archive.")]
+    #[diagnostic(code(archive::unable_to_deserialise_manifest))]
+    UnableToDeserialiseManifest,
+    #[error("The contents of the package archive do not match the hash in its header.")]
+    #[diagnostic(code(archive::payload_hash_mismatch))]
+    PayloadHashMismatch,
+    #[error("The file {0} within the package archive does not match the hash in its header.")]
+    #[diagnostic(code(archive::file_hash_mismatch))]
+    FileHashMismatch(PathBuf),
+    #[error("The package archive is not signed, but signatures are required.")]
+    #[diagnostic(code(archive::missing_signature))]
+    MissingSignature,
+    #[error("The package archive contains {0}, which is not listed in its header.")]
+    #[diagnostic(code(archive::unlisted_entry))]
+    UnlistedEntry(PathBuf),
+    #[error("The package archive contains {0} more than once.")]
+    #[diagnostic(code(archive::duplicate_entry))]
+    DuplicateEntry(PathBuf),
+    #[error("The package archive lists {0} in its header, but does not contain it.")]
+    #[diagnostic(code(archive::missing_entry))]
+    MissingEntry(PathBuf),
+    #[error(
+        "The package archive contains {0} as a different kind of entry than its header lists."
+    )]
+    #[diagnostic(code(archive::entry_kind_mismatch))]
+    EntryKindMismatch(PathBuf),
+    #[error("The package archive contains the unsafe path {0}.")]
+    #[diagnostic(code(archive::unsafe_path))]
+    UnsafePath(PathBuf),
+    #[error("Unable to extract {0} from a package archive.")]
+    #[diagnostic(code(archive::unable_to_extract))]
+    UnableToExtract(PathBuf),
+    #[error("Unable to write package archive to {0}.")]
+    #[diagnostic(code(archive::unable_to_write_archive))]
+    UnableToWriteArchive(PathBuf),
+    #[error("Unable to read package manifest from {0}.")]
+    #[diagnostic(code(archive::unable_to_read_manifest))]
+    UnableToReadManifest(PathBuf),
+}
+
+#[derive(Error, Diagnostic, Debug)]
+pub enum SignatureError {
+    #[error("Unable to read signing key from {0}.")]
+    #[diagnostic(code(signature::unable_to_read_key))]
+    UnableToReadKey(PathBuf),
+    #[error("Unable to write key to {0}.")]
+    #[diagnostic(code(signature::unable_to_write_key))]
+    UnableToWriteKey(PathBuf),
+    #[error("Unable to write key to {0}, as a file already exists there.")]
+    #[diagnostic(code(signature::key_exists))]
+    KeyExists(PathBuf),
+    #[error("The signing key at {0} is invalid.")]
+    #[diagnostic(code(signature::invalid_key))]
+    InvalidKey(PathBuf),
+    #[error("Unable to deserialise the list of trusted keys.")]
+    #[diagnostic(code(signature::unable_to_deserialise_trusted_keys))]
+    UnableToDeserialiseTrustedKeys,
+    #[error("The trusted key '{0}' is invalid.")]
+    #[diagnostic(code(signature::invalid_trusted_key))]
+    InvalidTrustedKey(String),
+    #[error("The signature is malformed.")]
+    #[diagnostic(code(signature::invalid_signature))]
+    InvalidSignature,
+    #[error("The signature was not made by a trusted key.")]
+    #[diagnostic(code(signature::untrusted_signature))]
+    UntrustedSignature,
+}
+
+#[derive(Error, Diagnostic, Debug)]
+pub enum MetadataError {
+    #[error("Unable to read metadata from {0}.")]
+    #[diagnostic(code(metadata::unable_to_read))]
+    UnableToRead(PathBuf),
+    #[error("Unable to write metadata to {0}.")]
+    #[diagnostic(code(metadata::unable_to_write))]
+    UnableToWrite(PathBuf),
+    #[error("Unable to serialise metadata.")]
+    #[diagnostic(code(metadata::unable_to_serialise))]
+    UnableToSerialise,
+    #[error("Unable to deserialise metadata.")]
+    #[diagnostic(code(metadata::unable_to_deserialise))]
+    UnableToDeserialise,
+    #[error("Unable to decompress metadata.")]
+    #[diagnostic(code(metadata::unable_to_decompress))]
+    UnableToDecompress,
+    #[error("Unable to read metadata as it has no header.")]
+    #[diagnostic(code(metadata::missing_header))]
+    MissingHeader,
+    #[error("Unable to read metadata written with schema version {0}; a newer version of Gany is required.")]
+    #[diagnostic(code(metadata::unsupported_schema_version))]
+    UnsupportedSchemaVersion(u16),
+    #[error("Unable to read metadata compressed with unknown method {0}.")]
+    #[diagnostic(code(metadata::unknown_compression))]
+    UnknownCompression(u8),
+}
+
+#[derive(Error, Diagnostic, Debug)]
+pub enum QueryError {
+    #[error("The pattern '{0}' is invalid.")]
+    #[diagnostic(code(query::invalid_pattern))]
+    InvalidPattern(String),
+    #[error("Unable to find a package named '{0}'.")]
+    #[diagnostic(code(query::package_not_found))]
+    PackageNotFound(String),
+    #[error("'{0}' is not required by any of the packages in the graph.")]
+    #[diagnostic(code(query::not_required))]
+    NotRequired(String),
+    #[error("'{0}' is not installed.")]
+    #[diagnostic(code(query::not_installed))]
+    NotInstalled(String),
+    #[error("Unable to find version {1} of '{0}' in any repository.")]
+    #[diagnostic(code(query::version_not_found))]
+    VersionNotFound(String, semver::Version),
+}
+
+#[derive(Error, Diagnostic, Debug)]
+pub enum AttributeError {
+    #[error("The file capabilities '{0}' are invalid.")]
+    #[diagnostic(code(attribute::invalid_capabilities))]
+    InvalidCapabilities(String),
+    #[error("Attributes are given for {0}, which is not in the package.")]
+    #[diagnostic(code(attribute::unknown_path))]
+    UnknownPath(PathBuf),
+    #[error("Unable to find the user '{0}' owning {1}, and no numeric user ID was given.")]
+    #[diagnostic(code(attribute::unknown_user))]
+    UnknownUser(String, PathBuf),
+    #[error("Unable to find the group '{0}' owning {1}, and no numeric group ID was given.")]
+    #[diagnostic(code(attribute::unknown_group))]
+    UnknownGroup(String, PathBuf),
+    #[error("Unable to apply the attributes of {0}.")]
+    #[diagnostic(code(attribute::unable_to_apply))]
+    UnableToApply(PathBuf),
+}
@@ -0,0 +1,229 @@
+use crate::database;
+use crate::error::QueryError;
+use crate::graph::{self, Edge, Node};
+use crate::package::{self, ClauseReason, Package, PackageRequirement};
+use crate::repository;
+use crate::ARCH;
+use miette::Result;
+use semver::Version;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
+
+/// Why a package is installed
+#[derive(Eq, PartialEq, Clone, Debug, Serialize)]
+pub enum Why {
+    /// The package was requested explicitly
+    Explicit(Node),
+    /// The package is required through a chain of dependencies, starting from an explicitly installed package
+    Required(Vec<Edge>),
+    /// The package was installed as a dependency, but is no longer required
+    Orphan(Node),
+}
+
+/// Explains why a package is installed, by the chain of installed packages whose dependencies pulled it in
+///
+/// # Arguments
+///
+/// * `name` - The name of an installed package (required)
+pub fn why(name: &str) -> Result<Why> {
+    let database = database::read_database()?;
+    let installed_package = database
+        .packages
+        .get(name)
+        .ok_or_else(|| QueryError::NotInstalled(name.to_owned()))?;
+    let node = Node::from_package(&installed_package.package);
+    if installed_package.explicit {
+        return Ok(Why::Explicit(node));
+    }
+    match graph::installed_graph()?.dependency_path(name) {
+        Some(path) => Ok(Why::Required(path)),
+        None => Ok(Why::Orphan(node)),
+    }
+}
+
+/// A constraint the solver was unable to satisfy alongside the others when installing a version of a package
+#[derive(Eq, PartialEq, Clone, Debug, Serialize)]
+pub enum Constraint {
+    /// The version of the package being installed must be installed
+    Forced { package: Node },
+    /// An explicitly installed package must remain installed
+    Explicit { package: Node },
+    /// An installed package, or another version of it, must remain installed
+    Installed { package: Node },
+    /// A package depends on something only the candidates fulfill
+    Dependency {
+        package: Node,
+        requirement: PackageRequirement,
+        candidates: Vec<Node>,
+    },
+    /// A package conflicts with another package
+    Conflict {
+        package: Node,
+        conflicting: Node,
+        requirement: PackageRequirement,
+    },
+    /// Two versions of a package cannot be installed together
+    SingleVersion { package: Node, other: Node },
+}
+
+impl Constraint {
+    /// Describes a clause of the formula solved for a transaction as a constraint
+    ///
+    /// # Arguments
+    ///
+    /// * `reason` - Why the clause is part of the formula (required)
+    ///
+    /// * `forced_package` - The version of the package being installed (required)
+    fn from_reason(reason: ClauseReason, forced_package: &Package) -> Self {
+        match reason {
+            ClauseReason::Requested(package) if package == *forced_package => Constraint::Forced {
+                package: Node::from_package(&package),
+            },
+            ClauseReason::Requested(package) => Constraint::Explicit {
+                package: Node::from_package(&package),
+            },
+            ClauseReason::Installed(package) => Constraint::Installed {
+                package: Node::from_package(&package),
+            },
+            ClauseReason::Dependency {
+                package,
+                requirement,
+                candidates,
+            } => Constraint::Dependency {
+                package: Node::from_package(&package),
+                requirement,
+                candidates: candidates.iter().map(Node::from_package).collect(),
+            },
+            ClauseReason::Conflict {
+                package,
+                conflicting,
+                requirement,
+            } => Constraint::Conflict {
+                package: Node::from_package(&package),
+                conflicting: Node::from_package(&conflicting),
+                requirement,
+            },
+            ClauseReason::SingleVersion(package, other) => Constraint::SingleVersion {
+                package: Node::from_package(&package),
+                other: Node::from_package(&other),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Constraint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Constraint::Forced { package } => write!(f, "{} is being installed", package.label()),
+            Constraint::Explicit { package } => {
+                write!(f, "{} is installed explicitly", package.label())
+            }
+            Constraint::Installed { package } => write!(
+                f,
+                "{} is installed, so it or another version of it must remain installed",
+                package.label()
+            ),
+            Constraint::Dependency {
+                package,
+                requirement,
+                candidates,
+            } if candidates.is_empty() => write!(
+                f,
+                "{} depends on {} {}, which no package fulfills",
+                package.label(),
+                requirement.name,
+                requirement.version
+            ),
+            Constraint::Dependency {
+                package,
+                requirement,
+                candidates,
+            } => write!(
+                f,
+                "{} depends on {} {}, fulfilled only by {}",
+                package.label(),
+                requirement.name,
+                requirement.version,
+                candidates
+                    .iter()
+                    .map(Node::label)
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            Constraint::Conflict {
+                package,
+                conflicting,
+                requirement,
+            } => write!(
+                f,
+                "{} conflicts with {} {}, fulfilled by {}",
+                package.label(),
+                requirement.name,
+                requirement.version,
+                conflicting.label()
+            ),
+            Constraint::SingleVersion { package, other } => write!(
+                f,
+                "{} and {} cannot both be installed, as they are versions of the same package",
+                package.label(),
+                other.label()
+            ),
+        }
+    }
+}
+
+/// Why a version of a package can or cannot be installed
+#[derive(Eq, PartialEq, Clone, Debug, Serialize)]
+pub struct WhyNot {
+    /// The version of the package being installed
+    pub package: Node,
+    /// Whether the explicitly installed packages and the package can be installed together
+    pub installable: bool,
+    /// The constraints the solver was unable to satisfy together, each needed to prevent the package from being installed
+    pub constraints: Vec<Constraint>,
+}
+
+/// Explains why a version of a package can or cannot be installed, by running the solver with that version forced
+///
+/// # Arguments
+///
+/// * `name` - The name of a package (required)
+///
+/// * `version` - The version of the package to force (required)
+pub async fn why_not(name: &str, version: &Version) -> Result<WhyNot> {
+    let database = database::read_database()?;
+    let repositories = repository::read_repositories()?;
+    let forced_package = repositories
+        .iter()
+        .flat_map(|repository| repository.packages.iter().flatten())
+        .find(|package| {
+            package.name == name && package.version == *version && package.arch == *ARCH
+        })
+        .cloned()
+        .ok_or_else(|| QueryError::VersionNotFound(name.to_owned(), version.clone()))?;
+    let mut requested_packages: HashSet<&Package> = database
+        .packages
+        .values()
+        .filter(|installed_package| {
+            installed_package.explicit && installed_package.package.name != name
+        })
+        .map(|installed_package| &installed_package.package)
+        .collect();
+    requested_packages.insert(&forced_package);
+    let reasons = package::explain_unsolvable(
+        &requested_packages,
+        &database.installed_packages(),
+        &HashSet::new(),
+    )
+    .await?;
+    Ok(WhyNot {
+        package: Node::from_package(&forced_package),
+        installable: reasons.is_none(),
+        constraints: reasons
+            .into_iter()
+            .flatten()
+            .map(|reason| Constraint::from_reason(reason, &forced_package))
+            .collect(),
+    })
+}
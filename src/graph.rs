@@ -0,0 +1,337 @@
+use crate::database;
+use crate::error::QueryError;
+use crate::package::{self, Package};
+use crate::transaction;
+use miette::Result;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt::Write;
+
+/// The relationship an edge of a dependency graph represents
+#[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Copy, Debug, Serialize)]
+pub enum EdgeKind {
+    /// The package depends on the other
+    Dependency,
+    /// The package conflicts with the other
+    Conflict,
+}
+
+/// A package in a dependency graph
+#[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Debug, Serialize)]
+pub struct Node {
+    /// The name of the package
+    pub name: String,
+    /// The version of the package
+    pub version: semver::Version,
+}
+
+impl Node {
+    /// Describes a package as a node of a dependency graph
+    ///
+    /// # Arguments
+    ///
+    /// * `package` - A package (required)
+    pub fn from_package(package: &Package) -> Self {
+        Node {
+            name: package.name.clone(),
+            version: package.version.clone(),
+        }
+    }
+
+    /// The label of this node when rendered
+    pub fn label(&self) -> String {
+        format!("{} {}", self.name, self.version)
+    }
+}
+
+/// Escapes text for use within a quoted string in the Graphviz DOT language
+///
+/// Brackets and other punctuation have no meaning within quoted strings, so only quotes and backslashes are escaped.
+///
+/// # Arguments
+///
+/// * `text` - The text to be escaped (required)
+fn escape_dot(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Escapes text for use within a quoted label in the Mermaid language
+///
+/// Characters with meaning to Mermaid are written as entity codes.
+///
+/// # Arguments
+///
+/// * `text` - The text to be escaped (required)
+fn escape_mermaid(text: &str) -> String {
+    text.chars()
+        .map(|character| match character {
+            '"' => "#quot;".to_owned(),
+            '#' | '[' | ']' | '(' | ')' | '{' | '}' | '<' | '>' | '|' => {
+                format!("#{};", character as u32)
+            }
+            _ => character.to_string(),
+        })
+        .collect()
+}
+
+/// A relationship between two packages in a dependency graph
+#[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Debug, Serialize)]
+pub struct Edge {
+    /// The package with the requirement
+    pub from: Node,
+    /// The package fulfilling the requirement
+    pub to: Node,
+    /// The relationship the requirement represents
+    pub kind: EdgeKind,
+    /// The version requirement, as written
+    pub requirement: String,
+}
+
+/// A graph of the relationships between packages
+#[derive(Eq, PartialEq, Clone, Debug, Default, Serialize)]
+pub struct DependencyGraph {
+    /// The packages in the graph
+    pub nodes: BTreeSet<Node>,
+    /// The relationships between the packages in the graph
+    pub edges: BTreeSet<Edge>,
+    /// The packages the graph is rooted at, either those requested or those installed explicitly
+    pub roots: BTreeSet<Node>,
+}
+
+/// The languages a dependency graph can be rendered in
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum GraphFormat {
+    Dot,
+    Mermaid,
+}
+
+impl DependencyGraph {
+    /// Builds a graph of the relationships within a set of packages
+    ///
+    /// # Arguments
+    ///
+    /// * `packages` - The packages in the graph (required)
+    ///
+    /// * `roots` - The packages the graph is rooted at (required)
+    pub fn from_packages(packages: &HashSet<Package>, roots: &HashSet<Package>) -> Self {
+        let mut graph = DependencyGraph {
+            nodes: packages.iter().map(Node::from_package).collect(),
+            edges: BTreeSet::new(),
+            roots: roots.iter().map(Node::from_package).collect(),
+        };
+        for package in packages {
+            let requirements = package
+                .dependencies
+                .iter()
+                .flatten()
+                .map(|requirement| (EdgeKind::Dependency, requirement))
+                .chain(
+                    package
+                        .conflicts
+                        .iter()
+                        .flatten()
+                        .map(|requirement| (EdgeKind::Conflict, requirement)),
+                );
+            for (kind, requirement) in requirements {
+                for candidate in packages
+                    .iter()
+                    .filter(|candidate| requirement.is_fulfilled_by(candidate))
+                {
+                    graph.edges.insert(Edge {
+                        from: Node::from_package(package),
+                        to: Node::from_package(candidate),
+                        kind,
+                        requirement: requirement.version.to_string(),
+                    });
+                }
+            }
+        }
+        graph
+    }
+
+    /// Finds the shortest chain of dependencies from any root of the graph to a package
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the package at the end of the chain (required)
+    pub fn dependency_path(&self, name: &str) -> Option<Vec<Edge>> {
+        let mut previous_edges: HashMap<&Node, &Edge> = HashMap::new();
+        let mut visited: HashSet<&Node> = self.roots.iter().collect();
+        let mut pending: VecDeque<&Node> = self.roots.iter().collect();
+        while let Some(node) = pending.pop_front() {
+            if node.name == name {
+                let mut path: Vec<Edge> = Vec::new();
+                let mut current = node;
+                while let Some(edge) = previous_edges.get(current) {
+                    path.push((*edge).clone());
+                    current = &edge.from;
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for edge in self
+                .edges
+                .iter()
+                .filter(|edge| edge.kind == EdgeKind::Dependency && edge.from == *node)
+            {
+                if visited.insert(&edge.to) {
+                    previous_edges.insert(&edge.to, edge);
+                    pending.push_back(&edge.to);
+                }
+            }
+        }
+        None
+    }
+
+    /// Reduces the graph to the shortest chain of dependencies from any root to a package
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the package at the end of the chain (required)
+    pub fn why(&self, name: &str) -> Result<DependencyGraph> {
+        let path = self
+            .dependency_path(name)
+            .ok_or_else(|| QueryError::NotRequired(name.to_owned()))?;
+        let mut nodes: BTreeSet<Node> = path
+            .iter()
+            .flat_map(|edge| [edge.from.clone(), edge.to.clone()])
+            .collect();
+        // A root is its own explanation, with no edges leading to it
+        nodes.extend(self.roots.iter().filter(|root| root.name == name).cloned());
+        Ok(DependencyGraph {
+            roots: self
+                .roots
+                .iter()
+                .filter(|root| nodes.contains(root))
+                .cloned()
+                .collect(),
+            nodes,
+            edges: path.into_iter().collect(),
+        })
+    }
+
+    /// Assigns each node of the graph an identifier, unique within the graph and safe for use in any graph language
+    ///
+    /// Identifiers are numbered in the order of the nodes, with names and versions given as labels, so that packages differing only in punctuation never collide.
+    fn identifiers(&self) -> BTreeMap<&Node, String> {
+        self.nodes
+            .iter()
+            .enumerate()
+            .map(|(index, node)| (node, format!("n{}", index)))
+            .collect()
+    }
+
+    /// Renders the graph in a graph language
+    ///
+    /// # Arguments
+    ///
+    /// * `format` - The language to render the graph in (required)
+    pub fn render(&self, format: GraphFormat) -> String {
+        match format {
+            GraphFormat::Dot => self.to_dot(),
+            GraphFormat::Mermaid => self.to_mermaid(),
+        }
+    }
+
+    /// Renders the graph in the Graphviz DOT language
+    pub fn to_dot(&self) -> String {
+        let identifiers = self.identifiers();
+        let mut dot = String::from("digraph gany {\n");
+        for node in &self.nodes {
+            let shape = if self.roots.contains(node) {
+                "box"
+            } else {
+                "ellipse"
+            };
+            writeln!(
+                dot,
+                "    {} [label=\"{}\", shape={}];",
+                identifiers[node],
+                escape_dot(&node.label()),
+                shape
+            )
+            .unwrap();
+        }
+        for edge in &self.edges {
+            let (verb, style) = match edge.kind {
+                EdgeKind::Dependency => ("depends", "solid"),
+                EdgeKind::Conflict => ("conflicts", "dashed"),
+            };
+            writeln!(
+                dot,
+                "    {} -> {} [label=\"{} {}\", style={}];",
+                identifiers[&edge.from],
+                identifiers[&edge.to],
+                verb,
+                escape_dot(&edge.requirement),
+                style
+            )
+            .unwrap();
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Renders the graph in the Mermaid language
+    pub fn to_mermaid(&self) -> String {
+        let identifiers = self.identifiers();
+        let mut mermaid = String::from("graph TD\n");
+        for node in &self.nodes {
+            let label = escape_mermaid(&node.label());
+            if self.roots.contains(node) {
+                writeln!(mermaid, "    {}[\"{}\"]", identifiers[node], label).unwrap();
+            } else {
+                writeln!(mermaid, "    {}(\"{}\")", identifiers[node], label).unwrap();
+            }
+        }
+        for edge in &self.edges {
+            let (verb, arrow) = match edge.kind {
+                EdgeKind::Dependency => ("depends", "-->"),
+                EdgeKind::Conflict => ("conflicts", "-.->"),
+            };
+            writeln!(
+                mermaid,
+                "    {} {}|\"{} {}\"| {}",
+                identifiers[&edge.from],
+                arrow,
+                verb,
+                escape_mermaid(&edge.requirement),
+                identifiers[&edge.to]
+            )
+            .unwrap();
+        }
+        mermaid
+    }
+}
+
+/// Builds a graph of the installed packages, rooted at those installed explicitly
+pub fn installed_graph() -> Result<DependencyGraph> {
+    let database = database::read_database()?;
+    let packages: HashSet<Package> = database
+        .packages
+        .values()
+        .map(|installed_package| installed_package.package.clone())
+        .collect();
+    let roots: HashSet<Package> = database
+        .packages
+        .values()
+        .filter(|installed_package| installed_package.explicit)
+        .map(|installed_package| installed_package.package.clone())
+        .collect();
+    Ok(DependencyGraph::from_packages(&packages, &roots))
+}
+
+/// Builds a graph of every package involved in installing some packages, rooted at those packages
+///
+/// # Arguments
+///
+/// * `names` - The names of the packages being installed (required)
+pub async fn transaction_graph(names: &[String]) -> Result<DependencyGraph> {
+    let local_packages: HashSet<Package> = HashSet::new();
+    let mut roots: HashSet<Package> = HashSet::new();
+    for name in names {
+        roots.insert(transaction::find_newest_package(name, &local_packages).await?);
+    }
+    let packages = package::crawl_package_tree(&roots, &local_packages).await?;
+    Ok(DependencyGraph::from_packages(&packages, &roots))
+}
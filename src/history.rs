@@ -0,0 +1,208 @@
+use crate::error::HistoryError;
+use crate::journal;
+use crate::metadata::{self, Compression};
+use crate::snapshot::Snapshot;
+use crate::transaction::PlannedChange;
+use miette::Result;
+use serde::{Deserialize, Serialize};
+#[cfg(unix)]
+use std::ffi::CStr;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The file recording every transaction committed on this system
+pub const HISTORY_FILE: &str = "/etc/gany/gany-history.bin";
+
+/// A transaction committed on this system
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// The identifier of the transaction, counting up from one
+    pub id: u64,
+    /// When the transaction was committed, in seconds since the Unix epoch
+    pub timestamp: u64,
+    /// The command line the transaction was committed by
+    pub command_line: Vec<String>,
+    /// The name of the user the transaction was committed by
+    pub user: String,
+    /// The real user ID the transaction was committed by
+    #[serde(default)]
+    pub uid: Option<u32>,
+    /// The user who claimed to have invoked `sudo` to commit the transaction, which is not verified
+    #[serde(default)]
+    pub sudo_user: Option<String>,
+    /// The changes the transaction made to each package
+    pub changes: Vec<PlannedChange>,
+    /// The snapshots taken around the transaction
+    #[serde(default)]
+    pub snapshots: Vec<Snapshot>,
+}
+
+/// The record of every transaction committed on this system
+#[derive(Eq, PartialEq, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct History {
+    /// The committed transactions, oldest first
+    #[serde(default)]
+    pub entries: Vec<HistoryEntry>,
+}
+
+impl History {
+    /// The identifier the next committed transaction will be given
+    pub fn next_id(&self) -> u64 {
+        self.entries.last().map(|entry| entry.id + 1).unwrap_or(1)
+    }
+
+    /// Finds a committed transaction by its identifier
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The identifier of a transaction (required)
+    pub fn get(&self, id: u64) -> Option<&HistoryEntry> {
+        self.entries.iter().find(|entry| entry.id == id)
+    }
+}
+
+/// Determines the real user running Gany, by their user ID and name
+///
+/// The user is looked up by the real user ID of the process, rather than environment variables any user could set.
+#[cfg(unix)]
+fn current_user() -> (Option<u32>, String) {
+    // SAFETY: `getuid` always succeeds
+    let uid = unsafe { libc::getuid() };
+    let mut passwd = std::mem::MaybeUninit::<libc::passwd>::uninit();
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    let mut buffer: Vec<libc::c_char> = vec![0; 16384];
+    // SAFETY: every pointer given is valid for the length given, and the record is only read if one was found
+    let name = unsafe {
+        let status = libc::getpwuid_r(
+            uid,
+            passwd.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        );
+        if status == 0 && !result.is_null() && !(*result).pw_name.is_null() {
+            Some(
+                CStr::from_ptr((*result).pw_name)
+                    .to_string_lossy()
+                    .into_owned(),
+            )
+        } else {
+            None
+        }
+    };
+    // Users without an account record are known only by their ID
+    (Some(uid), name.unwrap_or_else(|| uid.to_string()))
+}
+
+/// Determines the real user running Gany, by their user ID and name
+#[cfg(not(unix))]
+fn current_user() -> (Option<u32>, String) {
+    (None, std::env::var("USERNAME").unwrap_or_default())
+}
+
+/// Describes a transaction about to be committed by this process
+///
+/// # Arguments
+///
+/// * `changes` - The changes the transaction makes to each package (required)
+pub fn new_entry(changes: Vec<PlannedChange>) -> Result<HistoryEntry> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    let (uid, user) = current_user();
+    Ok(HistoryEntry {
+        id: read_history()?.next_id(),
+        timestamp,
+        command_line: std::env::args().collect(),
+        user,
+        uid,
+        sudo_user: std::env::var("SUDO_USER").ok(),
+        changes,
+        snapshots: Vec::new(),
+    })
+}
+
+/// Reads the record of committed transactions from the filesystem
+///
+/// If no transactions have been committed yet, an empty record is returned.
+pub fn read_history() -> Result<History> {
+    read_history_file(Path::new(HISTORY_FILE))
+}
+
+/// Reads the record of committed transactions from a file
+///
+/// # Arguments
+///
+/// * `path` - The path of the record (required)
+fn read_history_file(path: &Path) -> Result<History> {
+    let history_file = match fs::read(path) {
+        Ok(history_file) => history_file,
+        Err(_) => return Ok(History::default()),
+    };
+    Ok(metadata::decode(&history_file).map_err(|_| HistoryError::UnableToDeserialiseHistory)?)
+}
+
+/// Adds a committed transaction to the record of committed transactions
+///
+/// A transaction already recorded is not recorded again, so that an interrupted transaction may be completed more than once.
+///
+/// # Arguments
+///
+/// * `entry` - The committed transaction (required)
+///
+/// * `root` - The directory packages are installed into, beneath which the record is kept (required)
+pub fn record_transaction(entry: &HistoryEntry, root: &Path) -> Result<()> {
+    let history_path = journal::rooted_path(Path::new(HISTORY_FILE), root);
+    let mut history = read_history_file(&history_path)?;
+    if history.get(entry.id).is_some() {
+        return Ok(());
+    }
+    history.entries.push(entry.clone());
+    write_history(&history, &history_path)
+}
+
+/// Records a snapshot taken after a transaction was committed
+///
+/// # Arguments
+///
+/// * `id` - The identifier of the transaction (required)
+///
+/// * `snapshot` - The snapshot taken (required)
+pub fn record_snapshot(id: u64, snapshot: Snapshot) -> Result<()> {
+    let mut history = read_history()?;
+    let entry = history
+        .entries
+        .iter_mut()
+        .find(|entry| entry.id == id)
+        .ok_or(HistoryError::TransactionNotFound(id))?;
+    entry.snapshots.push(snapshot);
+    write_history(&history, Path::new(HISTORY_FILE))
+}
+
+/// Writes the record of committed transactions to the filesystem
+///
+/// # Arguments
+///
+/// * `history` - The record of committed transactions (required)
+///
+/// * `path` - The path of the record (required)
+fn write_history(history: &History, path: &Path) -> Result<()> {
+    let history_data = metadata::encode(history, Compression::Lz4)?;
+    journal::write_atomically(path, &history_data)
+        .map_err(|_| HistoryError::UnableToWriteHistory)?;
+    Ok(())
+}
+
+/// Finds a committed transaction by its identifier
+///
+/// # Arguments
+///
+/// * `id` - The identifier of a transaction (required)
+pub fn find_transaction(id: u64) -> Result<HistoryEntry> {
+    read_history()?
+        .get(id)
+        .cloned()
+        .ok_or_else(|| HistoryError::TransactionNotFound(id).into())
+}
@@ -0,0 +1,379 @@
+use crate::archive::{EntryKind, FileEntry};
+use crate::attributes::{self, FileAttributes};
+use crate::database::{self, Database};
+use crate::error::JournalError;
+use crate::history::{self, HistoryEntry};
+use crate::metadata::{self, Compression};
+use miette::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// The file describing a transaction that has not yet been fully committed, beneath the directory packages are installed into
+pub const JOURNAL_FILE: &str = "/etc/gany/gany-journal.bin";
+
+/// The suffix given to files staged alongside the path they will be renamed to
+pub const STAGING_SUFFIX: &str = ".gany-staged";
+
+/// How far a journaled transaction progressed
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum JournalState {
+    /// Files are being staged, and nothing has been replaced; an interrupted transaction is rolled back
+    Staging,
+    /// Every file has been staged, and the transaction must be completed; an interrupted transaction is rolled forward
+    Committed,
+}
+
+/// A directory of a package being installed, given its attributes once its transaction is committed
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct StagedDirectory {
+    /// The filesystem entry of the directory, with its attributes resolved for this system
+    pub entry: FileEntry,
+    /// The attributes the package specifies for the directory
+    pub attributes: Option<FileAttributes>,
+}
+
+/// A write-ahead record of a transaction, written before the filesystem is changed
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct Journal {
+    /// How far the transaction progressed
+    pub state: JournalState,
+    /// The installed paths of the staged entries, each staged alongside its installed path
+    pub staged_paths: Vec<PathBuf>,
+    /// The installed entries to be removed once the staged entries are in place
+    pub removed_entries: Vec<FileEntry>,
+    /// The record of installed packages once the transaction is committed
+    pub database: Database,
+    /// The record of the transaction in the history of committed transactions
+    #[serde(default)]
+    pub history_entry: Option<HistoryEntry>,
+    /// The installed paths of the directories that did not exist before the entries were staged
+    #[serde(default)]
+    pub created_directories: Vec<PathBuf>,
+    /// The directories of the packages being installed, which are only given their attributes once the staged entries are in place
+    #[serde(default)]
+    pub directories: Vec<StagedDirectory>,
+}
+
+/// Determines the path a file is staged at before being renamed into place
+///
+/// Files are staged in the same directory as their installed path, so that they are renamed within one filesystem.
+///
+/// # Arguments
+///
+/// * `path` - The path the file is installed to (required)
+pub fn staged_path(path: &Path) -> PathBuf {
+    let file_name = path
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{}{}", file_name, STAGING_SUFFIX))
+}
+
+/// Flushes the entry for a path in its parent directory to disk, so that a rename or removal survives power loss
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => fs::File::open(parent)?.sync_all(),
+        _ => Ok(()),
+    }
+}
+
+/// Flushes the entry for a path in its parent directory to disk, so that a rename or removal survives power loss
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Writes a file atomically, so that a crash leaves either its old or its new contents, and never a mix of both
+///
+/// # Arguments
+///
+/// * `path` - The path of the file (required)
+///
+/// * `data` - The new contents of the file (required)
+pub fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    let staged_path = staged_path(path);
+    let mut staged_file = fs::File::create(&staged_path)?;
+    staged_file.write_all(data)?;
+    staged_file.sync_all()?;
+    fs::rename(&staged_path, path)?;
+    sync_parent(path)
+}
+
+/// Maps an installed path onto the directory packages are installed into
+///
+/// # Arguments
+///
+/// * `path` - An installed path (required)
+///
+/// * `root` - The directory packages are installed into (required)
+pub(crate) fn rooted_path(path: &Path, root: &Path) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// Finds the directories that do not yet exist, and so would be created when entries are staged
+///
+/// # Arguments
+///
+/// * `directories` - The installed paths of the directories entries are staged into (required)
+///
+/// * `root` - The directory packages are installed into (required)
+pub fn missing_directories<'a>(
+    directories: impl IntoIterator<Item = &'a Path>,
+    root: &Path,
+) -> Vec<PathBuf> {
+    let mut missing_directories: BTreeSet<PathBuf> = BTreeSet::new();
+    for directory in directories {
+        for ancestor in directory.ancestors() {
+            if ancestor.parent().is_none() || ancestor.as_os_str().is_empty() {
+                break;
+            }
+            if fs::symlink_metadata(rooted_path(ancestor, root)).is_ok() {
+                break;
+            }
+            missing_directories.insert(ancestor.to_path_buf());
+        }
+    }
+    missing_directories.into_iter().collect()
+}
+
+/// Writes a journal to the filesystem
+///
+/// # Arguments
+///
+/// * `journal` - The journal of a transaction (required)
+///
+/// * `root` - The directory packages are installed into, beneath which the journal is kept (required)
+pub fn write_journal(journal: &Journal, root: &Path) -> Result<()> {
+    let journal_data = metadata::encode(journal, Compression::Lz4)?;
+    write_atomically(&rooted_path(Path::new(JOURNAL_FILE), root), &journal_data)
+        .map_err(|_| JournalError::UnableToWriteJournal)?;
+    Ok(())
+}
+
+/// Reads the journal of an interrupted transaction, if there is one
+///
+/// # Arguments
+///
+/// * `root` - The directory packages are installed into, beneath which the journal is kept (required)
+pub fn read_journal(root: &Path) -> Result<Option<Journal>> {
+    let journal_data = match fs::read(rooted_path(Path::new(JOURNAL_FILE), root)) {
+        Ok(journal_data) => journal_data,
+        Err(_) => return Ok(None),
+    };
+    Ok(Some(
+        metadata::decode(&journal_data).map_err(|_| JournalError::UnableToDeserialiseJournal)?,
+    ))
+}
+
+/// Removes the journal once its transaction is complete
+///
+/// # Arguments
+///
+/// * `root` - The directory packages are installed into, beneath which the journal is kept (required)
+fn remove_journal(root: &Path) -> Result<()> {
+    let journal_path = rooted_path(Path::new(JOURNAL_FILE), root);
+    fs::remove_file(&journal_path).map_err(|_| JournalError::UnableToWriteJournal)?;
+    sync_parent(&journal_path).map_err(|_| JournalError::UnableToWriteJournal)?;
+    Ok(())
+}
+
+/// Completes a committed transaction, moving its staged entries into place, giving its directories their attributes and removing replaced entries
+///
+/// Completing a transaction more than once has the same effect as completing it once.
+///
+/// # Arguments
+///
+/// * `journal` - The journal of a committed transaction (required)
+///
+/// * `root` - The directory packages are installed into (required)
+pub fn roll_forward(journal: &Journal, root: &Path) -> Result<()> {
+    for path in &journal.staged_paths {
+        let installed_path = rooted_path(path, root);
+        let staged_path = staged_path(&installed_path);
+        // An entry with nothing staged was moved into place before the transaction was interrupted
+        if fs::symlink_metadata(&staged_path).is_err() {
+            continue;
+        }
+        fs::rename(&staged_path, &installed_path)
+            .map_err(|_| JournalError::UnableToCommit(installed_path.clone()))?;
+        sync_parent(&installed_path)
+            .map_err(|_| JournalError::UnableToCommit(installed_path.clone()))?;
+    }
+    // Directories may be shared with the rest of the system, so they are only changed once the transaction is committed
+    for directory in &journal.directories {
+        attributes::apply_attributes(
+            &rooted_path(&directory.entry.path, root),
+            &directory.entry,
+            directory.attributes.as_ref(),
+        )?;
+    }
+    // Remove the deepest entries first, so that directories are empty by the time they are reached
+    let mut removed_entries: Vec<&FileEntry> = journal.removed_entries.iter().collect();
+    removed_entries.sort_by(|a, b| b.path.cmp(&a.path));
+    for entry in removed_entries {
+        let path = rooted_path(&entry.path, root);
+        // Entries already removed, and directories still holding other files, are left as they are
+        let _ = match entry.kind {
+            EntryKind::Directory => fs::remove_dir(&path),
+            _ => fs::remove_file(&path),
+        };
+    }
+    database::write_database(&journal.database, root)?;
+    if let Some(history_entry) = &journal.history_entry {
+        history::record_transaction(history_entry, root)?;
+    }
+    remove_journal(root)
+}
+
+/// Abandons a transaction that was never committed, removing whatever was staged
+///
+/// Directories created while staging are removed too, unless something else has since been placed in them.
+///
+/// # Arguments
+///
+/// * `journal` - The journal of an uncommitted transaction (required)
+///
+/// * `root` - The directory packages are installed into (required)
+pub fn roll_back(journal: &Journal, root: &Path) -> Result<()> {
+    for path in &journal.staged_paths {
+        let _ = fs::remove_file(staged_path(&rooted_path(path, root)));
+    }
+    // Remove the deepest directories first, so that their parents are empty by the time they are reached
+    for directory in journal.created_directories.iter().rev() {
+        let _ = fs::remove_dir(rooted_path(directory, root));
+    }
+    remove_journal(root)
+}
+
+/// Commits a transaction whose entries have all been staged
+///
+/// Once the journal is marked as committed, the transaction is completed even if it is interrupted.
+///
+/// # Arguments
+///
+/// * `journal` - The journal of a staged transaction (required)
+///
+/// * `root` - The directory packages are installed into (required)
+pub fn commit(mut journal: Journal, root: &Path) -> Result<()> {
+    journal.state = JournalState::Committed;
+    write_journal(&journal, root)?;
+    roll_forward(&journal, root)
+}
+
+/// Recovers from an interrupted transaction, if there was one
+///
+/// Committed transactions are rolled forward, and uncommitted ones are rolled back.
+///
+/// # Arguments
+///
+/// * `root` - The directory packages are installed into (required)
+pub fn recover(root: &Path) -> Result<Option<JournalState>> {
+    let journal = match read_journal(root)? {
+        Some(journal) => journal,
+        None => return Ok(None),
+    };
+    match journal.state {
+        JournalState::Staging => roll_back(&journal, root)?,
+        JournalState::Committed => roll_forward(&journal, root)?,
+    }
+    Ok(Some(journal.state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive;
+    use crate::database::DATABASE_FILE;
+
+    fn file_entry(path: &str) -> FileEntry {
+        FileEntry {
+            path: PathBuf::from(path),
+            kind: EntryKind::File,
+            size: 0,
+            digest: None,
+            target: None,
+            mode: None,
+            uid: None,
+            gid: None,
+        }
+    }
+
+    /// Lays out an interrupted transaction beneath a root, replacing one file, removing another and creating a directory
+    fn interrupted_transaction(root: &Path, state: JournalState) -> Journal {
+        fs::create_dir_all(root.join("etc/gany")).unwrap();
+        fs::create_dir_all(root.join("usr/bin")).unwrap();
+        fs::create_dir_all(root.join("usr/share/tool")).unwrap();
+        fs::write(root.join("usr/bin/tool"), "old").unwrap();
+        fs::write(staged_path(&root.join("usr/bin/tool")), "new").unwrap();
+        fs::write(root.join("usr/bin/obsolete"), "obsolete").unwrap();
+        let journal = Journal {
+            state,
+            staged_paths: vec![PathBuf::from("/usr/bin/tool")],
+            removed_entries: vec![file_entry("/usr/bin/obsolete")],
+            database: Database::default(),
+            history_entry: None,
+            created_directories: vec![PathBuf::from("/usr/share/tool")],
+            directories: vec![StagedDirectory {
+                entry: FileEntry {
+                    kind: EntryKind::Directory,
+                    mode: Some(0o700),
+                    ..file_entry("/usr/share/tool")
+                },
+                attributes: None,
+            }],
+        };
+        write_journal(&journal, root).unwrap();
+        journal
+    }
+
+    #[test]
+    fn interrupted_staging_is_rolled_back() {
+        let root = tempfile::tempdir().unwrap();
+        interrupted_transaction(root.path(), JournalState::Staging);
+        assert_eq!(recover(root.path()).unwrap(), Some(JournalState::Staging));
+        assert_eq!(
+            fs::read_to_string(root.path().join("usr/bin/tool")).unwrap(),
+            "old"
+        );
+        assert!(!staged_path(&root.path().join("usr/bin/tool")).exists());
+        assert!(root.path().join("usr/bin/obsolete").exists());
+        assert!(!root.path().join("usr/share/tool").exists());
+        assert!(!rooted_path(Path::new(DATABASE_FILE), root.path()).exists());
+        assert_eq!(read_journal(root.path()).unwrap(), None);
+        assert_eq!(recover(root.path()).unwrap(), None);
+    }
+
+    #[test]
+    fn interrupted_commit_is_rolled_forward() {
+        let root = tempfile::tempdir().unwrap();
+        let journal = interrupted_transaction(root.path(), JournalState::Committed);
+        assert_eq!(recover(root.path()).unwrap(), Some(JournalState::Committed));
+        assert_eq!(
+            fs::read_to_string(root.path().join("usr/bin/tool")).unwrap(),
+            "new"
+        );
+        assert!(!staged_path(&root.path().join("usr/bin/tool")).exists());
+        assert!(!root.path().join("usr/bin/obsolete").exists());
+        assert_eq!(
+            archive::entry_mode(&fs::metadata(root.path().join("usr/share/tool")).unwrap()),
+            Some(0o700)
+        );
+        let database_data = fs::read(rooted_path(Path::new(DATABASE_FILE), root.path())).unwrap();
+        assert_eq!(
+            metadata::decode::<Database>(&database_data).unwrap(),
+            journal.database
+        );
+        assert_eq!(read_journal(root.path()).unwrap(), None);
+        // Completing the transaction again changes nothing
+        write_journal(&journal, root.path()).unwrap();
+        roll_forward(&journal, root.path()).unwrap();
+        assert_eq!(
+            fs::read_to_string(root.path().join("usr/bin/tool")).unwrap(),
+            "new"
+        );
+    }
+}
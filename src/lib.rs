@@ -1,6 +1,24 @@
+pub mod archive;
+pub mod attributes;
+pub mod config;
+pub mod database;
 mod error;
-mod package;
-mod repository;
+pub mod explain;
+pub mod graph;
+pub mod history;
+pub mod journal;
+pub mod metadata;
+pub mod package;
+pub mod query;
+pub mod repository;
+pub mod scripts;
+pub mod signature;
+pub mod snapshot;
+pub mod transaction;
+pub mod triggers;
+pub mod verify;
+
+use lazy_static::lazy_static;
 
 lazy_static! {
     static ref ARCH: String = std::env::consts::ARCH.to_string();
@@ -1,10 +1,13 @@
-#![feature(panic_info_message)]
-#![feature(error_reporter)]
-mod lib;
+use gany as lib;
 
+use anyhow::Context;
 use clap::{clap_app, crate_version, ArgMatches};
 use lazy_static::lazy_static;
+use miette::IntoDiagnostic;
 use mimalloc::MiMalloc;
+use serde::Serialize;
+use std::io::Write;
+use std::path::Path;
 
 #[global_allocator]
 /// The global memory allocator
@@ -16,6 +19,7 @@ lazy_static! {
         (version: crate_version!())
         (author: "Emil Sayahi")
         (about: "Gany is a performant package manager written in Rust.")
+        (@arg output: --output +global +takes_value possible_values(&["text", "json"]) default_value("text") "Format of output")
         (@subcommand show =>
             (about: "Shows information regarding the usage and handling of this software")
             (@arg warranty: -w --warranty "Prints warranty information")
@@ -23,81 +27,1366 @@ lazy_static! {
         )
         (@subcommand add =>
             (about: "Add a package to your software installation")
-            (@arg PACKAGE_NAME: +required +takes_value "Name of a package")
+            (@arg PACKAGES: +required +takes_value +multiple "Names of packages, or paths to package archives")
+            (@arg yes: -y --yes "Commits the transaction without asking for confirmation")
+            (@arg ("dry-run"): -n --("dry-run") "Stops once the transaction is planned")
+            (@arg ("no-scripts"): --("no-scripts") "Commits the transaction without running package scripts")
         )
         (@subcommand drop =>
           (about: "Drop a package from your software installation")
-          (@arg PACKAGE_NAME: +required +takes_value "Name of a package")
+          (@arg PACKAGES: +required +takes_value +multiple "Names of packages")
+          (@arg yes: -y --yes "Commits the transaction without asking for confirmation")
+          (@arg ("dry-run"): -n --("dry-run") "Stops once the transaction is planned")
+          (@arg ("no-scripts"): --("no-scripts") "Commits the transaction without running package scripts")
         )
         (@subcommand refresh =>
             (about: "Refresh the local package repository with one from a remote software distribution")
         )
         (@subcommand upgrade =>
           (about: "Upgrade your local packages with newer versions held in a remote software distribution")
+          (@arg yes: -y --yes "Commits the transaction without asking for confirmation")
+          (@arg ("dry-run"): -n --("dry-run") "Stops once the transaction is planned")
+          (@arg ("no-scripts"): --("no-scripts") "Commits the transaction without running package scripts")
         )
         (@subcommand package =>
             (about: "Package a piece of software for future distribution")
             (@arg PATH: +required +takes_value "Path to some software")
+            (@arg manifest: -m --manifest +required +takes_value "Path to a manifest describing the software")
+            (@arg destination: -d --destination +takes_value "Path to a directory to write the package to")
+            (@arg key: -k --key +takes_value "Path to a key to sign the package with")
         )
         (@subcommand extract =>
           (about: "Extract a software package")
           (@arg PATH: +required +takes_value "Path to a package")
+          (@arg destination: -d --destination +takes_value "Path to a directory to extract the package into")
       )
+        (@subcommand inspect =>
+            (about: "Inspect a software package")
+            (@arg PATH: +required +takes_value "Path to a package")
+            (@arg verify: -v --verify "Verifies the integrity and signature of the package")
+        )
+        (@subcommand owns =>
+            (about: "Find the installed packages that own some files")
+            (@arg PATHS: +required +takes_value +multiple "Paths to files, or glob patterns matching them")
+        )
+        (@subcommand files =>
+            (about: "List the files of a package, whether installed or not")
+            (@arg PACKAGE_NAME: +required +takes_value "Name of a package")
+            (@arg ghost: -g --ghost "Lists ghost files")
+            (@arg directories: -d --directories "Lists directories")
+            (@arg config: -c --config "Lists configuration files")
+        )
+        (@subcommand search =>
+            (about: "Search for packages by name and description")
+            (@arg PATTERN: +required +takes_value "Pattern to search for")
+            (@arg regex: -r --regex conflicts_with[glob] "Treats the pattern as a regular expression")
+            (@arg glob: -g --glob conflicts_with[regex] "Treats the pattern as a glob pattern")
+            (@arg files: -f --files "Also searches the files provided by packages")
+        )
+        (@subcommand info =>
+            (about: "Show information about every available version of a package")
+            (@arg PACKAGE_NAME: +required +takes_value "Name of a package")
+        )
+        (@subcommand list =>
+            (about: "List the packages in your software installation")
+            (@arg explicit: -e --explicit "Lists packages that were requested explicitly")
+            (@arg dependencies: -d --dependencies "Lists packages that were installed as dependencies")
+            (@arg orphans: -o --orphans "Lists packages that are no longer required as dependencies")
+            (@arg upgradable: -u --upgradable "Lists packages with newer versions available")
+            (@arg foreign: -f --foreign "Lists packages that are no longer in any repository")
+        )
+        (@subcommand graph =>
+            (about: "Graph the relationships between packages")
+            (@arg PACKAGES: +takes_value +multiple "Names of packages to graph the installation of, rather than the installed packages")
+            (@arg format: -f --format +takes_value possible_values(&["dot", "mermaid"]) default_value("dot") "Graph language to render in")
+            (@arg why: -w --why +takes_value "Only graphs the chain of dependencies leading to a package")
+        )
+        (@subcommand why =>
+            (about: "Explain why a package is installed")
+            (@arg PACKAGE_NAME: +required +takes_value "Name of an installed package")
+        )
+        (@subcommand why_not =>
+            (name: "why-not")
+            (about: "Explain what prevents a version of a package from being installed")
+            (@arg PACKAGE_NAME: +required +takes_value "Name of a package")
+            (@arg VERSION: +required +takes_value "Version of the package")
+        )
+        (@subcommand config_diff =>
+            (name: "config-diff")
+            (about: "Compare modified configuration files with the new versions written beside them")
+            (@arg PACKAGE_NAME: +takes_value "Name of an installed package")
+        )
+        (@subcommand verify =>
+            (about: "Verify installed files against the records of their packages")
+            (@arg PACKAGE_NAME: +takes_value "Name of an installed package")
+        )
+        (@subcommand history =>
+            (about: "Browse the transactions committed on this system")
+            (@subcommand show =>
+                (about: "Show the changes made by a transaction")
+                (@arg ID: +required +takes_value "Identifier of a transaction")
+            )
+            (@subcommand undo =>
+                (about: "Reverse the changes made by a transaction")
+                (@arg ID: +required +takes_value "Identifier of a transaction")
+                (@arg yes: -y --yes "Commits the transaction without asking for confirmation")
+                (@arg ("dry-run"): -n --("dry-run") "Stops once the transaction is planned")
+                (@arg ("no-scripts"): --("no-scripts") "Commits the transaction without running package scripts")
+            )
+        )
+        (@subcommand rollback =>
+            (about: "Reverse the changes made by the most recent transaction")
+            (@arg yes: -y --yes "Commits the transaction without asking for confirmation")
+            (@arg ("dry-run"): -n --("dry-run") "Stops once the transaction is planned")
+            (@arg ("no-scripts"): --("no-scripts") "Commits the transaction without running package scripts")
+        )
+        (@subcommand repo =>
+            (about: "Manage software repositories")
+            (@subcommand build =>
+                (about: "Build a repository from a directory of package archives")
+                (@arg DIRECTORY: +required +takes_value "Path to a directory of package archives")
+                (@arg name: -n --name +required +takes_value "Name of the repository")
+                (@arg description: -d --description +takes_value "Description of the repository")
+                (@arg address: -a --address +required +takes_value "Address the repository will be served from")
+                (@arg key: -k --key +takes_value "Path to a key to sign the repository with")
+                (@arg index: -i --index +takes_value possible_values(&["yaml", "json"]) "Also write a human-readable index of the repository")
+            )
+            (@subcommand keygen =>
+                (about: "Generate a key for signing repositories")
+                (@arg PATH: +required +takes_value "Path to write the signing key to")
+            )
+            (@subcommand migrate =>
+                (about: "Migrate repository data to the current metadata format")
+                (@arg INPUT: +required +takes_value "Path to existing repository data")
+                (@arg OUTPUT: +takes_value "Path to write the migrated repository data to, if not in place")
+            )
+        )
     );
 
     /// The arguments passed to the Gany CLI
     static ref MATCHES: ArgMatches = APP.clone().get_matches();
 }
 
+/// Determines the message a panic was raised with
+///
+/// # Arguments
+///
+/// * `panic_info` - Information about the panic (required)
+fn panic_message(panic_info: &std::panic::PanicHookInfo) -> String {
+    let payload = panic_info.payload();
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_default()
+}
+
 /// The main function of Gany's CLI
-fn main() {
-    std::panic::set_hook(Box::new(|e| {
-        println!(
-            "{}\nDefined in: {}:{}:{}",
-            format!("{}", e.message().unwrap())
-                .replace("called `Result::unwrap()` on an `Err` value", "Error"),
-            e.location().unwrap().file(),
-            e.location().unwrap().line(),
-            e.location().unwrap().column()
-        );
+#[tokio::main]
+async fn main() {
+    // Whether JSON was requested is determined up front, so the panic hook never parses arguments itself
+    let json_output = output_json();
+    std::panic::set_hook(Box::new(move |e| {
+        let message =
+            panic_message(e).replace("called `Result::unwrap()` on an `Err` value", "Error");
+        let location = e
+            .location()
+            .map(|location| {
+                format!(
+                    "{}:{}:{}",
+                    location.file(),
+                    location.line(),
+                    location.column()
+                )
+            })
+            .unwrap_or_default();
+        if json_output {
+            let panic_json = serde_json::json!({
+                "error": {
+                    "code": "panic",
+                    "message": message,
+                    "location": location,
+                }
+            });
+            eprintln!("{}", serde_json::to_string_pretty(&panic_json).unwrap());
+        } else {
+            eprintln!("{}\nDefined in: {}", message, location);
+        }
     }));
 
-    println!(
-        "
+    // Machine-readable output must not be interleaved with the notice, so it is sent elsewhere
+    let notice = "
     Gany  Copyright (C) 2020-2024  Emil Sayahi
     This program comes with ABSOLUTELY NO WARRANTY; for details type `gany show -w'.
     This is free software, and you are welcome to redistribute it
     under certain conditions; type `gany show -c' for details.
-    "
-    );
+    ";
+    if output_json() {
+        eprintln!("{}", notice);
+    } else {
+        println!("{}", notice);
+    }
 
-    match MATCHES.subcommand() {
+    // A transaction interrupted by a crash or power loss is completed or abandoned before another is planned
+    let history_subcommand = MATCHES
+        .subcommand_matches("history")
+        .and_then(|history_matches| history_matches.subcommand_name());
+    let mutating = matches!(
+        MATCHES.subcommand_name(),
+        Some("add" | "drop" | "upgrade" | "rollback")
+    ) || history_subcommand == Some("undo");
+    if mutating {
+        match lib::journal::recover(Path::new(lib::transaction::INSTALL_ROOT)) {
+            Ok(Some(lib::journal::JournalState::Committed)) => {
+                eprintln!("Completed a transaction that was interrupted after being committed.")
+            }
+            Ok(Some(lib::journal::JournalState::Staging)) => {
+                eprintln!("Abandoned a transaction that was interrupted before being committed.")
+            }
+            Ok(None) => (),
+            Err(error) => {
+                report_error(&error);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let result = match MATCHES.subcommand() {
         Some(("show", show_matches)) => {
             show(show_matches);
+            Ok(())
         }
-        Some(("add", add_matches)) => {
-            lib::add_package(add_matches);
+        Some(("add", add_matches)) => add(add_matches).await,
+        Some(("drop", drop_matches)) => drop_packages(drop_matches).await,
+        Some(("refresh", _)) => refresh().await,
+        Some(("upgrade", upgrade_matches)) => upgrade(upgrade_matches).await,
+        Some(("package", package_matches)) => package(package_matches),
+        Some(("extract", extract_matches)) => extract(extract_matches),
+        Some(("inspect", inspect_matches)) => inspect(inspect_matches),
+        Some(("owns", owns_matches)) => owns(owns_matches),
+        Some(("files", files_matches)) => files(files_matches),
+        Some(("search", search_matches)) => search(search_matches),
+        Some(("info", info_matches)) => info(info_matches),
+        Some(("list", list_matches)) => list(list_matches),
+        Some(("graph", graph_matches)) => graph(graph_matches).await,
+        Some(("why", why_matches)) => why(why_matches),
+        Some(("why-not", why_not_matches)) => why_not(why_not_matches).await,
+        Some(("config-diff", config_diff_matches)) => config_diff(config_diff_matches),
+        Some(("verify", verify_matches)) => verify(verify_matches),
+        Some(("history", history_matches)) => match history_matches.subcommand() {
+            Some(("show", show_matches)) => show_transaction(show_matches),
+            Some(("undo", undo_matches)) => undo(undo_matches).await,
+            _ => history(),
+        },
+        Some(("rollback", rollback_matches)) => rollback(rollback_matches).await,
+        Some(("repo", repo_matches)) => match repo_matches.subcommand() {
+            Some(("build", build_matches)) => build_repository(build_matches),
+            Some(("keygen", keygen_matches)) => generate_signing_key(keygen_matches),
+            Some(("migrate", migrate_matches)) => migrate_repository(migrate_matches),
+            _ => {
+                println!("{}", APP.get_about().unwrap());
+                Ok(())
+            }
+        },
+        None => {
+            println!("{}", APP.get_about().unwrap());
+            Ok(())
         }
-        Some(("drop", drop_matches)) => {
-            lib::drop_package(drop_matches);
+        _ => unreachable!(), // If all subcommands are defined above, anything else is unreachable!()
+    };
+    if let Err(error) = result {
+        report_error(&error);
+        std::process::exit(1);
+    }
+}
+
+/// Whether machine-readable JSON output was requested
+fn output_json() -> bool {
+    MATCHES.value_of("output") == Some("json")
+}
+
+/// Prints a value as JSON
+///
+/// # Arguments
+///
+/// * `value` - The value to be printed (required)
+fn print_json<T: Serialize>(value: &T) -> miette::Result<()> {
+    println!("{}", serde_json::to_string_pretty(value).into_diagnostic()?);
+    Ok(())
+}
+
+/// Reports an error, as JSON if machine-readable output was requested
+///
+/// JSON errors carry the diagnostic code of the error, such as `fetch_repositories::unable_to_read_repository_list`.
+///
+/// # Arguments
+///
+/// * `error` - The error to be reported (required)
+fn report_error(error: &miette::Report) {
+    if output_json() {
+        let error_json = serde_json::json!({
+            "error": {
+                "code": error.code().map(|code| code.to_string()),
+                "message": error.to_string(),
+                "help": error.help().map(|help| help.to_string()),
+            }
+        });
+        println!("{}", serde_json::to_string_pretty(&error_json).unwrap());
+    } else {
+        eprintln!("{:?}", error);
+    }
+}
+
+/// Formats a number of bytes for display
+///
+/// # Arguments
+///
+/// * `bytes` - A number of bytes (required)
+fn format_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, units[unit])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+/// Formats a change in a number of bytes for display
+///
+/// # Arguments
+///
+/// * `delta` - A change in a number of bytes (required)
+fn format_size_delta(delta: i64) -> String {
+    let sign = if delta < 0 { "-" } else { "+" };
+    format!("{}{}", sign, format_size(delta.unsigned_abs()))
+}
+
+/// Prints the changes a transaction makes to each package
+///
+/// # Arguments
+///
+/// * `changes` - The changes made to each package (required)
+fn print_changes(changes: &[lib::transaction::PlannedChange]) {
+    for change in changes {
+        let versions = match (&change.installed_version, &change.version) {
+            (Some(installed_version), Some(version)) if installed_version != version => {
+                format!("{} -> {}", installed_version, version)
+            }
+            (_, Some(version)) => version.to_string(),
+            (Some(installed_version), None) => installed_version.to_string(),
+            (None, None) => String::new(),
+        };
+        let kind = match change.kind {
+            lib::transaction::ChangeKind::Install => "install",
+            lib::transaction::ChangeKind::Upgrade => "upgrade",
+            lib::transaction::ChangeKind::Downgrade => "downgrade",
+            lib::transaction::ChangeKind::Reinstall => "reinstall",
+            lib::transaction::ChangeKind::Remove => "remove",
+        };
+        println!(
+            "  {:<10} {} {} ({})",
+            kind,
+            change.name,
+            versions,
+            format_size_delta(change.installed_size_delta)
+        );
+    }
+}
+
+/// Prints the changes a transaction will make
+///
+/// # Arguments
+///
+/// * `plan` - The plan of the transaction (required)
+fn print_plan(plan: &lib::transaction::TransactionPlan) {
+    print_changes(&plan.changes);
+    println!("Download size: {}", format_size(plan.download_size));
+    println!(
+        "Installed size change: {}",
+        format_size_delta(plan.installed_size_delta)
+    );
+}
+
+/// Asks the user to confirm a transaction
+fn confirm() -> miette::Result<bool> {
+    print!("Proceed with the transaction? [y/N] ");
+    std::io::stdout().flush().into_diagnostic()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).into_diagnostic()?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Presents the plan of a transaction, committing it once confirmed
+///
+/// Every command that commits a transaction accepts the flags below.
+///
+/// # Arguments
+///
+/// * `plan` - The plan of the transaction (required)
+///
+/// * `yes` - Commits the transaction without asking for confirmation, which JSON output requires unless `dry-run` is given
+///
+/// * `dry-run` - Stops once the transaction is planned
+///
+/// * `no-scripts` - Commits the transaction without running package scripts
+async fn commit_plan(
+    plan: &lib::transaction::TransactionPlan,
+    matches: &clap::ArgMatches,
+) -> miette::Result<()> {
+    let dry_run = matches.is_present("dry-run");
+    // A transaction cannot be confirmed without its plan being shown, which JSON output only does once it is done
+    if output_json() && !plan.is_empty() && !dry_run && !matches.is_present("yes") {
+        return Err(miette::miette!(
+            code = "transaction::confirmation_unavailable",
+            "Unable to ask for confirmation of a transaction while producing JSON output; pass --yes to commit it, or --dry-run to only plan it."
+        ));
+    }
+    if !output_json() && !plan.is_empty() {
+        println!("The following changes will be made:");
+        print_plan(plan);
+    }
+    let committed = !plan.is_empty() && !dry_run && (matches.is_present("yes") || confirm()?);
+    let report = if committed {
+        Some(lib::transaction::commit_plan(plan, !matches.is_present("no-scripts")).await?)
+    } else {
+        None
+    };
+    if output_json() {
+        return print_json(&serde_json::json!({
+            "plan": plan,
+            "committed": committed,
+            "report": report,
+        }));
+    }
+    if let Some(report) = &report {
+        for trigger in &report.triggers_run {
+            println!("Ran the '{}' trigger.", trigger);
         }
-        Some(("refresh", _)) => {
-            lib::repository::fetch_repositories(true);
+        for script_failure in &report.script_failures {
+            eprintln!("Warning: {}.", script_failure);
         }
-        Some(("upgrade", _)) => {
-            lib::upgrade_packages();
+        for trigger_failure in &report.trigger_failures {
+            eprintln!("Warning: {}.", trigger_failure);
         }
-        None => println!("{}", APP.get_about().unwrap()),
-        _ => unreachable!(), // If all subcommands are defined above, anything else is unreachable!()
+        if let Some(snapshot_failure) = &report.snapshot_failure {
+            eprintln!("Warning: {}", snapshot_failure);
+        }
+        for path in &report.preserved_config_files {
+            println!(
+                "Kept the modified {}; the new version was written to {}.",
+                path.display(),
+                lib::config::new_config_path(path).display()
+            );
+        }
+    }
+    if plan.is_empty() {
+        println!("Nothing to do.");
+    } else if dry_run {
+        println!("Dry run; no changes were made.");
+    } else if committed {
+        println!("Transaction committed.");
+    } else {
+        println!("Transaction cancelled.");
+    }
+    Ok(())
+}
+
+/// Adds packages to your software installation
+///
+/// # Arguments
+///
+/// * `PACKAGES` - Names of packages, or paths to package archives
+///
+/// * `yes` - Commits the transaction without asking for confirmation
+///
+/// * `dry-run` - Stops once the transaction is planned
+async fn add(matches: &clap::ArgMatches) -> miette::Result<()> {
+    let requests: Vec<String> = matches
+        .values_of("PACKAGES")
+        .with_context(|| "No packages were given".to_string())
+        .unwrap()
+        .map(|request| request.to_owned())
+        .collect();
+    let plan = lib::transaction::plan_addition(&requests).await?;
+    commit_plan(&plan, matches).await
+}
+
+/// Drops packages from your software installation
+///
+/// # Arguments
+///
+/// * `PACKAGES` - Names of packages
+///
+/// * `yes` - Commits the transaction without asking for confirmation
+///
+/// * `dry-run` - Stops once the transaction is planned
+async fn drop_packages(matches: &clap::ArgMatches) -> miette::Result<()> {
+    let names: Vec<String> = matches
+        .values_of("PACKAGES")
+        .with_context(|| "No packages were given".to_string())
+        .unwrap()
+        .map(|name| name.to_owned())
+        .collect();
+    let plan = lib::transaction::plan_removal(&names)?;
+    commit_plan(&plan, matches).await
+}
+
+/// Upgrades your local packages with newer versions held in a remote software distribution
+///
+/// # Arguments
+///
+/// * `yes` - Commits the transaction without asking for confirmation
+///
+/// * `dry-run` - Stops once the transaction is planned
+async fn upgrade(matches: &clap::ArgMatches) -> miette::Result<()> {
+    let plan = lib::transaction::plan_upgrade().await?;
+    commit_plan(&plan, matches).await
+}
+
+/// Refreshes the local package repository with one from a remote software distribution
+async fn refresh() -> miette::Result<()> {
+    let repositories = lib::repository::fetch_repositories(true).await?;
+    if output_json() {
+        return print_json(&repositories);
+    }
+    for repository in &repositories {
+        println!(
+            "Refreshed '{}' with {} package(s).",
+            repository.name,
+            repository
+                .packages
+                .as_ref()
+                .map(|packages| packages.len())
+                .unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+/// Finds the installed packages that own some files
+///
+/// # Arguments
+///
+/// * `PATHS` - Paths to files, or glob patterns matching them
+fn owns(matches: &clap::ArgMatches) -> miette::Result<()> {
+    let patterns: Vec<String> = matches
+        .values_of("PATHS")
+        .with_context(|| "No paths were given".to_string())
+        .unwrap()
+        .map(|pattern| pattern.to_owned())
+        .collect();
+    let ownerships = lib::query::find_owners(&patterns)?;
+    if output_json() {
+        return print_json(&ownerships);
+    }
+    if ownerships.is_empty() {
+        println!("No files match the given paths.");
+    }
+    for ownership in &ownerships {
+        if ownership.owners.is_empty() {
+            println!("{} is not owned by any package", ownership.path.display());
+        }
+        for (name, version) in &ownership.owners {
+            println!(
+                "{} is owned by {} {}",
+                ownership.path.display(),
+                name,
+                version
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Lists the files of a package, whether installed or not
+///
+/// # Arguments
+///
+/// * `PACKAGE_NAME` - Name of a package
+///
+/// * `ghost` - Lists ghost files
+///
+/// * `directories` - Lists directories
+///
+/// * `config` - Lists configuration files
+fn files(matches: &clap::ArgMatches) -> miette::Result<()> {
+    let name = matches
+        .value_of("PACKAGE_NAME")
+        .with_context(|| "No package name was given".to_string())
+        .unwrap();
+    let filter = lib::query::FileFilter {
+        ghost: matches.is_present("ghost"),
+        directories: matches.is_present("directories"),
+        config: matches.is_present("config"),
+    };
+    let package_files = lib::query::list_files(name, filter)?;
+    if output_json() {
+        return print_json(&package_files);
+    }
+    for file in &package_files.files {
+        let mut markers: Vec<&str> = Vec::new();
+        if file.kind == Some(lib::archive::EntryKind::Directory) {
+            markers.push("directory");
+        }
+        if file.config {
+            markers.push("config");
+        }
+        if file.ghost {
+            markers.push("ghost");
+        }
+        if markers.is_empty() {
+            println!("{}", file.path.display());
+        } else {
+            println!("{} ({})", file.path.display(), markers.join(", "));
+        }
+    }
+    Ok(())
+}
+
+/// Searches for packages by name and description
+///
+/// # Arguments
+///
+/// * `PATTERN` - Pattern to search for
+///
+/// * `regex` - Treats the pattern as a regular expression
+///
+/// * `glob` - Treats the pattern as a glob pattern
+///
+/// * `files` - Also searches the files provided by packages
+fn search(matches: &clap::ArgMatches) -> miette::Result<()> {
+    let pattern = matches
+        .value_of("PATTERN")
+        .with_context(|| "No search pattern was given".to_string())
+        .unwrap();
+    let mode = if matches.is_present("regex") {
+        lib::query::MatchMode::Regex
+    } else if matches.is_present("glob") {
+        lib::query::MatchMode::Glob
+    } else {
+        lib::query::MatchMode::Substring
+    };
+    let search_results = lib::query::search_packages(pattern, mode, matches.is_present("files"))?;
+    if output_json() {
+        return print_json(&search_results);
+    }
+    if search_results.is_empty() {
+        println!("No packages match '{}'.", pattern);
+    }
+    for search_result in &search_results {
+        println!(
+            "{}/{} {} ({})\n    {}",
+            search_result.repository,
+            search_result.package.name,
+            search_result.package.version,
+            search_result.package.arch,
+            search_result.package.description
+        );
+        for path in &search_result.matched_files {
+            println!("    {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Formats a set of package requirements for display
+///
+/// # Arguments
+///
+/// * `requirements` - A set of package requirements (required)
+fn format_requirements(
+    requirements: &Option<std::collections::HashSet<lib::package::PackageRequirement>>,
+) -> String {
+    let mut requirements: Vec<String> = requirements
+        .iter()
+        .flatten()
+        .map(|requirement| format!("{} {}", requirement.name, requirement.version))
+        .collect();
+    if requirements.is_empty() {
+        return "None".to_owned();
+    }
+    requirements.sort();
+    requirements.join(", ")
+}
+
+/// Prints a dependency tree, indented by depth
+///
+/// Packages whose dependencies are listed elsewhere in the tree are marked with `(*)`.
+///
+/// # Arguments
+///
+/// * `nodes` - The dependencies at this depth of the tree (required)
+///
+/// * `depth` - The depth of the tree (required)
+fn print_dependency_tree(nodes: &[lib::query::DependencyNode], depth: usize) {
+    for node in nodes {
+        match &node.fulfilled_by {
+            Some((name, version)) => println!(
+                "{}{} {} => {} {}{}",
+                "  ".repeat(depth),
+                node.requirement.name,
+                node.requirement.version,
+                name,
+                version,
+                if node.repeated { " (*)" } else { "" }
+            ),
+            None => println!(
+                "{}{} {} => unfulfilled",
+                "  ".repeat(depth),
+                node.requirement.name,
+                node.requirement.version
+            ),
+        }
+        print_dependency_tree(&node.dependencies, depth + 1);
+    }
+}
+
+/// Shows information about every available version of a package
+///
+/// # Arguments
+///
+/// * `PACKAGE_NAME` - Name of a package
+fn info(matches: &clap::ArgMatches) -> miette::Result<()> {
+    let name = matches
+        .value_of("PACKAGE_NAME")
+        .with_context(|| "No package name was given".to_string())
+        .unwrap();
+    let package_info = lib::query::package_info(name)?;
+    if output_json() {
+        return print_json(&package_info);
     }
+    println!("Name: {}", package_info.name);
+    match (&package_info.installed_version, package_info.explicit) {
+        (Some(version), Some(true)) => println!("Installed: {} (explicitly)", version),
+        (Some(version), _) => println!("Installed: {} (as a dependency)", version),
+        (None, _) => println!("Installed: No"),
+    }
+    for version in &package_info.versions {
+        println!();
+        println!(
+            "Version: {}{}",
+            version.package.version,
+            if version.installed {
+                " [installed]"
+            } else {
+                ""
+            }
+        );
+        println!("  Repository: {}", version.repository);
+        println!("  Architecture: {}", version.package.arch);
+        println!("  Description: {}", version.package.description);
+        println!(
+            "  Dependencies: {}",
+            format_requirements(&version.package.dependencies)
+        );
+        println!(
+            "  Conflicts: {}",
+            format_requirements(&version.package.conflicts)
+        );
+        println!(
+            "  Archive hash: {}",
+            version.package.keccak.as_deref().unwrap_or("Unknown")
+        );
+    }
+    if !package_info.dependency_tree.is_empty() {
+        println!();
+        println!("Dependency tree:");
+        print_dependency_tree(&package_info.dependency_tree, 1);
+    }
+    Ok(())
+}
+
+/// Lists the packages in your software installation
+///
+/// # Arguments
+///
+/// * `explicit` - Lists packages that were requested explicitly
+///
+/// * `dependencies` - Lists packages that were installed as dependencies
+///
+/// * `orphans` - Lists packages that are no longer required as dependencies
+///
+/// * `upgradable` - Lists packages with newer versions available
+///
+/// * `foreign` - Lists packages that are no longer in any repository
+fn list(matches: &clap::ArgMatches) -> miette::Result<()> {
+    let filter = lib::query::ListFilter {
+        explicit: matches.is_present("explicit"),
+        dependencies: matches.is_present("dependencies"),
+        orphans: matches.is_present("orphans"),
+        upgradable: matches.is_present("upgradable"),
+        foreign: matches.is_present("foreign"),
+    };
+    let listed_packages = lib::query::list_packages(filter)?;
+    if output_json() {
+        return print_json(&listed_packages);
+    }
+    let name_width = listed_packages
+        .iter()
+        .map(|listed_package| listed_package.name.len())
+        .max()
+        .unwrap_or_default()
+        .max("NAME".len());
+    let version_width = listed_packages
+        .iter()
+        .map(|listed_package| listed_package.version.to_string().len())
+        .max()
+        .unwrap_or_default()
+        .max("INSTALLED".len());
+    println!(
+        "{:name_width$}  {:version_width$}  AVAILABLE",
+        "NAME", "INSTALLED"
+    );
+    for listed_package in &listed_packages {
+        let available_version = listed_package
+            .available_version
+            .as_ref()
+            .map(|available_version| available_version.to_string())
+            .unwrap_or_else(|| "-".to_owned());
+        println!(
+            "{:name_width$}  {:version_width$}  {}",
+            listed_package.name,
+            listed_package.version.to_string(),
+            available_version
+        );
+    }
+    Ok(())
+}
+
+/// Graphs the relationships between packages
+///
+/// # Arguments
+///
+/// * `PACKAGES` - Names of packages to graph the installation of, rather than the installed packages
+///
+/// * `format` - Graph language to render in
+///
+/// * `why` - Only graphs the chain of dependencies leading to a package
+async fn graph(matches: &clap::ArgMatches) -> miette::Result<()> {
+    let dependency_graph = match matches.values_of("PACKAGES") {
+        Some(names) => {
+            let names: Vec<String> = names.map(|name| name.to_owned()).collect();
+            lib::graph::transaction_graph(&names).await?
+        }
+        None => lib::graph::installed_graph()?,
+    };
+    let dependency_graph = match matches.value_of("why") {
+        Some(name) => dependency_graph.why(name)?,
+        None => dependency_graph,
+    };
+    if output_json() {
+        return print_json(&dependency_graph);
+    }
+    let format = match matches.value_of("format") {
+        Some("mermaid") => lib::graph::GraphFormat::Mermaid,
+        _ => lib::graph::GraphFormat::Dot,
+    };
+    print!("{}", dependency_graph.render(format));
+    Ok(())
 }
 
-pub fn add_repository(matches: &clap::ArgMatches) {
-    let url = matches
+/// Explains why a package is installed
+///
+/// # Arguments
+///
+/// * `PACKAGE_NAME` - Name of an installed package
+fn why(matches: &clap::ArgMatches) -> miette::Result<()> {
+    let name = matches
+        .value_of("PACKAGE_NAME")
+        .with_context(|| "No package name was given".to_string())
+        .unwrap();
+    let why = lib::explain::why(name)?;
+    if output_json() {
+        return print_json(&why);
+    }
+    match why {
+        lib::explain::Why::Explicit(node) => {
+            println!("{} was installed explicitly.", node.label())
+        }
+        lib::explain::Why::Orphan(node) => println!(
+            "{} was installed as a dependency, but is no longer required.",
+            node.label()
+        ),
+        lib::explain::Why::Required(path) => {
+            if let Some(first_edge) = path.first() {
+                println!("{} (installed explicitly)", first_edge.from.label());
+            }
+            for edge in &path {
+                println!(
+                    "  -> {} (depends on {} {})",
+                    edge.to.label(),
+                    edge.to.name,
+                    edge.requirement
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Explains what prevents a version of a package from being installed
+///
+/// # Arguments
+///
+/// * `PACKAGE_NAME` - Name of a package
+///
+/// * `VERSION` - Version of the package
+async fn why_not(matches: &clap::ArgMatches) -> miette::Result<()> {
+    let name = matches
+        .value_of("PACKAGE_NAME")
+        .with_context(|| "No package name was given".to_string())
+        .unwrap();
+    let version = matches
+        .value_of("VERSION")
+        .with_context(|| "No version was given".to_string())
+        .unwrap();
+    let version = semver::Version::parse(version).into_diagnostic()?;
+    let why_not = lib::explain::why_not(name, &version).await?;
+    if output_json() {
+        return print_json(&why_not);
+    }
+    if why_not.installable {
+        println!(
+            "Nothing prevents {} from being installed.",
+            why_not.package.label()
+        );
+    } else {
+        println!("{} cannot be installed.", why_not.package.label());
+    }
+    for constraint in &why_not.constraints {
+        println!("  - {}", constraint);
+    }
+    Ok(())
+}
+
+/// Compares modified configuration files with the new versions written beside them
+///
+/// # Arguments
+///
+/// * `PACKAGE_NAME` - Name of an installed package
+fn config_diff(matches: &clap::ArgMatches) -> miette::Result<()> {
+    let config_diffs = lib::config::config_diffs(
+        matches.value_of("PACKAGE_NAME"),
+        Path::new(lib::transaction::INSTALL_ROOT),
+    )?;
+    if output_json() {
+        return print_json(&config_diffs);
+    }
+    if config_diffs.is_empty() {
+        println!("No configuration files have new versions waiting to be merged.");
+    }
+    for config_diff in &config_diffs {
+        println!("{} ({}):", config_diff.path.display(), config_diff.package);
+        print!("{}", config_diff.diff);
+    }
+    Ok(())
+}
+
+/// Verifies installed files against the records of their packages
+///
+/// Exits with a failure if any problems are found, so that drift can be detected by scripts.
+/// Changes to the contents of configuration files are listed, but are expected on an administered system and do not cause a failure.
+///
+/// # Arguments
+///
+/// * `PACKAGE_NAME` - Name of an installed package
+fn verify(matches: &clap::ArgMatches) -> miette::Result<()> {
+    let verifications = lib::verify::verify_installed(
+        matches.value_of("PACKAGE_NAME"),
+        Path::new(lib::transaction::INSTALL_ROOT),
+    )?;
+    let intact = verifications
+        .iter()
+        .all(|verification| verification.is_intact());
+    if output_json() {
+        print_json(&verifications)?;
+    } else {
+        for verification in &verifications {
+            if verification.problems.is_empty() {
+                continue;
+            }
+            println!("{} {}:", verification.name, verification.version);
+            for file_problem in &verification.problems {
+                let marker = if file_problem.config { " (config)" } else { "" };
+                println!(
+                    "  {}{}: {}",
+                    file_problem.path.display(),
+                    marker,
+                    file_problem.problem
+                );
+            }
+        }
+        let config_changed = verifications
+            .iter()
+            .any(|verification| !verification.problems.is_empty());
+        if intact && config_changed {
+            println!(
+                "Verified {} package(s); only configuration files were changed.",
+                verifications.len()
+            );
+        } else if intact {
+            println!(
+                "Verified {} package(s); no problems were found.",
+                verifications.len()
+            );
+        }
+    }
+    if !intact {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Formats a time for display
+///
+/// # Arguments
+///
+/// * `timestamp` - A time, in seconds since the Unix epoch (required)
+fn format_timestamp(timestamp: u64) -> String {
+    let days = timestamp / 86400;
+    let seconds = timestamp % 86400;
+    // Convert days since the epoch into a date in the proleptic Gregorian calendar, counting years from March
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+/// Lists the transactions committed on this system
+fn history() -> miette::Result<()> {
+    let history = lib::history::read_history()?;
+    if output_json() {
+        return print_json(&history.entries);
+    }
+    if history.entries.is_empty() {
+        println!("No transactions have been committed.");
+    }
+    for entry in &history.entries {
+        println!(
+            "{:>5}  {}  {:<12}  {} change(s)  {}",
+            entry.id,
+            format_timestamp(entry.timestamp),
+            entry.user,
+            entry.changes.len(),
+            entry.command_line.join(" ")
+        );
+    }
+    Ok(())
+}
+
+/// Shows the changes made by a transaction
+///
+/// # Arguments
+///
+/// * `ID` - Identifier of a transaction
+fn show_transaction(matches: &clap::ArgMatches) -> miette::Result<()> {
+    let id = matches
+        .value_of("ID")
+        .with_context(|| "No transaction identifier was given".to_string())
+        .unwrap();
+    let id: u64 = id.parse().into_diagnostic()?;
+    let entry = lib::history::find_transaction(id)?;
+    if output_json() {
+        return print_json(&entry);
+    }
+    println!("Transaction: {}", entry.id);
+    println!("Committed at: {}", format_timestamp(entry.timestamp));
+    match (entry.uid, &entry.sudo_user) {
+        (Some(uid), Some(sudo_user)) => println!(
+            "Committed by: {} (user ID {}), through sudo by {}",
+            entry.user, uid, sudo_user
+        ),
+        (Some(uid), None) => println!("Committed by: {} (user ID {})", entry.user, uid),
+        _ => println!("Committed by: {}", entry.user),
+    }
+    println!("Command line: {}", entry.command_line.join(" "));
+    println!("Changes:");
+    print_changes(&entry.changes);
+    if !entry.snapshots.is_empty() {
+        println!("Snapshots:");
+        for snapshot in &entry.snapshots {
+            println!(
+                "  {} ({}): {}",
+                snapshot.label,
+                snapshot.backend,
+                snapshot.location.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Reverses the changes made by a transaction
+///
+/// # Arguments
+///
+/// * `ID` - Identifier of a transaction
+///
+/// * `yes` - Commits the transaction without asking for confirmation
+///
+/// * `dry-run` - Stops once the transaction is planned
+async fn undo(matches: &clap::ArgMatches) -> miette::Result<()> {
+    let id = matches
+        .value_of("ID")
+        .with_context(|| "No transaction identifier was given".to_string())
+        .unwrap();
+    let id: u64 = id.parse().into_diagnostic()?;
+    let plan = lib::transaction::plan_undo(id).await?;
+    commit_plan(&plan, matches).await
+}
+
+/// Reverses the changes made by the most recent transaction
+///
+/// # Arguments
+///
+/// * `yes` - Commits the transaction without asking for confirmation
+///
+/// * `dry-run` - Stops once the transaction is planned
+async fn rollback(matches: &clap::ArgMatches) -> miette::Result<()> {
+    let plan = lib::transaction::plan_rollback().await?;
+    commit_plan(&plan, matches).await
+}
+
+/// Packages a piece of software for future distribution
+///
+/// # Arguments
+///
+/// * `PATH` - Path to some software
+///
+/// * `manifest` - Path to a manifest describing the software
+///
+/// * `destination` - Path to a directory to write the package to
+///
+/// * `key` - Path to a key to sign the package with
+fn package(matches: &clap::ArgMatches) -> miette::Result<()> {
+    let path = matches
+        .value_of("PATH")
+        .with_context(|| "No path to some software was given".to_string())
+        .unwrap();
+    let manifest = matches
+        .value_of("manifest")
+        .with_context(|| "No path to a manifest was given".to_string())
+        .unwrap();
+    let destination = matches.value_of("destination").unwrap_or(".");
+    let archive_path = lib::archive::package_directory(
+        Path::new(path),
+        Path::new(manifest),
+        Path::new(destination),
+        matches.value_of("key").map(Path::new),
+    )?;
+    if output_json() {
+        return print_json(&serde_json::json!({ "archive": archive_path }));
+    }
+    println!("Packaged software into {}.", archive_path.display());
+    Ok(())
+}
+
+/// Extracts a software package
+///
+/// # Arguments
+///
+/// * `PATH` - Path to a package
+///
+/// * `destination` - Path to a directory to extract the package into
+fn extract(matches: &clap::ArgMatches) -> miette::Result<()> {
+    let path = matches
+        .value_of("PATH")
+        .with_context(|| "No path to a package was given".to_string())
+        .unwrap();
+    let destination = matches.value_of("destination").unwrap_or(".");
+    let archive = lib::archive::read_archive_file(Path::new(path))?;
+    let trusted_keys = lib::signature::read_trusted_keys()?;
+    lib::archive::verify_archive(&archive, &trusted_keys)?;
+    lib::archive::extract_archive(&archive, Path::new(destination))?;
+    if output_json() {
+        return print_json(&serde_json::json!({
+            "package": archive.header.package,
+            "destination": destination,
+        }));
+    }
+    println!(
+        "Extracted {} {} into {}.",
+        archive.header.package.name, archive.header.package.version, destination
+    );
+    Ok(())
+}
+
+/// Inspects a software package
+///
+/// # Arguments
+///
+/// * `PATH` - Path to a package
+///
+/// * `verify` - Verifies the integrity and signature of the package
+fn inspect(matches: &clap::ArgMatches) -> miette::Result<()> {
+    let path = matches
         .value_of("PATH")
         .with_context(|| "No path to a package was given".to_string())
         .unwrap();
-    gany::add_repository_with_url(url.to_owned());
+    let archive = lib::archive::read_archive_file(Path::new(path))?;
+    let verify = matches.is_present("verify");
+    if verify {
+        let trusted_keys = lib::signature::read_trusted_keys()?;
+        lib::archive::verify_archive(&archive, &trusted_keys)?;
+    }
+    if output_json() {
+        return print_json(&serde_json::json!({
+            "header": archive.header,
+            "signed": archive.signature.is_some(),
+            "verified": verify,
+        }));
+    }
+    let package = &archive.header.package;
+    println!("Name: {}", package.name);
+    println!("Version: {}", package.version);
+    println!("Architecture: {}", package.arch);
+    println!("Description: {}", package.description);
+    println!("Signed: {}", archive.signature.is_some());
+    println!("Files:");
+    for entry in &archive.header.entries {
+        match &entry.digest {
+            Some(digest) => println!(
+                "  {} ({} bytes, {})",
+                entry.path.display(),
+                entry.size,
+                digest
+            ),
+            None => println!("  {}", entry.path.display()),
+        }
+    }
+    if verify {
+        println!("The package is intact.");
+    }
+    Ok(())
+}
+
+/// Builds a repository from a directory of package archives
+///
+/// # Arguments
+///
+/// * `DIRECTORY` - Path to a directory of package archives
+///
+/// * `name` - Name of the repository
+///
+/// * `description` - Description of the repository
+///
+/// * `address` - Address the repository will be served from
+///
+/// * `key` - Path to a key to sign the repository with
+///
+/// * `index` - Also write a human-readable index of the repository
+fn build_repository(matches: &clap::ArgMatches) -> miette::Result<()> {
+    let directory = matches
+        .value_of("DIRECTORY")
+        .with_context(|| "No path to a directory of package archives was given".to_string())
+        .unwrap();
+    let name = matches
+        .value_of("name")
+        .with_context(|| "No repository name was given".to_string())
+        .unwrap();
+    let description = matches.value_of("description").unwrap_or_default();
+    let address = matches
+        .value_of("address")
+        .with_context(|| "No repository address was given".to_string())
+        .unwrap();
+    let index_format = match matches.value_of("index") {
+        Some("yaml") => Some(lib::repository::IndexFormat::Yaml),
+        Some("json") => Some(lib::repository::IndexFormat::Json),
+        _ => None,
+    };
+    let repository = lib::repository::build_repository(
+        Path::new(directory),
+        name.to_owned(),
+        description.to_owned(),
+        address.to_owned(),
+        matches.value_of("key").map(Path::new),
+        index_format,
+    )?;
+    if output_json() {
+        return print_json(&repository);
+    }
+    println!(
+        "Built repository '{}' with {} package(s).",
+        repository.name,
+        repository.packages.unwrap_or_default().len()
+    );
+    Ok(())
+}
+
+/// Generates a key for signing repositories
+///
+/// # Arguments
+///
+/// * `PATH` - Path to write the signing key to
+fn generate_signing_key(matches: &clap::ArgMatches) -> miette::Result<()> {
+    let path = matches
+        .value_of("PATH")
+        .with_context(|| "No path to write the signing key to was given".to_string())
+        .unwrap();
+    let verifying_key = lib::signature::generate_signing_key(Path::new(path))?;
+    if output_json() {
+        return print_json(&serde_json::json!({
+            "public_key": hex::encode(verifying_key.to_bytes()),
+        }));
+    }
+    println!(
+        "Generated signing key with public key: {}",
+        hex::encode(verifying_key.to_bytes())
+    );
+    Ok(())
+}
+
+/// Migrates repository data to the current metadata format
+///
+/// # Arguments
+///
+/// * `INPUT` - Path to existing repository data
+///
+/// * `OUTPUT` - Path to write the migrated repository data to, if not in place
+fn migrate_repository(matches: &clap::ArgMatches) -> miette::Result<()> {
+    let input = matches
+        .value_of("INPUT")
+        .with_context(|| "No path to repository data was given".to_string())
+        .unwrap();
+    let output = matches.value_of("OUTPUT").unwrap_or(input);
+    let repository = lib::repository::migrate_repository(Path::new(input), Path::new(output))?;
+    if output_json() {
+        return print_json(&serde_json::json!({
+            "repository": repository.name,
+            "schema_version": lib::metadata::SCHEMA_VERSION,
+        }));
+    }
+    println!(
+        "Migrated repository '{}' to metadata schema version {}.",
+        repository.name,
+        lib::metadata::SCHEMA_VERSION
+    );
+    Ok(())
 }
 
 /// Shows information regarding the usage and handling of this software
@@ -0,0 +1,255 @@
+use crate::error::MetadataError;
+use crate::package::{Package, PackageRequirement};
+use crate::repository::Repository;
+use miette::Result;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+
+/// The bytes identifying data written in Gany's metadata format
+pub const MAGIC: &[u8; 8] = b"GANYMETA";
+
+/// The version of the metadata schema written by this version of Gany
+///
+/// Fields may be added to the schema without changing its version, as readers ignore fields they do not know of.
+/// The version only changes when existing data can no longer be read as before.
+pub const SCHEMA_VERSION: u16 = 1;
+
+/// The length of the header preceding the encoded metadata
+const HEADER_LENGTH: usize = MAGIC.len() + 2 + 1;
+
+/// The compression applied to encoded metadata
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum Compression {
+    /// The metadata is not compressed
+    None = 0,
+    /// The metadata is LZ4-compressed
+    Lz4 = 1,
+}
+
+impl Compression {
+    fn from_flag(flag: u8) -> Result<Self> {
+        match flag {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            _ => Err(MetadataError::UnknownCompression(flag).into()),
+        }
+    }
+}
+
+/// Encodes metadata, preceded by a header describing it
+///
+/// The header consists of the magic bytes, the schema version and a compression flag.
+/// The metadata itself is encoded as MessagePack with named fields, so that fields may be added or ignored.
+///
+/// # Arguments
+///
+/// * `value` - The metadata to be encoded (required)
+///
+/// * `compression` - The compression to apply to the encoded metadata (required)
+pub fn encode<T: Serialize>(value: &T, compression: Compression) -> Result<Vec<u8>> {
+    let payload = rmp_serde::to_vec_named(value).map_err(|_| MetadataError::UnableToSerialise)?;
+    let payload = match compression {
+        Compression::None => payload,
+        Compression::Lz4 => lz4_flex::compress_prepend_size(&payload),
+    };
+    let mut data = Vec::with_capacity(HEADER_LENGTH + payload.len());
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&SCHEMA_VERSION.to_le_bytes());
+    data.push(compression as u8);
+    data.extend_from_slice(&payload);
+    Ok(data)
+}
+
+/// Determines whether some data is written in Gany's metadata format
+///
+/// # Arguments
+///
+/// * `data` - The data to be checked (required)
+pub fn is_metadata(data: &[u8]) -> bool {
+    data.len() >= HEADER_LENGTH && data.starts_with(MAGIC)
+}
+
+/// Decodes metadata written by `encode`
+///
+/// # Arguments
+///
+/// * `data` - The encoded metadata, including its header (required)
+pub fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+    if !is_metadata(data) {
+        return Err(MetadataError::MissingHeader.into());
+    }
+    let schema_version = u16::from_le_bytes([data[MAGIC.len()], data[MAGIC.len() + 1]]);
+    if schema_version > SCHEMA_VERSION {
+        return Err(MetadataError::UnsupportedSchemaVersion(schema_version).into());
+    }
+    let compression = Compression::from_flag(data[MAGIC.len() + 2])?;
+    let payload = &data[HEADER_LENGTH..];
+    let payload = match compression {
+        Compression::None => payload.to_vec(),
+        Compression::Lz4 => lz4_flex::decompress_size_prepended(payload)
+            .map_err(|_| MetadataError::UnableToDecompress)?,
+    };
+    Ok(rmp_serde::from_slice(&payload).map_err(|_| MetadataError::UnableToDeserialise)?)
+}
+
+/// Decodes repository data, migrating it from the legacy `bincode` layout if necessary
+///
+/// # Arguments
+///
+/// * `data` - The encoded repository data (required)
+pub fn decode_repository(data: &[u8]) -> Result<Repository> {
+    if is_metadata(data) {
+        decode(data)
+    } else {
+        let legacy_repository: LegacyRepository =
+            bincode::deserialize(data).map_err(|_| MetadataError::UnableToDeserialise)?;
+        Ok(legacy_repository.into())
+    }
+}
+
+/// Decodes a set of repositories, migrating them from the legacy `bincode` layout if necessary
+///
+/// # Arguments
+///
+/// * `data` - The encoded set of repositories (required)
+pub fn decode_repositories(data: &[u8]) -> Result<HashSet<Repository>> {
+    if is_metadata(data) {
+        decode(data)
+    } else {
+        let legacy_repositories: HashSet<LegacyRepository> =
+            bincode::deserialize(data).map_err(|_| MetadataError::UnableToDeserialise)?;
+        Ok(legacy_repositories
+            .into_iter()
+            .map(|legacy_repository| legacy_repository.into())
+            .collect())
+    }
+}
+
+/// A repository as laid out before the metadata format was versioned
+///
+/// This layout must never change, as it exists only to read data written by older versions of Gany.
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct LegacyRepository {
+    pub name: String,
+    pub description: String,
+    pub address: String,
+    pub packages: Option<HashSet<LegacyPackage>>,
+}
+
+impl Hash for LegacyRepository {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+        self.address.hash(state);
+    }
+}
+
+/// A package as laid out before the metadata format was versioned
+///
+/// This layout must never change, as it exists only to read data written by older versions of Gany.
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct LegacyPackage {
+    pub arch: String,
+    pub name: String,
+    pub description: String,
+    pub version: semver::Version,
+    pub dependencies: Option<HashSet<LegacyPackageRequirement>>,
+    pub conflicts: Option<HashSet<LegacyPackageRequirement>>,
+    pub files: Vec<PathBuf>,
+    pub keccak: Option<String>,
+}
+
+impl Hash for LegacyPackage {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.arch.hash(state);
+        self.name.hash(state);
+        self.version.hash(state);
+    }
+}
+
+/// A package requirement as laid out before the metadata format was versioned
+///
+/// This layout must never change, as it exists only to read data written by older versions of Gany.
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct LegacyPackageRequirement {
+    pub arch: String,
+    pub name: String,
+    pub version: semver::VersionReq,
+    pub dependencies: Option<HashSet<LegacyPackageRequirement>>,
+    pub conflicts: Option<HashSet<LegacyPackageRequirement>>,
+}
+
+impl Hash for LegacyPackageRequirement {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.arch.hash(state);
+        self.name.hash(state);
+        self.version.hash(state);
+    }
+}
+
+/// Converts a set of legacy package requirements into the current layout
+///
+/// # Arguments
+///
+/// * `legacy_requirements` - A set of package requirements in the legacy layout (optional)
+fn from_legacy_requirements(
+    legacy_requirements: Option<HashSet<LegacyPackageRequirement>>,
+) -> Option<HashSet<PackageRequirement>> {
+    legacy_requirements.map(|legacy_requirements| {
+        legacy_requirements
+            .into_iter()
+            .map(|legacy_requirement| legacy_requirement.into())
+            .collect()
+    })
+}
+
+impl From<LegacyPackageRequirement> for PackageRequirement {
+    fn from(legacy_requirement: LegacyPackageRequirement) -> Self {
+        PackageRequirement {
+            arch: legacy_requirement.arch,
+            name: legacy_requirement.name,
+            version: legacy_requirement.version,
+            dependencies: from_legacy_requirements(legacy_requirement.dependencies),
+            conflicts: from_legacy_requirements(legacy_requirement.conflicts),
+        }
+    }
+}
+
+impl From<LegacyRepository> for Repository {
+    fn from(legacy_repository: LegacyRepository) -> Self {
+        Repository {
+            name: legacy_repository.name,
+            description: legacy_repository.description,
+            address: legacy_repository.address,
+            packages: legacy_repository.packages.map(|packages| {
+                packages
+                    .into_iter()
+                    .map(|legacy_package| legacy_package.into())
+                    .collect()
+            }),
+        }
+    }
+}
+
+impl From<LegacyPackage> for Package {
+    fn from(legacy_package: LegacyPackage) -> Self {
+        Package {
+            arch: legacy_package.arch,
+            name: legacy_package.name,
+            description: legacy_package.description,
+            version: legacy_package.version,
+            dependencies: from_legacy_requirements(legacy_package.dependencies),
+            conflicts: from_legacy_requirements(legacy_package.conflicts),
+            files: legacy_package.files,
+            config_files: None,
+            ghost_files: None,
+            file_attributes: None,
+            download_size: None,
+            installed_size: None,
+            scripts: None,
+            keccak: legacy_package.keccak,
+        }
+    }
+}
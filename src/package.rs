@@ -1,6 +1,15 @@
-use crate::repository::Repository;
-use semver::VersionReq;
-use std::collections::HashSet;
+use crate::attributes::FileAttributes;
+use crate::error::PackageInstallationError;
+use crate::repository::{self, Repository};
+use crate::scripts::PackageScripts;
+use crate::ARCH;
+use miette::Result;
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use varisat::{CnfFormula, ExtendFormula, Lit, Solver, Var};
 
 #[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
 /// A package in a repository
@@ -14,15 +23,63 @@ pub struct Package {
     /// The version of the packaged software
     pub version: Version,
     /// The packages that a package depends on
+    #[serde(default)]
     pub dependencies: Option<HashSet<PackageRequirement>>,
     /// The packages that a package conflicts with
+    #[serde(default)]
     pub conflicts: Option<HashSet<PackageRequirement>>,
     /// The files that a package owns, including potential ghost files
+    #[serde(default)]
     pub files: Vec<PathBuf>,
+    /// The files that a package owns which are configuration files
+    #[serde(default)]
+    pub config_files: Option<HashSet<PathBuf>>,
+    /// The files that a package owns which are not shipped in its archive, such as logs and sockets
+    ///
+    /// Ghost files count towards conflicts between packages, and are deleted if present when the package is removed.
+    #[serde(default)]
+    pub ghost_files: Option<HashSet<PathBuf>>,
+    /// The modes, ownership, capabilities and extended attributes of the files that a package owns, applied when they are installed
+    #[serde(default)]
+    pub file_attributes: Option<BTreeMap<PathBuf, FileAttributes>>,
+    /// The size of the archive the software is packaged in, in bytes
+    #[serde(default)]
+    pub download_size: Option<u64>,
+    /// The total size of the files the package installs, in bytes
+    #[serde(default)]
+    pub installed_size: Option<u64>,
+    /// The scripts run at each stage of the package's lifecycle
+    #[serde(default)]
+    pub scripts: Option<PackageScripts>,
     /// The SHA3-256 hash of the LZ4-compressed archive the software is packaged in
+    #[serde(default)]
     pub keccak: Option<String>,
 }
 
+impl Package {
+    /// Determines whether this package is the same build as another, regardless of where either was found
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - Another package (required)
+    pub fn is_same_build(&self, other: &Package) -> bool {
+        self.name == other.name && self.version == other.version && self.arch == other.arch
+    }
+
+    /// The file name of the archive this package is distributed in, relative to its repository
+    pub fn archive_name(&self) -> String {
+        format!("{}-{}-{}.gany", self.name, self.version, self.arch)
+    }
+}
+
+impl Hash for Package {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.arch.hash(state);
+        self.name.hash(state);
+        self.version.hash(state);
+    }
+}
+
 /// A package requirement which may be fulfilled by a package
 #[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub struct PackageRequirement {
@@ -33,29 +90,55 @@ pub struct PackageRequirement {
     pub conflicts: Option<HashSet<PackageRequirement>>,
 }
 
+impl PackageRequirement {
+    /// Determines whether a package fulfills this requirement
+    ///
+    /// Packages are matched by architecture, name and version alone; what a package itself depends on or conflicts with does not affect whether it fulfills a requirement.
+    ///
+    /// # Arguments
+    ///
+    /// * `package` - A package which may fulfill this requirement (required)
+    pub fn is_fulfilled_by(&self, package: &Package) -> bool {
+        self.arch == package.arch
+            && self.name == package.name
+            && self.version.matches(&package.version)
+    }
+}
+
+impl Hash for PackageRequirement {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.arch.hash(state);
+        self.name.hash(state);
+        self.version.hash(state);
+    }
+}
+
 /// Find candidate packages that fulfill a package requirement
 ///
 /// # Arguments
 ///
 /// * `package_requirement` - A requirement for a package (required)
-pub async fn get_candidate_packages(package_requirement: &PackageRequirement) -> HashSet<Package> {
+///
+/// * `local_packages` - Packages from local archives, considered alongside those in repositories (required)
+pub async fn get_candidate_packages(
+    package_requirement: &PackageRequirement,
+    local_packages: &HashSet<Package>,
+) -> Result<HashSet<Package>> {
     let mut candidate_packages: HashSet<Package> = HashSet::new();
     let repositories: HashSet<Repository> = repository::fetch_repositories(false).await?;
     for repository in repositories {
         for potential_candidate in repository.packages.unwrap_or_default() {
-            if package_requirement.arch == potential_candidate.arch
-                && package_requirement.name == potential_candidate.name
-                && package_requirement
-                    .version
-                    .matches(&potential_candidate.version)
-                && package_requirement.dependencies == potential_candidate.dependencies
-                && package_requirement.conflicts == potential_candidate.conflicts
-            {
-                candidate_packages.insert(package_candidate);
+            if package_requirement.is_fulfilled_by(&potential_candidate) {
+                candidate_packages.insert(potential_candidate);
             }
         }
     }
-    candidate_packages
+    for potential_candidate in local_packages {
+        if package_requirement.is_fulfilled_by(potential_candidate) {
+            candidate_packages.insert(potential_candidate.clone());
+        }
+    }
+    Ok(candidate_packages)
 }
 
 /// Create a set of all packages involved in a transaction
@@ -63,91 +146,469 @@ pub async fn get_candidate_packages(package_requirement: &PackageRequirement) ->
 /// # Arguments
 ///
 /// * `packages` - A set of packages requested in a transaction (required)
-pub async fn crawl_package_tree(packages: &HashSet<Package>) -> HashSet<Package> {
+///
+/// * `local_packages` - Packages from local archives, considered alongside those in repositories (required)
+pub async fn crawl_package_tree(
+    packages: &HashSet<Package>,
+    local_packages: &HashSet<Package>,
+) -> Result<HashSet<Package>> {
     let mut all_packages_set: HashSet<Package> = HashSet::new();
-    for package in packages {
+    let mut pending_packages: VecDeque<Package> = packages.iter().cloned().collect();
+    while let Some(package) = pending_packages.pop_front() {
         // Skip packages that do not support the user's architecture
-        if package.arch != *ARCH {
+        if package.arch != *ARCH || all_packages_set.contains(&package) {
             continue;
         }
-        all_packages_set.insert(package.clone());
         // Determine dependencies and conflicts for each package
-        let dependencies_and_conflicts: HashSet<PackageRequirement> = &package
+        let dependencies_and_conflicts = package
             .dependencies
-            .unwrap_or_default()
-            .union(&package.conflicts.unwrap_or_default())
-            .collect();
+            .iter()
+            .flatten()
+            .chain(package.conflicts.iter().flatten());
         for package_requirement in dependencies_and_conflicts {
-            let candidate_packages = get_candidate_packages(&package_requirement).await?;
-            // Recursively crawl the package tree
-            for candidate_package in candidate_packages {
-                all_packages_set = all_packages_set
-                    .union(build_package_tree(&candidate_packages))
-                    .collect();
-            }
+            let candidate_packages =
+                get_candidate_packages(package_requirement, local_packages).await?;
+            pending_packages.extend(
+                candidate_packages
+                    .into_iter()
+                    .filter(|candidate_package| !all_packages_set.contains(candidate_package)),
+            );
         }
+        all_packages_set.insert(package);
     }
-    all_packages_set
+    Ok(all_packages_set)
 }
 
-/// Determines how a set of packages can be installed without conflicts, if possible.
+/// Why a clause is part of the formula solved for a transaction
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub enum ClauseReason {
+    /// The package was requested, so must be installed
+    Requested(Package),
+    /// The package is installed, so it or another version of it must remain installed
+    Installed(Package),
+    /// The package can only be installed alongside one of the candidates fulfilling its dependency
+    Dependency {
+        package: Package,
+        requirement: PackageRequirement,
+        candidates: Vec<Package>,
+    },
+    /// The package cannot be installed alongside a package fulfilling its conflict
+    Conflict {
+        package: Package,
+        conflicting: Package,
+        requirement: PackageRequirement,
+    },
+    /// Only one version of a package can be installed
+    SingleVersion(Package, Package),
+}
+
+/// The formula solved to determine how a set of packages can be installed
+struct TransactionFormula {
+    /// The formula the variables of the packages are allocated in, without any clauses
+    formula: CnfFormula,
+    /// The variable of each package involved in the transaction, true if the package is installed
+    variables: HashMap<Package, Var>,
+    /// The clauses of the formula, each with why it is part of the formula
+    clauses: Vec<(ClauseReason, Vec<Lit>)>,
+}
+
+/// Builds the formula solved to determine how a set of packages can be installed
 ///
 /// # Arguments
 ///
 /// * `packages` - A set of packages requested for installation (required)
-pub async fn solve_packages(packages: &HashSet<&Package>) -> Result<Vec<Package>> {
-    let mut solver = Solver::new();
-    let mut transaction_formula = CnfFormula::new();
-    let all_packages_set = crawl_package_tree(packages).await?;
+///
+/// * `installed_packages` - The packages installed on this system (required)
+///
+/// * `local_packages` - Packages from local archives, considered alongside those in repositories (required)
+async fn build_transaction_formula(
+    packages: &HashSet<&Package>,
+    installed_packages: &HashSet<&Package>,
+    local_packages: &HashSet<Package>,
+) -> Result<TransactionFormula> {
+    let root_packages: HashSet<Package> = packages
+        .iter()
+        .chain(installed_packages.iter())
+        .map(|package| (*package).clone())
+        .collect();
+    let mut all_packages_set = crawl_package_tree(&root_packages, local_packages).await?;
+    // Installed packages are known by their installed records, rather than by the repository packages of the same build
+    all_packages_set.retain(|package| {
+        installed_packages.contains(package)
+            || !installed_packages
+                .iter()
+                .any(|installed_package| installed_package.is_same_build(package))
+    });
+    all_packages_set.extend(installed_packages.iter().map(|package| (*package).clone()));
+    transaction_formula(packages, installed_packages, &all_packages_set)
+}
+
+/// Builds the formula solved to determine how a set of packages can be installed, from every package involved
+///
+/// # Arguments
+///
+/// * `packages` - A set of packages requested for installation (required)
+///
+/// * `installed_packages` - The packages installed on this system (required)
+///
+/// * `all_packages_set` - Every package involved in the transaction, including the requested and installed packages (required)
+fn transaction_formula(
+    packages: &HashSet<&Package>,
+    installed_packages: &HashSet<&Package>,
+    all_packages_set: &HashSet<Package>,
+) -> Result<TransactionFormula> {
+    let mut formula = CnfFormula::new();
+    let mut clauses: Vec<(ClauseReason, Vec<Lit>)> = Vec::new();
 
     // Create a map of variables by package to ensure that each package has a unique variable in the formula
     let mut all_packages_map: HashMap<Package, Var> = HashMap::new();
     for package in all_packages_set {
-        all_packages_map.insert(package, solver.new_var());
+        all_packages_map.insert(package.clone(), formula.new_var());
     }
+    let candidate_packages = |requirement: &PackageRequirement| -> Vec<Package> {
+        let mut candidates: Vec<Package> = all_packages_set
+            .iter()
+            .filter(|candidate| requirement.is_fulfilled_by(candidate))
+            .cloned()
+            .collect();
+        candidates.sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+        candidates
+    };
     for package in packages {
-        let package_var = all_packages_map.get(package).unwrap();
-        for dependency in package.dependencies.unwrap_or_default() {
-            let dependency_candidates = get_candidate_packages(&dependency).await?;
-            let dependency_candidate_vars: Vec<Var> = dependency_candidates
-                .into_iter()
-                .map(|candidate| all_packages_map.get(&candidate).unwrap())
-                .collect();
-            transaction_formula.add_clause(&[[!package_var], &dependency_candidate_vars].concat());
-            // Package implies dependency
+        let package_var = all_packages_map
+            .get(*package)
+            .ok_or(PackageInstallationError::UnableToSolveTransaction)?;
+        // Requested packages must be installed
+        clauses.push((
+            ClauseReason::Requested((*package).clone()),
+            vec![Lit::positive(*package_var)],
+        ));
+    }
+    for package in installed_packages {
+        // Installed packages must remain installed, unless replaced by another version
+        let installed_clause: Vec<Lit> = all_packages_set
+            .iter()
+            .filter(|other_package| other_package.name == package.name)
+            .map(|other_package| Lit::positive(all_packages_map[other_package]))
+            .collect();
+        clauses.push((
+            ClauseReason::Installed((*package).clone()),
+            installed_clause,
+        ));
+    }
+    for package in all_packages_set {
+        let package_lit = Lit::negative(all_packages_map[package]);
+        for dependency in package.dependencies.iter().flatten() {
+            // Package implies one of the candidates fulfilling the dependency
+            let candidates = candidate_packages(dependency);
+            let mut dependency_clause: Vec<Lit> = vec![package_lit];
+            dependency_clause.extend(
+                candidates
+                    .iter()
+                    .map(|candidate| Lit::positive(all_packages_map[candidate])),
+            );
+            clauses.push((
+                ClauseReason::Dependency {
+                    package: package.clone(),
+                    requirement: dependency.clone(),
+                    candidates,
+                },
+                dependency_clause,
+            ));
         }
-        for conflict in package.conflicts.unwrap_or_default() {
-            let conflict_candidates = get_candidate_packages(&conflict).await?;
-            let conflict_candidate_vars: Vec<Var> = conflict_candidates
-                .into_iter()
-                .map(|candidate| all_packages_map.get(&candidate).unwrap())
-                .collect();
-            let conflict_candidate_lits: Vec<Lit> = conflict_candidate_vars
-                .into_iter()
-                .map(|var| !var)
-                .collect();
-            transaction_formula.add_clause(&[[!package_var], &conflict_candidate_lits].concat());
+        for conflict in package.conflicts.iter().flatten() {
             // Both package and conflict cannot be installed
+            for candidate in candidate_packages(conflict) {
+                let candidate_lit = Lit::negative(all_packages_map[&candidate]);
+                clauses.push((
+                    ClauseReason::Conflict {
+                        package: package.clone(),
+                        conflicting: candidate,
+                        requirement: conflict.clone(),
+                    },
+                    vec![package_lit, candidate_lit],
+                ));
+            }
+        }
+    }
+    // At most one version of each package can be installed
+    let mut versions_by_name: BTreeMap<&String, Vec<&Package>> = BTreeMap::new();
+    for package in all_packages_set {
+        versions_by_name
+            .entry(&package.name)
+            .or_default()
+            .push(package);
+    }
+    for versions in versions_by_name.values_mut() {
+        versions.sort_by(|a, b| a.version.cmp(&b.version));
+        for (index, package) in versions.iter().enumerate() {
+            for other_package in &versions[index + 1..] {
+                clauses.push((
+                    ClauseReason::SingleVersion((*package).clone(), (*other_package).clone()),
+                    vec![
+                        Lit::negative(all_packages_map[*package]),
+                        Lit::negative(all_packages_map[*other_package]),
+                    ],
+                ));
+            }
         }
     }
+    Ok(TransactionFormula {
+        formula,
+        variables: all_packages_map,
+        clauses,
+    })
+}
+
+/// Determines how a set of packages can be installed without conflicts, if possible.
+///
+/// Installed packages remain installed, with their dependencies fulfilled and their conflicts respected.
+///
+/// # Arguments
+///
+/// * `packages` - A set of packages requested for installation (required)
+///
+/// * `installed_packages` - The packages installed on this system (required)
+///
+/// * `local_packages` - Packages from local archives, considered alongside those in repositories (required)
+pub async fn solve_packages(
+    packages: &HashSet<&Package>,
+    installed_packages: &HashSet<&Package>,
+    local_packages: &HashSet<Package>,
+) -> Result<Vec<Package>> {
+    let transaction_formula =
+        build_transaction_formula(packages, installed_packages, local_packages).await?;
+    solve_transaction_formula(transaction_formula, packages, installed_packages)
+}
+
+/// Determines the packages to install from the formula solved for a transaction, if it can be solved
+///
+/// # Arguments
+///
+/// * `transaction_formula` - The formula solved for the transaction (required)
+///
+/// * `packages` - A set of packages requested for installation (required)
+///
+/// * `installed_packages` - The packages installed on this system (required)
+fn solve_transaction_formula(
+    transaction_formula: TransactionFormula,
+    packages: &HashSet<&Package>,
+    installed_packages: &HashSet<&Package>,
+) -> Result<Vec<Package>> {
+    let TransactionFormula {
+        formula: mut transaction_formula,
+        variables: all_packages_map,
+        clauses,
+    } = transaction_formula;
+    for (_, clause) in &clauses {
+        transaction_formula.add_clause(clause);
+    }
+    let mut solver = Solver::new();
     solver.add_formula(&transaction_formula);
-    if solver.solve() {
-        let mut transaction: Vec<Package> = Vec::new();
-        let solution_option = solver.model();
-        if let Some(solution) = solution_option {
-            for literal in solution {
-                if literal.is_pos() {
-                    let package = all_packages_map
-                        .iter()
-                        .find(|(_, var)| **var == literal.var())
-                        .unwrap()
-                        .0;
-                    transaction.push(package.clone());
-                }
+    // Installed packages keep their versions where possible, releasing them one at a time until the formula is solved
+    let mut kept_packages: Vec<&Package> = installed_packages
+        .iter()
+        .copied()
+        .filter(|installed_package| {
+            !packages
+                .iter()
+                .any(|package| package.name == installed_package.name)
+        })
+        .collect();
+    kept_packages.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut assumptions: Vec<Lit> = kept_packages
+        .iter()
+        .filter_map(|package| all_packages_map.get(*package))
+        .map(|var| Lit::positive(*var))
+        .collect();
+    while let Some(core) = failed_assumptions(&mut solver, &assumptions)? {
+        match core.first() {
+            Some(released) => assumptions.retain(|assumption| assumption != released),
+            None => return Err(PackageInstallationError::UnableToSolveTransaction.into()),
+        }
+    }
+    let solution = solver.model().unwrap_or_default();
+    let chosen_packages: HashSet<&Package> = all_packages_map
+        .iter()
+        .filter(|(_, var)| solution.contains(&Lit::positive(**var)))
+        .map(|(package, _)| package)
+        .collect();
+    // Only keep the chosen packages needed by the requested and installed packages, as the solver may choose packages needlessly
+    let mut transaction: Vec<Package> = Vec::new();
+    let mut visited_packages: HashSet<&Package> = HashSet::new();
+    let mut pending_packages: VecDeque<&Package> = packages.iter().copied().collect();
+    pending_packages.extend(chosen_packages.iter().copied().filter(|package| {
+        installed_packages
+            .iter()
+            .any(|installed_package| installed_package.name == package.name)
+    }));
+    while let Some(package) = pending_packages.pop_front() {
+        if !visited_packages.insert(package) {
+            continue;
+        }
+        if !installed_packages.contains(package) {
+            transaction.push(package.clone());
+        }
+        for dependency in package.dependencies.iter().flatten() {
+            if let Some(candidate) = chosen_packages
+                .iter()
+                .find(|candidate| dependency.is_fulfilled_by(candidate))
+            {
+                pending_packages.push_back(candidate);
             }
         }
-        Ok(transaction)
-    } else {
-        Err(PackageInstallationError::UnableToSolveTransaction)
+    }
+    Ok(transaction)
+}
+
+/// Solves a formula under a set of assumptions, returning the assumptions that could not hold together, if any
+///
+/// # Arguments
+///
+/// * `solver` - A solver holding the formula (required)
+///
+/// * `assumptions` - The literals assumed to be true (required)
+fn failed_assumptions(solver: &mut Solver, assumptions: &[Lit]) -> Result<Option<Vec<Lit>>> {
+    solver.assume(assumptions);
+    match solver.solve() {
+        Ok(true) => Ok(None),
+        Ok(false) => Ok(Some(solver.failed_core().unwrap_or_default().to_vec())),
+        Err(_) => Err(PackageInstallationError::UnableToSolveTransaction.into()),
+    }
+}
+
+/// Determines why a set of packages cannot be installed together, if they cannot
+///
+/// Each clause of the transaction formula is guarded by an assumption, so that the solver reports the clauses it was unable to satisfy together.
+/// Those clauses are then reduced until each one is needed for the packages to be uninstallable.
+///
+/// # Arguments
+///
+/// * `packages` - A set of packages requested for installation (required)
+///
+/// * `installed_packages` - The packages installed on this system (required)
+///
+/// * `local_packages` - Packages from local archives, considered alongside those in repositories (required)
+pub async fn explain_unsolvable(
+    packages: &HashSet<&Package>,
+    installed_packages: &HashSet<&Package>,
+    local_packages: &HashSet<Package>,
+) -> Result<Option<Vec<ClauseReason>>> {
+    let TransactionFormula {
+        mut formula,
+        clauses,
+        ..
+    } = build_transaction_formula(packages, installed_packages, local_packages).await?;
+    let mut selectors: HashMap<Lit, usize> = HashMap::new();
+    for (index, (_, clause)) in clauses.iter().enumerate() {
+        let selector = Lit::positive(formula.new_var());
+        let mut guarded_clause = clause.clone();
+        guarded_clause.push(!selector);
+        formula.add_clause(&guarded_clause);
+        selectors.insert(selector, index);
+    }
+    let mut solver = Solver::new();
+    solver.add_formula(&formula);
+    let mut assumptions: Vec<Lit> = selectors.keys().copied().collect();
+    assumptions.sort_by_key(|selector| selectors[selector]);
+    let mut core = match failed_assumptions(&mut solver, &assumptions)? {
+        Some(core) => core,
+        None => return Ok(None),
+    };
+    core.sort_by_key(|selector| selectors[selector]);
+    // Drop each clause the rest of the core remains unsatisfiable without
+    let mut index = 0;
+    while index < core.len() {
+        let mut reduced_core = core.clone();
+        reduced_core.remove(index);
+        match failed_assumptions(&mut solver, &reduced_core)? {
+            Some(smaller_core) => {
+                reduced_core.retain(|selector| smaller_core.contains(selector));
+                core = reduced_core;
+            }
+            None => index += 1,
+        }
+    }
+    Ok(Some(
+        core.iter()
+            .map(|selector| clauses[selectors[selector]].0.clone())
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package(manifest: &str) -> Package {
+        serde_yaml::from_str(manifest).unwrap()
+    }
+
+    fn solve(
+        requested: &[&Package],
+        installed: &[&Package],
+        available: &[&Package],
+    ) -> Result<Vec<Package>> {
+        let requested_packages: HashSet<&Package> = requested.iter().copied().collect();
+        let installed_packages: HashSet<&Package> = installed.iter().copied().collect();
+        let all_packages_set: HashSet<Package> = available
+            .iter()
+            .chain(installed.iter())
+            .map(|package| (*package).clone())
+            .collect();
+        let transaction_formula =
+            transaction_formula(&requested_packages, &installed_packages, &all_packages_set)?;
+        solve_transaction_formula(
+            transaction_formula,
+            &requested_packages,
+            &installed_packages,
+        )
+    }
+
+    #[test]
+    fn upgrades_cannot_break_installed_dependents() {
+        let app = package(
+            "{arch: x86_64, name: app, description: '', version: 1.0.0,
+              dependencies: [{arch: x86_64, name: library, version: '^1', dependencies: null, conflicts: null}]}",
+        );
+        let library = package("{arch: x86_64, name: library, description: '', version: 1.4.0}");
+        let new_library = package("{arch: x86_64, name: library, description: '', version: 2.0.0}");
+        assert!(solve(&[&new_library], &[&app, &library], &[&new_library]).is_err());
+
+        // A version of the dependent accepting the upgrade is installed alongside it
+        let new_app = package(
+            "{arch: x86_64, name: app, description: '', version: 2.0.0,
+              dependencies: [{arch: x86_64, name: library, version: '^2', dependencies: null, conflicts: null}]}",
+        );
+        let mut transaction = solve(
+            &[&new_library],
+            &[&app, &library],
+            &[&new_library, &new_app],
+        )
+        .unwrap();
+        transaction.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(transaction, vec![new_app, new_library]);
+    }
+
+    #[test]
+    fn installed_conflicts_are_respected() {
+        let daemon = package(
+            "{arch: x86_64, name: daemon, description: '', version: 1.0.0,
+              conflicts: [{arch: x86_64, name: legacy, version: '*', dependencies: null, conflicts: null}]}",
+        );
+        let legacy = package("{arch: x86_64, name: legacy, description: '', version: 0.9.0}");
+        assert!(solve(&[&legacy], &[&daemon], &[&legacy]).is_err());
+    }
+
+    #[test]
+    fn installed_packages_keep_their_versions() {
+        let library = package("{arch: x86_64, name: library, description: '', version: 1.4.0}");
+        let new_library = package("{arch: x86_64, name: library, description: '', version: 1.5.0}");
+        let app = package(
+            "{arch: x86_64, name: app, description: '', version: 1.0.0,
+              dependencies: [{arch: x86_64, name: library, version: '^1', dependencies: null, conflicts: null}]}",
+        );
+        let transaction = solve(&[&app], &[&library], &[&app, &new_library]).unwrap();
+        assert_eq!(transaction, vec![app]);
     }
 }
@@ -0,0 +1,606 @@
+use crate::archive::EntryKind;
+use crate::database;
+use crate::error::QueryError;
+use crate::package::{Package, PackageRequirement};
+use crate::repository::{self, Repository};
+use miette::Result;
+use semver::Version;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::path::{Component, Path, PathBuf};
+
+/// The owners of a path on this system
+#[derive(Eq, PartialEq, Clone, Debug, Serialize)]
+pub struct Ownership {
+    /// The path being looked up
+    pub path: PathBuf,
+    /// The installed packages owning the path, by name and version; empty if the path is unowned
+    pub owners: Vec<(String, Version)>,
+}
+
+/// Makes a path absolute and removes any `.` or `..` components, without resolving symbolic links
+///
+/// Symbolic links are left alone so that a link owned by one package is not mistaken for its target.
+///
+/// # Arguments
+///
+/// * `path` - A path, absolute or relative to the current directory (required)
+pub fn normalise_path(path: &Path) -> PathBuf {
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        env::current_dir().unwrap_or_default().join(path)
+    };
+    let mut normalised_path = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normalised_path.pop();
+            }
+            _ => normalised_path.push(component),
+        }
+    }
+    normalised_path
+}
+
+/// Finds the installed packages owning some paths
+///
+/// Glob patterns are matched against both the filesystem and the installed paths, so ghost files and missing files are found too.
+///
+/// # Arguments
+///
+/// * `patterns` - Paths, or glob patterns matching paths (required)
+pub fn find_owners(patterns: &[String]) -> Result<Vec<Ownership>> {
+    let database = database::read_database()?;
+    let file_index = database.file_index();
+    let mut ownerships: Vec<Ownership> = Vec::new();
+    for pattern in patterns {
+        let mut paths: Vec<PathBuf> = Vec::new();
+        if glob::Pattern::escape(pattern) == *pattern {
+            paths.push(normalise_path(Path::new(pattern)));
+        } else {
+            let absolute_pattern = normalise_path(Path::new(pattern));
+            let compiled_pattern = glob::Pattern::new(&absolute_pattern.to_string_lossy())
+                .map_err(|_| QueryError::InvalidPattern(pattern.clone()))?;
+            let existing_paths = glob::glob(&absolute_pattern.to_string_lossy())
+                .map_err(|_| QueryError::InvalidPattern(pattern.clone()))?;
+            paths.extend(existing_paths.filter_map(|path| path.ok()));
+            paths.extend(
+                file_index
+                    .keys()
+                    .filter(|path| compiled_pattern.matches_path(path))
+                    .cloned(),
+            );
+            paths.sort();
+            paths.dedup();
+        }
+        for path in paths {
+            let owners = file_index
+                .get(&path)
+                .map(|installed_packages| {
+                    installed_packages
+                        .iter()
+                        .map(|installed_package| {
+                            (
+                                installed_package.package.name.clone(),
+                                installed_package.package.version.clone(),
+                            )
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            ownerships.push(Ownership { path, owners });
+        }
+    }
+    Ok(ownerships)
+}
+
+/// A file owned by a package
+#[derive(Eq, PartialEq, Clone, Debug, Serialize)]
+pub struct PackageFile {
+    /// The path the file is installed to
+    pub path: PathBuf,
+    /// The kind of filesystem entry, if known
+    pub kind: Option<EntryKind>,
+    /// Whether the file is a configuration file
+    pub config: bool,
+    /// Whether the file is a ghost file, owned by the package but not shipped in its archive
+    pub ghost: bool,
+}
+
+/// Which files of a package to list
+///
+/// If no kinds of file are selected, all files are listed.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default)]
+pub struct FileFilter {
+    /// List ghost files
+    pub ghost: bool,
+    /// List directories
+    pub directories: bool,
+    /// List configuration files
+    pub config: bool,
+}
+
+impl FileFilter {
+    /// Determines whether a file is selected by this filter
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - A file owned by a package (required)
+    pub fn matches(&self, file: &PackageFile) -> bool {
+        if !self.ghost && !self.directories && !self.config {
+            return true;
+        }
+        (self.ghost && file.ghost)
+            || (self.directories && file.kind == Some(EntryKind::Directory))
+            || (self.config && file.config)
+    }
+}
+
+/// The files of a package, and where they were listed from
+#[derive(Eq, PartialEq, Clone, Debug, Serialize)]
+pub struct PackageFiles {
+    /// The package the files belong to
+    pub package: Package,
+    /// Whether the package is installed, as opposed to only being available from a repository
+    pub installed: bool,
+    /// The files of the package
+    pub files: Vec<PackageFile>,
+}
+
+/// Lists the files of a package, from the record of installed packages or, failing that, from the repositories
+///
+/// Only the archive of a package records the kinds of its files, so for a package that is not installed, directories are inferred from the other files beneath them.
+/// Empty directories of such a package cannot be told apart from other files, and are not listed as directories.
+///
+/// # Arguments
+///
+/// * `name` - The name of a package (required)
+///
+/// * `filter` - Which files to list (required)
+pub fn list_files(name: &str, filter: FileFilter) -> Result<PackageFiles> {
+    let database = database::read_database()?;
+    let (package, entries, installed) = match database.packages.get(name) {
+        Some(installed_package) => (
+            installed_package.package.clone(),
+            installed_package.entries.clone(),
+            true,
+        ),
+        None => {
+            let repositories = repository::read_repositories()?;
+            let package = repositories
+                .into_iter()
+                .flat_map(|repository| repository.packages.unwrap_or_default())
+                .filter(|package| package.name == name)
+                .max_by(|a, b| a.version.cmp(&b.version))
+                .ok_or_else(|| QueryError::PackageNotFound(name.to_owned()))?;
+            (package, Vec::new(), false)
+        }
+    };
+    let config_files = package.config_files.clone().unwrap_or_default();
+    let ghost_files = package.ghost_files.clone().unwrap_or_default();
+    let mut kinds: HashMap<&Path, EntryKind> = entries
+        .iter()
+        .map(|entry| (entry.path.as_path(), entry.kind))
+        .collect();
+    if !installed {
+        kinds.extend(
+            package
+                .files
+                .iter()
+                .flat_map(|path| path.ancestors().skip(1))
+                .map(|ancestor| (ancestor, EntryKind::Directory)),
+        );
+    }
+    let files = package
+        .files
+        .iter()
+        .map(|path| PackageFile {
+            path: path.clone(),
+            kind: kinds.get(path.as_path()).copied(),
+            config: config_files.contains(path),
+            ghost: ghost_files.contains(path),
+        })
+        .filter(|file| filter.matches(file))
+        .collect();
+    Ok(PackageFiles {
+        package,
+        installed,
+        files,
+    })
+}
+
+/// How a search pattern is matched
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum MatchMode {
+    /// The pattern matches text containing it, regardless of case
+    Substring,
+    /// The pattern is a regular expression
+    Regex,
+    /// The pattern is a glob pattern, which must match the whole text
+    Glob,
+}
+
+/// A compiled search pattern
+enum Matcher {
+    Substring(String),
+    Regex(regex::Regex),
+    Glob(glob::Pattern),
+}
+
+impl Matcher {
+    fn new(pattern: &str, mode: MatchMode) -> Result<Self> {
+        Ok(match mode {
+            MatchMode::Substring => Matcher::Substring(pattern.to_lowercase()),
+            MatchMode::Regex => Matcher::Regex(
+                regex::Regex::new(pattern)
+                    .map_err(|_| QueryError::InvalidPattern(pattern.to_owned()))?,
+            ),
+            MatchMode::Glob => Matcher::Glob(
+                glob::Pattern::new(pattern)
+                    .map_err(|_| QueryError::InvalidPattern(pattern.to_owned()))?,
+            ),
+        })
+    }
+
+    fn matches(&self, text: &str) -> bool {
+        match self {
+            Matcher::Substring(pattern) => text.to_lowercase().contains(pattern),
+            Matcher::Regex(pattern) => pattern.is_match(text),
+            Matcher::Glob(pattern) => pattern.matches(text),
+        }
+    }
+}
+
+/// How closely a package matched a search, from closest to furthest
+#[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Copy, Debug, Serialize)]
+pub enum MatchRank {
+    /// The package's name is the search pattern itself
+    ExactName,
+    /// The package's name matched the search pattern
+    Name,
+    /// The package's description matched the search pattern
+    Description,
+    /// One of the package's files matched the search pattern
+    Files,
+}
+
+/// A package found by a search
+#[derive(Eq, PartialEq, Clone, Debug, Serialize)]
+pub struct SearchResult {
+    /// The package found
+    pub package: Package,
+    /// The name of the repository holding the package
+    pub repository: String,
+    /// How closely the package matched the search
+    pub rank: MatchRank,
+    /// The files of the package matching the search, if files were searched
+    pub matched_files: Vec<PathBuf>,
+}
+
+/// Searches the repositories for packages by name and description, and optionally by the files they provide
+///
+/// Results are ordered with exact name matches first, followed by name, description and file matches.
+///
+/// # Arguments
+///
+/// * `pattern` - The pattern to search for (required)
+///
+/// * `mode` - How the pattern is matched (required)
+///
+/// * `search_files` - Whether to also search the files provided by packages (required)
+pub fn search_packages(
+    pattern: &str,
+    mode: MatchMode,
+    search_files: bool,
+) -> Result<Vec<SearchResult>> {
+    let matcher = Matcher::new(pattern, mode)?;
+    let repositories = repository::read_repositories()?;
+    let mut search_results: Vec<SearchResult> = Vec::new();
+    for repository in repositories {
+        for package in repository.packages.unwrap_or_default() {
+            let matched_files: Vec<PathBuf> = if search_files {
+                package
+                    .files
+                    .iter()
+                    .filter(|path| matcher.matches(&path.to_string_lossy()))
+                    .cloned()
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            let rank = if package.name == pattern {
+                MatchRank::ExactName
+            } else if matcher.matches(&package.name) {
+                MatchRank::Name
+            } else if matcher.matches(&package.description) {
+                MatchRank::Description
+            } else if !matched_files.is_empty() {
+                MatchRank::Files
+            } else {
+                continue;
+            };
+            search_results.push(SearchResult {
+                package,
+                repository: repository.name.clone(),
+                rank,
+                matched_files,
+            });
+        }
+    }
+    search_results.sort_by(|a, b| {
+        a.rank
+            .cmp(&b.rank)
+            .then_with(|| a.package.name.cmp(&b.package.name))
+            .then_with(|| b.package.version.cmp(&a.package.version))
+    });
+    Ok(search_results)
+}
+
+/// A version of a package available from a repository
+#[derive(Eq, PartialEq, Clone, Debug, Serialize)]
+pub struct AvailableVersion {
+    /// The package at this version
+    pub package: Package,
+    /// The name of the repository holding the package
+    pub repository: String,
+    /// Whether this is the installed version of the package
+    pub installed: bool,
+}
+
+/// A requirement of a package, and the package that would fulfill it
+#[derive(Eq, PartialEq, Clone, Debug, Serialize)]
+pub struct DependencyNode {
+    /// The requirement being fulfilled
+    pub requirement: PackageRequirement,
+    /// The newest package fulfilling the requirement, by name and version, if any
+    pub fulfilled_by: Option<(String, Version)>,
+    /// The requirements of the package fulfilling this requirement
+    pub dependencies: Vec<DependencyNode>,
+    /// Whether the requirements of the package fulfilling this requirement are listed elsewhere in the tree, rather than here
+    pub repeated: bool,
+}
+
+/// Everything known about a package
+#[derive(Eq, PartialEq, Clone, Debug, Serialize)]
+pub struct PackageInfo {
+    /// The name of the package
+    pub name: String,
+    /// The installed version of the package, if any
+    pub installed_version: Option<Version>,
+    /// Whether the installed package was requested explicitly
+    pub explicit: Option<bool>,
+    /// Every version of the package available from the repositories, newest first
+    pub versions: Vec<AvailableVersion>,
+    /// The dependency tree of the newest version of the package
+    pub dependency_tree: Vec<DependencyNode>,
+}
+
+/// Finds the newest package in the repositories fulfilling a requirement
+///
+/// # Arguments
+///
+/// * `requirement` - A requirement for a package (required)
+///
+/// * `repositories` - The repositories on this system (required)
+pub fn newest_candidate<'a>(
+    requirement: &PackageRequirement,
+    repositories: &'a HashSet<Repository>,
+) -> Option<&'a Package> {
+    repositories
+        .iter()
+        .flat_map(|repository| repository.packages.iter().flatten())
+        .filter(|package| requirement.is_fulfilled_by(package))
+        .max_by(|a, b| a.version.cmp(&b.version))
+}
+
+/// Builds the tree of dependencies of a package, resolving each to the newest package fulfilling it
+///
+/// Each package is only descended into once, where it first appears; later appearances are marked as repeated.
+/// This keeps trees of shared dependencies small, and terminates dependency cycles.
+///
+/// # Arguments
+///
+/// * `package` - The package at the root of the tree (required)
+///
+/// * `repositories` - The repositories on this system (required)
+///
+/// * `expanded` - The packages already descended into, by name and version (required)
+pub fn dependency_tree(
+    package: &Package,
+    repositories: &HashSet<Repository>,
+    expanded: &mut HashSet<(String, Version)>,
+) -> Vec<DependencyNode> {
+    expanded.insert((package.name.clone(), package.version.clone()));
+    let mut dependencies: Vec<&PackageRequirement> =
+        package.dependencies.iter().flatten().collect();
+    dependencies.sort_by(|a, b| a.name.cmp(&b.name));
+    dependencies
+        .into_iter()
+        .map(|requirement| {
+            let candidate = newest_candidate(requirement, repositories);
+            let fulfilled_by =
+                candidate.map(|candidate| (candidate.name.clone(), candidate.version.clone()));
+            let repeated = fulfilled_by
+                .as_ref()
+                .map(|fulfilled_by| expanded.contains(fulfilled_by))
+                .unwrap_or(false);
+            let children = match candidate {
+                Some(candidate) if !repeated => dependency_tree(candidate, repositories, expanded),
+                _ => Vec::new(),
+            };
+            DependencyNode {
+                requirement: requirement.clone(),
+                fulfilled_by,
+                dependencies: children,
+                repeated: repeated
+                    && candidate
+                        .map(|candidate| candidate.dependencies.iter().flatten().next().is_some())
+                        .unwrap_or(false),
+            }
+        })
+        .collect()
+}
+
+/// Gathers everything known about a package, from the repositories and the record of installed packages
+///
+/// # Arguments
+///
+/// * `name` - The name of a package (required)
+pub fn package_info(name: &str) -> Result<PackageInfo> {
+    let database = database::read_database()?;
+    let repositories = repository::read_repositories()?;
+    let installed_package = database.packages.get(name);
+    let mut versions: Vec<AvailableVersion> = repositories
+        .iter()
+        .flat_map(|repository| {
+            repository
+                .packages
+                .iter()
+                .flatten()
+                .filter(|package| package.name == name)
+                .map(move |package| AvailableVersion {
+                    package: package.clone(),
+                    repository: repository.name.clone(),
+                    installed: installed_package
+                        .map(|installed_package| installed_package.package.is_same_build(package))
+                        .unwrap_or(false),
+                })
+        })
+        .collect();
+    if versions.is_empty() && installed_package.is_none() {
+        return Err(QueryError::PackageNotFound(name.to_owned()).into());
+    }
+    versions.sort_by(|a, b| {
+        b.package
+            .version
+            .cmp(&a.package.version)
+            .then_with(|| a.repository.cmp(&b.repository))
+    });
+    let newest_package = versions
+        .first()
+        .map(|version| &version.package)
+        .or_else(|| installed_package.map(|installed_package| &installed_package.package));
+    let dependency_tree = newest_package
+        .map(|package| dependency_tree(package, &repositories, &mut HashSet::new()))
+        .unwrap_or_default();
+    Ok(PackageInfo {
+        name: name.to_owned(),
+        installed_version: installed_package
+            .map(|installed_package| installed_package.package.version.clone()),
+        explicit: installed_package.map(|installed_package| installed_package.explicit),
+        versions,
+        dependency_tree,
+    })
+}
+
+/// Which installed packages to list
+///
+/// If no kinds of package are selected, all installed packages are listed.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default)]
+pub struct ListFilter {
+    /// List packages that were requested explicitly
+    pub explicit: bool,
+    /// List packages that were installed as dependencies
+    pub dependencies: bool,
+    /// List packages installed as dependencies that are no longer required
+    pub orphans: bool,
+    /// List packages with newer versions in the repositories
+    pub upgradable: bool,
+    /// List packages that are no longer in any repository
+    pub foreign: bool,
+}
+
+/// An installed package, as listed
+#[derive(Eq, PartialEq, Clone, Debug, Serialize)]
+pub struct ListedPackage {
+    /// The name of the package
+    pub name: String,
+    /// The installed version of the package
+    pub version: Version,
+    /// The newest version of the package in the repositories, if any
+    pub available_version: Option<Version>,
+    /// Whether the package was requested explicitly
+    pub explicit: bool,
+    /// Whether the package was installed as a dependency and is no longer required
+    pub orphan: bool,
+}
+
+impl ListedPackage {
+    /// Whether a newer version of the package is in the repositories
+    pub fn is_upgradable(&self) -> bool {
+        self.available_version
+            .as_ref()
+            .map(|available_version| *available_version > self.version)
+            .unwrap_or(false)
+    }
+
+    /// Whether the package is no longer in any repository
+    pub fn is_foreign(&self) -> bool {
+        self.available_version.is_none()
+    }
+}
+
+impl ListFilter {
+    /// Determines whether an installed package is selected by this filter
+    ///
+    /// # Arguments
+    ///
+    /// * `listed_package` - An installed package (required)
+    pub fn matches(&self, listed_package: &ListedPackage) -> bool {
+        if !self.explicit
+            && !self.dependencies
+            && !self.orphans
+            && !self.upgradable
+            && !self.foreign
+        {
+            return true;
+        }
+        (self.explicit && listed_package.explicit)
+            || (self.dependencies && !listed_package.explicit)
+            || (self.orphans && listed_package.orphan)
+            || (self.upgradable && listed_package.is_upgradable())
+            || (self.foreign && listed_package.is_foreign())
+    }
+}
+
+/// Lists the installed packages, sorted by name
+///
+/// # Arguments
+///
+/// * `filter` - Which installed packages to list (required)
+pub fn list_packages(filter: ListFilter) -> Result<Vec<ListedPackage>> {
+    let database = database::read_database()?;
+    let repositories = repository::read_repositories()?;
+    let orphans: HashSet<&String> = database
+        .orphans()
+        .into_iter()
+        .map(|installed_package| &installed_package.package.name)
+        .collect();
+    // The database is keyed by name, so packages are already sorted
+    Ok(database
+        .packages
+        .values()
+        .map(|installed_package| {
+            let package = &installed_package.package;
+            let available_version = repositories
+                .iter()
+                .flat_map(|repository| repository.packages.iter().flatten())
+                .filter(|candidate| {
+                    candidate.name == package.name && candidate.arch == package.arch
+                })
+                .map(|candidate| candidate.version.clone())
+                .max();
+            ListedPackage {
+                name: package.name.clone(),
+                version: package.version.clone(),
+                available_version,
+                explicit: installed_package.explicit,
+                orphan: orphans.contains(&package.name),
+            }
+        })
+        .filter(|listed_package| filter.matches(listed_package))
+        .collect())
+}
@@ -1,5 +1,17 @@
+use crate::archive;
+use crate::error::{
+    FetchPackageError, FetchRepositoriesError, MetadataError, RepositoryBuildError,
+};
+use crate::journal;
+use crate::metadata::{self, Compression};
 use crate::package::Package;
+use crate::signature;
+use miette::Result;
+use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
 use url::Url;
 
 #[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
@@ -12,9 +24,20 @@ pub struct Repository {
     /// The address (URL or IP) of the repository
     pub address: String,
     /// The packages within this repository
+    #[serde(default)]
     pub packages: Option<HashSet<Package>>,
 }
 
+impl Hash for Repository {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+        self.address.hash(state);
+    }
+}
+
+/// The name of the repository metadata file when a repository is given as a local directory
+pub const REPOSITORY_METADATA_FILE: &str = "repository.bin";
+
 /// Fetches repository data from the filesystem or the Internet
 pub async fn fetch_repositories(sync: bool) -> Result<HashSet<Repository>> {
     match sync {
@@ -27,61 +50,314 @@ pub async fn fetch_repositories(sync: bool) -> Result<HashSet<Repository>> {
 pub fn read_repositories() -> Result<HashSet<Repository>> {
     let repositories_file = &fs::read("/etc/gany/gany-repos.bin");
     if let Ok(repositories) = repositories_file {
-        let repositories_data = bincode::deserialize(repositories);
+        let repositories_data = metadata::decode_repositories(repositories);
         if let Ok(repositories) = repositories_data {
             Ok(repositories)
         } else {
-            Err(FetchRepositoriesError::UnableToDeserialiseRepositoryDataFilesystem)
+            Err(FetchRepositoriesError::UnableToDeserialiseRepositoryDataFilesystem.into())
         }
     } else {
-        Err(FetchRepositoriesError::UnableToReadRepositoryData)
+        Err(FetchRepositoriesError::UnableToReadRepositoryData.into())
     }
 }
 
 /// Synchronises local repository data with remote sources
 pub async fn sync_repositories() -> Result<HashSet<Repository>> {
     let repositories_list_file = &fs::read("/etc/gany/gany-repos.yaml");
-
-    if (sync && repositories_list_file.is_err()) {
-        Err(FetchRepositoriesError::UnableToReadRepositoryList)
-    }
-
     if let Ok(repositories_list) = repositories_list_file {
-        let repositories_list_yaml: Result<HashSet<Url>, _> =
+        let repositories_list_yaml: Result<HashSet<String>, _> =
             serde_yaml::from_slice(repositories_list);
         if let Ok(repositories_list) = repositories_list_yaml {
+            let trusted_keys = signature::read_trusted_keys()?;
             let mut new_repositories: HashSet<Repository> = HashSet::new();
-            for repository_url in &repositories_list {
-                let client = reqwest::Client::new();
-                let http_request = client.get(&repository_url.as_str()).send().await;
-                if let Ok(response) = http_request {
-                    let repository_data = response.bytes().await?;
-                    let repository_bin: Result<Repository, _> =
-                        bincode::deserialize(&repository_data);
-                    if let Ok(repository) = repository_bin {
-                        new_repositories.insert(repository);
-                    } else {
-                        return Err(
-                            FetchRepositoriesError::UnableToDeserialiseRepositoryDataInternet(
-                                repository_url,
-                            ),
-                        );
-                    }
+            for repository_entry in &repositories_list {
+                let repository_url = &repository_url(repository_entry)?;
+                let metadata_address = metadata_address(repository_url);
+                let repository_data = read_address(&metadata_address).await?;
+                // Repository data must be signed by a trusted key, if any are configured
+                if !trusted_keys.is_empty() {
+                    let signature_address = signature_address(&metadata_address);
+                    let signature_data = read_address(&signature_address).await.map_err(|_| {
+                        FetchRepositoriesError::UnableToVerifyRepositoryData(repository_url.clone())
+                    })?;
+                    signature::verify(&repository_data, &signature_data, &trusted_keys).map_err(
+                        |_| {
+                            FetchRepositoriesError::UnableToVerifyRepositoryData(
+                                repository_url.clone(),
+                            )
+                        },
+                    )?;
+                }
+                let repository_bin = metadata::decode_repository(&repository_data);
+                if let Ok(mut repository) = repository_bin {
+                    // Package archives are resolved relative to where the repository was found
+                    repository.address = repository_url.to_string();
+                    new_repositories.insert(repository);
+                } else if repository_url.scheme() == "file" {
+                    return Err(
+                        FetchRepositoriesError::UnableToDeserialiseLocalRepositoryData(
+                            repository_url.clone(),
+                        )
+                        .into(),
+                    );
                 } else {
-                    return Err(FetchRepositoriesError::UnableToDownloadRepositoryData(
-                        repository.address,
-                    ));
+                    return Err(
+                        FetchRepositoriesError::UnableToDeserialiseRepositoryDataInternet(
+                            repository_url.clone(),
+                        )
+                        .into(),
+                    );
                 }
             }
-            write_file(
-                "/etc/gany/gany-repos.bin",
-                &bincode::serialize(&new_repositories).unwrap(),
-            );
+            journal::write_atomically(
+                Path::new("/etc/gany/gany-repos.bin"),
+                &metadata::encode(&new_repositories, Compression::Lz4)?,
+            )
+            .map_err(|_| FetchRepositoriesError::UnableToWriteRepositoryData)?;
             Ok(new_repositories)
         } else {
-            Err(FetchRepositoriesError::UnableToDeserialiseRepositoryList)
+            Err(FetchRepositoriesError::UnableToDeserialiseRepositoryList.into())
         }
     } else {
-        Err(FetchRepositoriesError::UnableToReadRepositoryList)
+        Err(FetchRepositoriesError::UnableToReadRepositoryList.into())
+    }
+}
+
+/// Determines the URL of a repository listed in the repository list
+///
+/// Entries may be URLs, or absolute paths to a repository on the local filesystem.
+///
+/// # Arguments
+///
+/// * `repository_entry` - An entry in the repository list (required)
+pub fn repository_url(repository_entry: &str) -> Result<Url> {
+    if let Ok(repository_url) = Url::parse(repository_entry) {
+        return Ok(repository_url);
     }
+    Ok(Url::from_file_path(repository_entry)
+        .map_err(|_| FetchRepositoriesError::InvalidRepositoryEntry(repository_entry.to_owned()))?)
+}
+
+/// Determines the address of a repository's metadata
+///
+/// If a local address refers to a directory, the repository metadata file within it is used.
+///
+/// # Arguments
+///
+/// * `repository_url` - The URL of a repository (required)
+pub fn metadata_address(repository_url: &Url) -> Url {
+    match repository_url.to_file_path() {
+        Ok(path) if path.is_dir() => {
+            Url::from_file_path(path.join(REPOSITORY_METADATA_FILE)).unwrap()
+        }
+        _ => repository_url.clone(),
+    }
+}
+
+/// Determines the address of the detached signature of some data
+///
+/// # Arguments
+///
+/// * `address` - The URL of the signed data (required)
+pub fn signature_address(address: &Url) -> Url {
+    let mut signature_address = address.clone();
+    signature_address.set_path(&format!(
+        "{}.{}",
+        address.path(),
+        signature::SIGNATURE_EXTENSION
+    ));
+    signature_address
+}
+
+/// Reads the data at an address, whether it is on the local filesystem or on the Internet
+///
+/// Addresses using the `file` scheme are read from the filesystem, including mounted media.
+///
+/// # Arguments
+///
+/// * `address` - The URL of some repository data or a package archive (required)
+pub async fn read_address(address: &Url) -> Result<Vec<u8>> {
+    if address.scheme() == "file" {
+        let path = address.to_file_path().map_err(|_| {
+            FetchRepositoriesError::UnableToReadLocalRepositoryData(address.clone())
+        })?;
+        Ok(fs::read(&path).map_err(|_| {
+            FetchRepositoriesError::UnableToReadLocalRepositoryData(address.clone())
+        })?)
+    } else {
+        let client = reqwest::Client::new();
+        let http_request = client
+            .get(address.as_str())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Ok(response) = http_request {
+            let data = response.bytes().await.map_err(|_| {
+                FetchRepositoriesError::UnableToDownloadRepositoryData(address.clone())
+            })?;
+            Ok(data.to_vec())
+        } else {
+            Err(FetchRepositoriesError::UnableToDownloadRepositoryData(address.clone()).into())
+        }
+    }
+}
+
+/// Determines the address of a package's archive within a repository
+///
+/// Archives sit alongside the repository metadata, so the same layout can be served over HTTP or from a local directory.
+///
+/// # Arguments
+///
+/// * `repository` - The repository containing the package (required)
+///
+/// * `package` - The package whose archive is being located (required)
+pub fn package_archive_address(repository: &Repository, package: &Package) -> Result<Url> {
+    let repository_address = Url::parse(&repository.address)
+        .map_err(|_| FetchPackageError::InvalidRepositoryAddress(repository.address.clone()))?;
+    // A local directory needs a trailing slash to have archives resolved inside of it
+    let base_address = match repository_address.to_file_path() {
+        Ok(path) if path.is_dir() && !repository_address.path().ends_with('/') => {
+            Url::from_directory_path(path).unwrap()
+        }
+        _ => repository_address,
+    };
+    Ok(base_address
+        .join(&package.archive_name())
+        .map_err(|_| FetchPackageError::InvalidRepositoryAddress(repository.address.clone()))?)
+}
+
+/// Fetches a package's archive from the repository containing it
+///
+/// # Arguments
+///
+/// * `repository` - The repository containing the package (required)
+///
+/// * `package` - The package whose archive is being fetched (required)
+pub async fn fetch_package_archive(repository: &Repository, package: &Package) -> Result<Vec<u8>> {
+    let archive_address = package_archive_address(repository, package)?;
+    Ok(read_address(&archive_address)
+        .await
+        .map_err(|_| FetchPackageError::UnableToFetchPackageArchive(archive_address))?)
+}
+
+/// The formats a human-readable repository index can be written in
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum IndexFormat {
+    Yaml,
+    Json,
+}
+
+/// Builds a repository from a directory of package archives
+///
+/// The repository metadata is written into the directory alongside the archives, so the directory can be served as-is.
+///
+/// # Arguments
+///
+/// * `directory` - The directory containing the package archives (required)
+///
+/// * `name` - The name of the repository (required)
+///
+/// * `description` - The description of the repository (required)
+///
+/// * `address` - The address the repository will be served from (required)
+///
+/// * `signing_key` - The path to a key to sign the repository metadata with (optional)
+///
+/// * `index_format` - The format of a human-readable index to write alongside the repository metadata (optional)
+pub fn build_repository(
+    directory: &Path,
+    name: String,
+    description: String,
+    address: String,
+    signing_key: Option<&Path>,
+    index_format: Option<IndexFormat>,
+) -> Result<Repository> {
+    let mut archive_paths: Vec<PathBuf> = fs::read_dir(directory)
+        .map_err(|_| RepositoryBuildError::UnableToReadDirectory(directory.to_path_buf()))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && path.extension() == Some("gany".as_ref()))
+        .collect();
+    archive_paths.sort();
+    let mut packages: HashSet<Package> = HashSet::new();
+    for archive_path in archive_paths {
+        let archive_data = fs::read(&archive_path)
+            .map_err(|_| RepositoryBuildError::UnableToReadArchive(archive_path.clone()))?;
+        let mut package = archive::read_package(&archive_data)?;
+        package.keccak = Some(archive::hash_data(&archive_data));
+        package.download_size = Some(archive_data.len() as u64);
+        // Clients locate archives by name, so the file must be named after its manifest
+        if archive_path.file_name() != Some(package.archive_name().as_ref()) {
+            return Err(RepositoryBuildError::MisnamedArchive(
+                archive_path,
+                package.archive_name(),
+            )
+            .into());
+        }
+        packages.insert(package);
+    }
+
+    let repository = Repository {
+        name,
+        description,
+        address,
+        packages: Some(packages),
+    };
+    let repository_data = metadata::encode(&repository, Compression::Lz4)?;
+    let metadata_path = directory.join(REPOSITORY_METADATA_FILE);
+    // Clients may be reading the repository while it is rebuilt, so they must never see a partial file
+    journal::write_atomically(&metadata_path, &repository_data)
+        .map_err(|_| RepositoryBuildError::UnableToWriteRepository(metadata_path.clone()))?;
+    if let Some(signing_key) = signing_key {
+        let signing_key = signature::read_signing_key(signing_key)?;
+        let signature_path =
+            metadata_path.with_extension(format!("bin.{}", signature::SIGNATURE_EXTENSION));
+        journal::write_atomically(
+            &signature_path,
+            &signature::sign(&repository_data, &signing_key),
+        )
+        .map_err(|_| RepositoryBuildError::UnableToWriteRepository(signature_path.clone()))?;
+    }
+    match index_format {
+        Some(IndexFormat::Yaml) => {
+            let index_path = metadata_path.with_extension("yaml");
+            journal::write_atomically(
+                &index_path,
+                serde_yaml::to_string(&repository).unwrap().as_bytes(),
+            )
+            .map_err(|_| RepositoryBuildError::UnableToWriteRepository(index_path))?;
+        }
+        Some(IndexFormat::Json) => {
+            let index_path = metadata_path.with_extension("json");
+            journal::write_atomically(
+                &index_path,
+                serde_json::to_string_pretty(&repository)
+                    .unwrap()
+                    .as_bytes(),
+            )
+            .map_err(|_| RepositoryBuildError::UnableToWriteRepository(index_path))?;
+        }
+        None => (),
+    }
+    Ok(repository)
+}
+
+/// Migrates repository data to the current metadata format
+///
+/// Repository data in the legacy `bincode` layout, or in an older version of the metadata format, is rewritten in the current one.
+/// Any signature of the repository data will need to be remade afterwards.
+///
+/// # Arguments
+///
+/// * `input` - The path to the repository data to be migrated (required)
+///
+/// * `output` - The path to write the migrated repository data to (required)
+pub fn migrate_repository(input: &Path, output: &Path) -> Result<Repository> {
+    let repository_data =
+        fs::read(input).map_err(|_| MetadataError::UnableToRead(input.to_path_buf()))?;
+    let repository = metadata::decode_repository(&repository_data)?;
+    // Repository data is often migrated in place, so it must never be left half-written
+    journal::write_atomically(output, &metadata::encode(&repository, Compression::Lz4)?)
+        .map_err(|_| MetadataError::UnableToWrite(output.to_path_buf()))?;
+    Ok(repository)
 }
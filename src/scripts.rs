@@ -0,0 +1,203 @@
+use crate::package::Package;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// The shell scripts are run with
+pub const SCRIPT_SHELL: &str = "/bin/sh";
+
+/// The search path scripts are run with, regardless of the environment Gany was run in
+pub const SCRIPT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// What happens to a transaction when one of a package's scripts fails
+#[derive(Eq, PartialEq, Hash, Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+    /// The transaction is stopped; once it is committed, the failure is only reported, and a failing trigger stops the triggers after it
+    #[default]
+    Abort,
+    /// The failure is reported, and the transaction continues
+    Warn,
+}
+
+/// The scripts run at each stage of a package's lifecycle
+#[derive(Eq, PartialEq, Hash, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PackageScripts {
+    /// Run before the package's files are put in place, if no other version is installed
+    #[serde(default)]
+    pub pre_install: Option<String>,
+    /// Run after the package's files are put in place, if no other version was installed
+    #[serde(default)]
+    pub post_install: Option<String>,
+    /// Run before the package's files are removed, including when another version replaces it
+    #[serde(default)]
+    pub pre_remove: Option<String>,
+    /// Run after the package's files are removed, including when another version replaces it
+    #[serde(default)]
+    pub post_remove: Option<String>,
+    /// Run before the package's files are put in place, if they replace another version
+    #[serde(default)]
+    pub pre_upgrade: Option<String>,
+    /// Run after the package's files are put in place, if they replaced another version
+    #[serde(default)]
+    pub post_upgrade: Option<String>,
+    /// What happens to a transaction when one of these scripts fails
+    #[serde(default)]
+    pub on_failure: FailurePolicy,
+}
+
+/// A stage of a package's lifecycle
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum ScriptPhase {
+    PreInstall,
+    PostInstall,
+    PreRemove,
+    PostRemove,
+    PreUpgrade,
+    PostUpgrade,
+}
+
+impl fmt::Display for ScriptPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ScriptPhase::PreInstall => "pre-install",
+            ScriptPhase::PostInstall => "post-install",
+            ScriptPhase::PreRemove => "pre-remove",
+            ScriptPhase::PostRemove => "post-remove",
+            ScriptPhase::PreUpgrade => "pre-upgrade",
+            ScriptPhase::PostUpgrade => "post-upgrade",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl PackageScripts {
+    /// The script run at a stage of a package's lifecycle, if there is one
+    ///
+    /// # Arguments
+    ///
+    /// * `phase` - A stage of a package's lifecycle (required)
+    pub fn script(&self, phase: ScriptPhase) -> Option<&String> {
+        match phase {
+            ScriptPhase::PreInstall => self.pre_install.as_ref(),
+            ScriptPhase::PostInstall => self.post_install.as_ref(),
+            ScriptPhase::PreRemove => self.pre_remove.as_ref(),
+            ScriptPhase::PostRemove => self.post_remove.as_ref(),
+            ScriptPhase::PreUpgrade => self.pre_upgrade.as_ref(),
+            ScriptPhase::PostUpgrade => self.post_upgrade.as_ref(),
+        }
+    }
+}
+
+/// A script that failed to run or did not succeed
+#[derive(Eq, PartialEq, Clone, Debug, Serialize)]
+pub struct ScriptFailure {
+    /// The name of the package the script belongs to
+    pub package: String,
+    /// The stage of the package's lifecycle the script was run at
+    pub phase: ScriptPhase,
+    /// Whether the script could be started at all
+    pub started: bool,
+    /// The exit code of the script, if it exited rather than being killed
+    pub exit_code: Option<i32>,
+}
+
+impl fmt::Display for ScriptFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.started {
+            return write!(
+                f,
+                "the {} script of '{}' could not be run",
+                self.phase, self.package
+            );
+        }
+        match self.exit_code {
+            Some(exit_code) => write!(
+                f,
+                "the {} script of '{}' exited with code {}",
+                self.phase, self.package, exit_code
+            ),
+            None => write!(
+                f,
+                "the {} script of '{}' was terminated",
+                self.phase, self.package
+            ),
+        }
+    }
+}
+
+/// Runs one of a package's scripts, if it has one for the given stage of its lifecycle
+///
+/// Scripts are run by `/bin/sh` in the directory packages are installed into, with an empty environment aside from:
+/// * `GANY_PACKAGE`, the name of the package
+/// * `GANY_PHASE`, the stage of the package's lifecycle
+/// * `GANY_OLD_VERSION`, the version of the package being replaced or removed, if any
+/// * `GANY_NEW_VERSION`, the version of the package being installed, if any
+/// * `GANY_ROOT`, the directory packages are installed into
+///
+/// The failure of a script is returned rather than acted on, as what follows depends on whether its transaction has been committed.
+///
+/// # Arguments
+///
+/// * `package` - The package the script belongs to (required)
+///
+/// * `phase` - The stage of the package's lifecycle (required)
+///
+/// * `old_version` - The version of the package being replaced or removed (optional)
+///
+/// * `new_version` - The version of the package being installed (optional)
+///
+/// * `root` - The directory packages are installed into (required)
+pub fn run_script(
+    package: &Package,
+    phase: ScriptPhase,
+    old_version: Option<&Version>,
+    new_version: Option<&Version>,
+    root: &Path,
+) -> Option<ScriptFailure> {
+    let script = package.scripts.as_ref()?.script(phase)?;
+    let mut command = Command::new(SCRIPT_SHELL);
+    command
+        .arg("-e")
+        .arg("-c")
+        .arg(script)
+        .current_dir(root)
+        .env_clear()
+        .env("PATH", SCRIPT_PATH)
+        .env("GANY_PACKAGE", &package.name)
+        .env("GANY_PHASE", phase.to_string())
+        .env("GANY_ROOT", root)
+        .stdin(Stdio::null());
+    if let Some(old_version) = old_version {
+        command.env("GANY_OLD_VERSION", old_version.to_string());
+    }
+    if let Some(new_version) = new_version {
+        command.env("GANY_NEW_VERSION", new_version.to_string());
+    }
+    let (started, exit_code) = match command.status() {
+        Ok(status) if status.success() => return None,
+        Ok(status) => (true, status.code()),
+        Err(_) => (false, None),
+    };
+    Some(ScriptFailure {
+        package: package.name.clone(),
+        phase,
+        started,
+        exit_code,
+    })
+}
+
+/// Determines what happens to a transaction when one of a package's scripts fails
+///
+/// # Arguments
+///
+/// * `package` - The package the scripts belong to (required)
+pub fn failure_policy(package: &Package) -> FailurePolicy {
+    package
+        .scripts
+        .as_ref()
+        .map(|scripts| scripts.on_failure)
+        .unwrap_or_default()
+}
@@ -0,0 +1,139 @@
+use crate::error::SignatureError;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use miette::Result;
+use rand::rngs::OsRng;
+use std::convert::TryInto;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// The file listing the public keys trusted to sign repository data and package archives
+pub const TRUSTED_KEYS_FILE: &str = "/etc/gany/gany-keys.yaml";
+
+/// The extension given to detached signature files
+pub const SIGNATURE_EXTENSION: &str = "sig";
+
+/// Creates the file a signing key is written to, readable only by its owner
+///
+/// An existing file is never overwritten.
+#[cfg(unix)]
+fn create_signing_key_file(path: &Path) -> io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+}
+
+/// Creates the file a signing key is written to
+///
+/// An existing file is never overwritten.
+#[cfg(not(unix))]
+fn create_signing_key_file(path: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+}
+
+/// Generates a new signing key, writing it and its public key to the filesystem
+///
+/// The public key is written alongside the signing key with a `.pub` extension.
+/// Existing keys are never overwritten.
+///
+/// # Arguments
+///
+/// * `path` - The path to write the signing key to (required)
+pub fn generate_signing_key(path: &Path) -> Result<VerifyingKey> {
+    let public_key_path = path.with_extension("pub");
+    if public_key_path.exists() {
+        return Err(SignatureError::KeyExists(public_key_path).into());
+    }
+    let signing_key = SigningKey::generate(&mut OsRng);
+    let verifying_key = signing_key.verifying_key();
+    let mut signing_key_file = create_signing_key_file(path).map_err(|error| {
+        if error.kind() == io::ErrorKind::AlreadyExists {
+            SignatureError::KeyExists(path.to_path_buf())
+        } else {
+            SignatureError::UnableToWriteKey(path.to_path_buf())
+        }
+    })?;
+    signing_key_file
+        .write_all(hex::encode(signing_key.to_bytes()).as_bytes())
+        .map_err(|_| SignatureError::UnableToWriteKey(path.to_path_buf()))?;
+    fs::write(&public_key_path, hex::encode(verifying_key.to_bytes()))
+        .map_err(|_| SignatureError::UnableToWriteKey(public_key_path))?;
+    Ok(verifying_key)
+}
+
+/// Reads a hexadecimal signing key from the filesystem
+///
+/// # Arguments
+///
+/// * `path` - The path to a signing key (required)
+pub fn read_signing_key(path: &Path) -> Result<SigningKey> {
+    let key_text = fs::read_to_string(path)
+        .map_err(|_| SignatureError::UnableToReadKey(path.to_path_buf()))?;
+    let key_bytes: [u8; 32] = hex::decode(key_text.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| SignatureError::InvalidKey(path.to_path_buf()))?;
+    Ok(SigningKey::from_bytes(&key_bytes))
+}
+
+/// Reads the public keys trusted to sign repository data and package archives
+///
+/// If no trusted keys are configured, an empty list is returned and signatures are not required.
+pub fn read_trusted_keys() -> Result<Vec<VerifyingKey>> {
+    let trusted_keys_file = match fs::read(TRUSTED_KEYS_FILE) {
+        Ok(trusted_keys_file) => trusted_keys_file,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let trusted_keys_yaml: Vec<String> = serde_yaml::from_slice(&trusted_keys_file)
+        .map_err(|_| SignatureError::UnableToDeserialiseTrustedKeys)?;
+    let mut trusted_keys = Vec::new();
+    for trusted_key in trusted_keys_yaml {
+        let key_bytes: [u8; 32] = hex::decode(trusted_key.trim())
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| SignatureError::InvalidTrustedKey(trusted_key.clone()))?;
+        let verifying_key = VerifyingKey::from_bytes(&key_bytes)
+            .map_err(|_| SignatureError::InvalidTrustedKey(trusted_key.clone()))?;
+        trusted_keys.push(verifying_key);
+    }
+    Ok(trusted_keys)
+}
+
+/// Signs some data, producing a detached signature
+///
+/// # Arguments
+///
+/// * `data` - The data to be signed (required)
+///
+/// * `signing_key` - The key to sign the data with (required)
+pub fn sign(data: &[u8], signing_key: &SigningKey) -> Vec<u8> {
+    signing_key.sign(data).to_bytes().to_vec()
+}
+
+/// Verifies that some data was signed by one of a set of trusted keys
+///
+/// # Arguments
+///
+/// * `data` - The data that was signed (required)
+///
+/// * `signature` - The detached signature of the data (required)
+///
+/// * `trusted_keys` - The public keys trusted to have signed the data (required)
+pub fn verify(data: &[u8], signature: &[u8], trusted_keys: &[VerifyingKey]) -> Result<()> {
+    let signature =
+        Signature::from_slice(signature).map_err(|_| SignatureError::InvalidSignature)?;
+    if trusted_keys
+        .iter()
+        .any(|trusted_key| trusted_key.verify(data, &signature).is_ok())
+    {
+        Ok(())
+    } else {
+        Err(SignatureError::UntrustedSignature.into())
+    }
+}
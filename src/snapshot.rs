@@ -0,0 +1,395 @@
+use crate::error::SnapshotError;
+use miette::Result;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The file configuring snapshots taken around transactions
+pub const SNAPSHOT_CONFIG_FILE: &str = "/etc/gany/gany-snapshots.yaml";
+
+/// The directory snapshots are kept in, unless configured otherwise
+pub const SNAPSHOT_DIRECTORY: &str = "/var/lib/gany/snapshots";
+
+/// A snapshot taken around a transaction
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// The name of the backend that took the snapshot
+    pub backend: String,
+    /// The label the snapshot was taken with
+    pub label: String,
+    /// Where the snapshot is kept
+    pub location: PathBuf,
+    /// When the snapshot was taken, in seconds since the Unix epoch
+    pub taken_at: u64,
+}
+
+/// A means of taking snapshots of the filesystem around transactions
+pub trait SnapshotBackend {
+    /// The name of this backend
+    fn name(&self) -> &str;
+
+    /// Takes a snapshot of the filesystem
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - A label unique to this snapshot (required)
+    ///
+    /// * `paths` - The installed paths affected by the transaction; backends may snapshot more than these (required)
+    ///
+    /// * `root` - The directory packages are installed into (required)
+    fn snapshot(&self, label: &str, paths: &[PathBuf], root: &Path) -> Result<Snapshot>;
+}
+
+/// Determines the current time, in seconds since the Unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Takes snapshots by copying or hard-linking the affected files into a snapshot directory
+///
+/// Hard links are safe to use, as transactions rename new files into place rather than writing over old ones.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct CopySnapshotBackend {
+    /// The directory snapshots are kept in
+    pub directory: PathBuf,
+    /// Whether files are hard-linked, rather than copied
+    pub hardlink: bool,
+}
+
+impl CopySnapshotBackend {
+    /// Preserves one filesystem entry in a snapshot
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The entry being preserved (required)
+    ///
+    /// * `destination` - Where the entry is preserved in the snapshot (required)
+    fn preserve(&self, source: &Path, destination: &Path) -> std::io::Result<()> {
+        let metadata = fs::symlink_metadata(source)?;
+        if metadata.is_dir() {
+            return fs::create_dir_all(destination);
+        }
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if metadata.file_type().is_symlink() {
+            copy_symlink(source, destination)
+        } else if self.hardlink {
+            // Hard links cannot cross filesystems, so files on another filesystem are copied instead
+            fs::hard_link(source, destination)
+                .or_else(|_| fs::copy(source, destination).map(|_| ()))
+        } else {
+            fs::copy(source, destination).map(|_| ())
+        }
+    }
+}
+
+impl SnapshotBackend for CopySnapshotBackend {
+    fn name(&self) -> &str {
+        if self.hardlink {
+            "hardlink"
+        } else {
+            "copy"
+        }
+    }
+
+    fn snapshot(&self, label: &str, paths: &[PathBuf], root: &Path) -> Result<Snapshot> {
+        let location = self.directory.join(label);
+        fs::create_dir_all(&location)
+            .map_err(|_| SnapshotError::UnableToSnapshot(location.clone()))?;
+        for path in paths {
+            let relative_path = path.strip_prefix("/").unwrap_or(path);
+            let source = root.join(relative_path);
+            // Paths the transaction creates have nothing to preserve yet
+            if fs::symlink_metadata(&source).is_err() {
+                continue;
+            }
+            self.preserve(&source, &location.join(relative_path))
+                .map_err(|_| SnapshotError::UnableToSnapshot(source.clone()))?;
+        }
+        Ok(Snapshot {
+            backend: self.name().to_owned(),
+            label: label.to_owned(),
+            location,
+            taken_at: now(),
+        })
+    }
+}
+
+/// Recreates a symbolic link elsewhere
+#[cfg(unix)]
+fn copy_symlink(source: &Path, destination: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(fs::read_link(source)?, destination)
+}
+
+/// Recreates a symbolic link elsewhere
+#[cfg(not(unix))]
+fn copy_symlink(source: &Path, destination: &Path) -> std::io::Result<()> {
+    fs::copy(source, destination).map(|_| ())
+}
+
+/// Takes read-only snapshots of a whole btrfs subvolume
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct BtrfsSnapshotBackend {
+    /// The subvolume being snapshotted
+    pub subvolume: PathBuf,
+    /// The directory snapshots are kept in, which must be on the same btrfs filesystem
+    pub directory: PathBuf,
+}
+
+impl BtrfsSnapshotBackend {
+    /// Determines whether the `btrfs` tool is available on this system
+    pub fn is_available() -> bool {
+        Command::new("btrfs")
+            .arg("--version")
+            .output()
+            .map(|output| output.status.success())
+            .unwrap_or(false)
+    }
+}
+
+impl SnapshotBackend for BtrfsSnapshotBackend {
+    fn name(&self) -> &str {
+        "btrfs"
+    }
+
+    fn snapshot(&self, label: &str, _paths: &[PathBuf], _root: &Path) -> Result<Snapshot> {
+        fs::create_dir_all(&self.directory)
+            .map_err(|_| SnapshotError::UnableToSnapshot(self.directory.clone()))?;
+        let location = self.directory.join(label);
+        let output = Command::new("btrfs")
+            .args(["subvolume", "snapshot", "-r"])
+            .arg(&self.subvolume)
+            .arg(&location)
+            .output()
+            .map_err(|_| SnapshotError::UnableToSnapshot(self.subvolume.clone()))?;
+        if !output.status.success() {
+            return Err(SnapshotError::BtrfsSnapshotFailed(
+                String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+            )
+            .into());
+        }
+        Ok(Snapshot {
+            backend: self.name().to_owned(),
+            label: label.to_owned(),
+            location,
+            taken_at: now(),
+        })
+    }
+}
+
+/// The kinds of snapshot backend that can be configured
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotKind {
+    /// Affected files are copied into the snapshot directory
+    #[default]
+    Copy,
+    /// Affected files are hard-linked into the snapshot directory
+    Hardlink,
+    /// The whole subvolume is snapshotted
+    Btrfs,
+}
+
+/// The directory snapshots are kept in, unless configured otherwise
+fn default_snapshot_directory() -> PathBuf {
+    PathBuf::from(SNAPSHOT_DIRECTORY)
+}
+
+/// The subvolume snapshotted by the btrfs backend, unless configured otherwise
+fn default_subvolume() -> PathBuf {
+    PathBuf::from("/")
+}
+
+/// Enables a configuration option unless it is configured otherwise
+fn default_true() -> bool {
+    true
+}
+
+/// The configuration of snapshots taken around transactions
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct SnapshotConfig {
+    /// Whether snapshots are taken at all
+    #[serde(default)]
+    pub enabled: bool,
+    /// The kind of snapshot taken
+    #[serde(default)]
+    pub backend: SnapshotKind,
+    /// The directory snapshots are kept in
+    #[serde(default = "default_snapshot_directory")]
+    pub directory: PathBuf,
+    /// The btrfs subvolume snapshotted by the btrfs backend
+    #[serde(default = "default_subvolume")]
+    pub subvolume: PathBuf,
+    /// Whether a snapshot is taken before each transaction is committed
+    #[serde(default = "default_true")]
+    pub pre: bool,
+    /// Whether a snapshot is taken after each transaction is committed
+    #[serde(default = "default_true")]
+    pub post: bool,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        SnapshotConfig {
+            enabled: false,
+            backend: SnapshotKind::default(),
+            directory: default_snapshot_directory(),
+            subvolume: default_subvolume(),
+            pre: true,
+            post: true,
+        }
+    }
+}
+
+impl SnapshotConfig {
+    /// Chooses the backend snapshots are taken with, if snapshots are enabled
+    ///
+    /// The btrfs backend is refused when btrfs is unavailable, rather than taking snapshots of another kind.
+    pub fn backend(&self) -> Result<Option<Box<dyn SnapshotBackend>>> {
+        if !self.enabled {
+            return Ok(None);
+        }
+        Ok(Some(match self.backend {
+            SnapshotKind::Btrfs => {
+                if !BtrfsSnapshotBackend::is_available() {
+                    return Err(SnapshotError::BtrfsUnavailable.into());
+                }
+                Box::new(BtrfsSnapshotBackend {
+                    subvolume: self.subvolume.clone(),
+                    directory: self.directory.clone(),
+                })
+            }
+            SnapshotKind::Copy => Box::new(CopySnapshotBackend {
+                directory: self.directory.clone(),
+                hardlink: false,
+            }),
+            SnapshotKind::Hardlink => Box::new(CopySnapshotBackend {
+                directory: self.directory.clone(),
+                hardlink: true,
+            }),
+        }))
+    }
+}
+
+/// Reads the configuration of snapshots from the filesystem
+///
+/// If snapshots have not been configured, they are disabled.
+pub fn read_snapshot_config() -> Result<SnapshotConfig> {
+    let snapshot_config_file = match fs::read(SNAPSHOT_CONFIG_FILE) {
+        Ok(snapshot_config_file) => snapshot_config_file,
+        Err(_) => return Ok(SnapshotConfig::default()),
+    };
+    Ok(serde_yaml::from_slice(&snapshot_config_file)
+        .map_err(|_| SnapshotError::UnableToDeserialiseConfig)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a file beneath a root, creating its parent directories
+    fn write_file(root: &Path, path: &str, contents: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn copy_backend_preserves_affected_files() {
+        let root = tempfile::tempdir().unwrap();
+        let snapshots = tempfile::tempdir().unwrap();
+        write_file(root.path(), "etc/example.conf", "setting = 1");
+        write_file(root.path(), "usr/bin/example", "binary");
+        write_file(root.path(), "usr/bin/unaffected", "binary");
+        let backend = CopySnapshotBackend {
+            directory: snapshots.path().to_owned(),
+            hardlink: false,
+        };
+        let snapshot = backend
+            .snapshot(
+                "transaction-1-pre",
+                &[
+                    PathBuf::from("/etc/example.conf"),
+                    PathBuf::from("/usr/bin/example"),
+                    PathBuf::from("/usr/bin/created"),
+                ],
+                root.path(),
+            )
+            .unwrap();
+        assert_eq!(snapshot.backend, "copy");
+        assert_eq!(
+            snapshot.location,
+            snapshots.path().join("transaction-1-pre")
+        );
+        assert_eq!(
+            fs::read_to_string(snapshot.location.join("etc/example.conf")).unwrap(),
+            "setting = 1"
+        );
+        assert!(snapshot.location.join("usr/bin/example").is_file());
+        // Paths not affected, or not yet created, are left out of the snapshot
+        assert!(!snapshot.location.join("usr/bin/unaffected").exists());
+        assert!(!snapshot.location.join("usr/bin/created").exists());
+    }
+
+    #[test]
+    fn copy_backend_is_unaffected_by_later_changes() {
+        let root = tempfile::tempdir().unwrap();
+        let snapshots = tempfile::tempdir().unwrap();
+        write_file(root.path(), "etc/example.conf", "setting = 1");
+        let backend = CopySnapshotBackend {
+            directory: snapshots.path().to_owned(),
+            hardlink: false,
+        };
+        let snapshot = backend
+            .snapshot(
+                "transaction-1-pre",
+                &[PathBuf::from("/etc/example.conf")],
+                root.path(),
+            )
+            .unwrap();
+        // Writing over the file in place would also change a hard-linked snapshot, but never a copy
+        fs::write(root.path().join("etc/example.conf"), "setting = 2").unwrap();
+        assert_eq!(
+            fs::read_to_string(snapshot.location.join("etc/example.conf")).unwrap(),
+            "setting = 1"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn copy_backend_recreates_symlinks() {
+        let root = tempfile::tempdir().unwrap();
+        let snapshots = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("usr/lib")).unwrap();
+        std::os::unix::fs::symlink("libexample.so.1", root.path().join("usr/lib/libexample.so"))
+            .unwrap();
+        let backend = CopySnapshotBackend {
+            directory: snapshots.path().to_owned(),
+            hardlink: false,
+        };
+        let snapshot = backend
+            .snapshot(
+                "transaction-1-pre",
+                &[PathBuf::from("/usr/lib/libexample.so")],
+                root.path(),
+            )
+            .unwrap();
+        assert_eq!(
+            fs::read_link(snapshot.location.join("usr/lib/libexample.so")).unwrap(),
+            PathBuf::from("libexample.so.1")
+        );
+    }
+
+    #[test]
+    fn copy_backend_is_the_default() {
+        let snapshot_config: SnapshotConfig = serde_yaml::from_str("enabled: true").unwrap();
+        assert_eq!(snapshot_config.backend, SnapshotKind::Copy);
+        assert_eq!(snapshot_config.backend().unwrap().unwrap().name(), "copy");
+    }
+}
@@ -0,0 +1,1408 @@
+use crate::archive::{self, EntryKind, FileEntry, PackageArchive};
+use crate::attributes;
+use crate::config;
+use crate::database::{self, Database};
+use crate::error::{ScriptError, TransactionError};
+use crate::history;
+use crate::journal::{self, Journal, JournalState, StagedDirectory};
+use crate::package::{self, Package};
+use crate::repository::{self, Repository};
+use crate::scripts::{self, FailurePolicy, ScriptFailure, ScriptPhase};
+use crate::signature;
+use crate::snapshot;
+use crate::triggers::{self, TriggerFailure};
+use crate::ARCH;
+use miette::Result;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The directory packages are installed into
+pub const INSTALL_ROOT: &str = "/";
+
+/// The directory package archives are cached in once fetched
+pub const ARCHIVE_CACHE_DIRECTORY: &str = "/var/cache/gany";
+
+/// Where the archive of a package in a transaction comes from
+#[derive(Clone, Debug)]
+pub enum PackageSource {
+    /// The package is held in a repository
+    Repository(Repository),
+    /// The package is held in a local archive
+    Local(PathBuf),
+}
+
+/// A path claimed by more than one package, or by a package while already present on disk without an owner
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct FileConflict {
+    /// The path claimed by the package being installed
+    pub path: PathBuf,
+    /// The name of the package being installed
+    pub package: String,
+    /// The name of the package already claiming the path, either installed or being installed; if none, the path exists on disk but is not owned by any package
+    pub owner: Option<String>,
+}
+
+impl fmt::Display for FileConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.owner {
+            Some(owner) => write!(
+                f,
+                "{} is claimed by both '{}' and '{}'",
+                self.path.display(),
+                self.package,
+                owner
+            ),
+            None => write!(
+                f,
+                "{} is claimed by '{}', but already exists and is not owned by any package",
+                self.path.display(),
+                self.package
+            ),
+        }
+    }
+}
+
+/// Determines the paths a package claims exclusive ownership of
+///
+/// Directories may be shared between packages, but every other entry, including ghost files, may only have one owner.
+///
+/// # Arguments
+///
+/// * `package` - A package (required)
+///
+/// * `entries` - The filesystem entries of the package (required)
+pub fn claimed_paths(package: &Package, entries: &[FileEntry]) -> HashSet<PathBuf> {
+    let directories: HashSet<&PathBuf> = entries
+        .iter()
+        .filter(|entry| entry.kind == EntryKind::Directory)
+        .map(|entry| &entry.path)
+        .collect();
+    entries
+        .iter()
+        .map(|entry| &entry.path)
+        .chain(package.files.iter())
+        .filter(|path| !directories.contains(path))
+        .cloned()
+        .collect()
+}
+
+/// Finds the paths claimed by more than one package once a transaction is committed
+///
+/// Conflicts are checked among the packages being installed, and between them and installed packages they do not replace.
+/// Files a package ships which already exist on disk without being owned by any installed package are conflicts too, rather than being overwritten.
+/// Configuration files are the exception, as existing ones are kept and the new versions written beside them.
+///
+/// # Arguments
+///
+/// * `planned_archives` - The packages being installed, with their archives (required)
+///
+/// * `database` - The record of installed packages (required)
+///
+/// * `root` - The directory packages are installed into (required)
+pub fn find_file_conflicts(
+    planned_archives: &[(Package, PackageArchive)],
+    database: &Database,
+    root: &Path,
+) -> Vec<FileConflict> {
+    let replaced_names: HashSet<&String> = planned_archives
+        .iter()
+        .map(|(package, _)| &package.name)
+        .collect();
+    let mut owners: HashMap<PathBuf, String> = HashMap::new();
+    // Paths owned by the packages being replaced may already exist on disk
+    let mut replaced_paths: HashSet<PathBuf> = HashSet::new();
+    for installed_package in database.packages.values() {
+        let paths = claimed_paths(&installed_package.package, &installed_package.entries);
+        if replaced_names.contains(&installed_package.package.name) {
+            replaced_paths.extend(paths);
+            continue;
+        }
+        for path in paths {
+            owners.insert(path, installed_package.package.name.clone());
+        }
+    }
+    let mut file_conflicts: Vec<FileConflict> = Vec::new();
+    for (package, archive) in planned_archives {
+        let mut paths: Vec<PathBuf> = claimed_paths(package, &archive.header.entries)
+            .into_iter()
+            .collect();
+        paths.sort();
+        let shipped_paths: HashSet<&PathBuf> = archive
+            .header
+            .entries
+            .iter()
+            .map(|entry| &entry.path)
+            .collect();
+        for path in paths {
+            match owners.get(&path) {
+                Some(owner) if *owner != package.name => file_conflicts.push(FileConflict {
+                    path,
+                    package: package.name.clone(),
+                    owner: Some(owner.clone()),
+                }),
+                Some(_) => (),
+                None => {
+                    // Ghost files are never written, so an existing one is not overwritten
+                    let on_disk =
+                        fs::symlink_metadata(root.join(path.strip_prefix("/").unwrap_or(&path)));
+                    // Existing configuration files are kept, with the new versions written beside them
+                    let is_kept_config_file = package
+                        .config_files
+                        .iter()
+                        .flatten()
+                        .any(|config_file| *config_file == path)
+                        && on_disk
+                            .as_ref()
+                            .map(|metadata| metadata.is_file())
+                            .unwrap_or(false);
+                    let overwrites_unowned_file = shipped_paths.contains(&path)
+                        && !replaced_paths.contains(&path)
+                        && on_disk.is_ok()
+                        && !is_kept_config_file;
+                    if overwrites_unowned_file {
+                        file_conflicts.push(FileConflict {
+                            path: path.clone(),
+                            package: package.name.clone(),
+                            owner: None,
+                        });
+                    }
+                    owners.insert(path, package.name.clone());
+                }
+            }
+        }
+    }
+    file_conflicts
+}
+
+/// Determines whether a requested package refers to a local archive, rather than a package name
+///
+/// Archives must be named with the `.gany` extension or given as a path, so that a file in the current directory is never mistaken for a package of the same name.
+///
+/// # Arguments
+///
+/// * `request` - A package name or path to a package archive (required)
+pub fn is_local_archive(request: &str) -> bool {
+    request.ends_with(".gany")
+        || request.contains('/')
+        || request.contains(std::path::MAIN_SEPARATOR)
+}
+
+/// Finds the newest package with a given name, among the repositories and local archives
+///
+/// # Arguments
+///
+/// * `name` - The name of a package (required)
+///
+/// * `local_packages` - Packages from local archives, considered alongside those in repositories (required)
+pub async fn find_newest_package(name: &str, local_packages: &HashSet<Package>) -> Result<Package> {
+    let repositories = repository::fetch_repositories(false).await?;
+    repositories
+        .iter()
+        .flat_map(|repository| repository.packages.iter().flatten())
+        .chain(local_packages.iter())
+        .filter(|package| package.arch == *ARCH && package.name == name)
+        .max_by(|a, b| a.version.cmp(&b.version))
+        .cloned()
+        .ok_or_else(|| TransactionError::PackageNotFound(name.to_owned()).into())
+}
+
+/// Finds where the archive of a package can be obtained from
+///
+/// # Arguments
+///
+/// * `package` - A package in a transaction (required)
+///
+/// * `local_archives` - The paths of local archives, by the packages they hold (required)
+///
+/// * `repositories` - The repositories on this system (required)
+pub fn find_package_source(
+    package: &Package,
+    local_archives: &HashMap<String, PathBuf>,
+    repositories: &HashSet<Repository>,
+) -> Result<PackageSource> {
+    if let Some(archive_path) = local_archives.get(&package.archive_name()) {
+        return Ok(PackageSource::Local(archive_path.clone()));
+    }
+    repositories
+        .iter()
+        .find(|repository| {
+            repository
+                .packages
+                .as_ref()
+                .map(|packages| packages.contains(package))
+                .unwrap_or(false)
+        })
+        .map(|repository| PackageSource::Repository(repository.clone()))
+        .ok_or_else(|| TransactionError::PackageNotFound(package.name.clone()).into())
+}
+
+/// Obtains the archive of a package, verifying it against its repository and any trusted keys
+///
+/// Archives fetched from a repository are kept in the archive cache.
+///
+/// # Arguments
+///
+/// * `package` - A package in a transaction (required)
+///
+/// * `source` - Where the archive of the package comes from (required)
+pub async fn obtain_archive(package: &Package, source: &PackageSource) -> Result<PackageArchive> {
+    let archive_data = match source {
+        PackageSource::Local(archive_path) => fs::read(archive_path)
+            .map_err(|_| TransactionError::UnableToObtainArchive(package.name.clone()))?,
+        PackageSource::Repository(repository) => {
+            let cached_path = Path::new(ARCHIVE_CACHE_DIRECTORY).join(package.archive_name());
+            let archive_data = match fs::read(&cached_path) {
+                Ok(archive_data) => archive_data,
+                Err(_) => {
+                    let archive_data =
+                        repository::fetch_package_archive(repository, package).await?;
+                    // A failure to cache the archive should not prevent its installation
+                    let _ = fs::create_dir_all(ARCHIVE_CACHE_DIRECTORY)
+                        .and_then(|_| journal::write_atomically(&cached_path, &archive_data));
+                    archive_data
+                }
+            };
+            if let Some(keccak) = &package.keccak {
+                if archive::hash_data(&archive_data) != *keccak {
+                    let _ = fs::remove_file(&cached_path);
+                    return Err(TransactionError::ArchiveHashMismatch(package.name.clone()).into());
+                }
+            }
+            archive_data
+        }
+    };
+    let archive = archive::read_archive(&archive_data)?;
+    if !archive.header.package.is_same_build(package) {
+        return Err(TransactionError::UnexpectedArchiveContents(package.name.clone()).into());
+    }
+    archive::verify_archive(&archive, &signature::read_trusted_keys()?)?;
+    Ok(archive)
+}
+
+/// Finds the filesystem entries of a package that are no longer owned once it is replaced
+///
+/// # Arguments
+///
+/// * `old_entries` - The filesystem entries of the package being replaced (required)
+///
+/// * `new_entries` - The filesystem entries of the replacement package (required)
+fn replaced_entries(old_entries: &[FileEntry], new_entries: &[FileEntry]) -> Vec<FileEntry> {
+    let new_paths: HashSet<&PathBuf> = new_entries.iter().map(|entry| &entry.path).collect();
+    old_entries
+        .iter()
+        .filter(|entry| !new_paths.contains(&entry.path))
+        .cloned()
+        .collect()
+}
+
+/// The kind of change a transaction makes to a package
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum ChangeKind {
+    /// The package is not yet installed
+    Install,
+    /// The package replaces an older installed version
+    Upgrade,
+    /// The package replaces a newer installed version
+    Downgrade,
+    /// The package replaces another build of the same version
+    Reinstall,
+    /// The package is removed
+    Remove,
+}
+
+/// A change a transaction makes to a package
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct PlannedChange {
+    /// The kind of change made
+    pub kind: ChangeKind,
+    /// The name of the package
+    pub name: String,
+    /// The version of the package installed before the transaction, if any
+    pub installed_version: Option<Version>,
+    /// Whether the package installed before the transaction was installed explicitly, if there was one
+    #[serde(default)]
+    pub installed_explicit: Option<bool>,
+    /// The version of the package installed by the transaction, if any
+    pub version: Option<Version>,
+    /// The number of bytes to be downloaded
+    pub download_size: u64,
+    /// The change in the number of bytes installed
+    pub installed_size_delta: i64,
+}
+
+/// A package installed by a transaction
+#[derive(Clone, Debug)]
+pub struct PlannedInstallation {
+    /// The package being installed
+    pub package: Package,
+    /// Where the archive of the package comes from
+    pub source: PackageSource,
+    /// Whether the package was requested explicitly
+    pub explicit: bool,
+}
+
+/// Every change a transaction will make, computed before anything is committed
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct TransactionPlan {
+    /// The changes made to each package, by name
+    pub changes: Vec<PlannedChange>,
+    /// The total number of bytes to be downloaded
+    pub download_size: u64,
+    /// The total change in the number of bytes installed
+    pub installed_size_delta: i64,
+    /// The packages to be installed
+    #[serde(skip)]
+    pub installations: Vec<PlannedInstallation>,
+    /// The names of the packages to be removed
+    #[serde(skip)]
+    pub removals: Vec<String>,
+}
+
+impl TransactionPlan {
+    /// Builds a plan from the packages a transaction installs and removes
+    ///
+    /// # Arguments
+    ///
+    /// * `installations` - The packages to be installed (required)
+    ///
+    /// * `removals` - The names of the packages to be removed (required)
+    ///
+    /// * `database` - The record of installed packages (required)
+    pub fn new(
+        installations: Vec<PlannedInstallation>,
+        removals: Vec<String>,
+        database: &Database,
+    ) -> Self {
+        let mut changes: Vec<PlannedChange> = Vec::new();
+        for installation in &installations {
+            let package = &installation.package;
+            let installed_package = database.packages.get(&package.name);
+            let installed_version = installed_package
+                .map(|installed_package| installed_package.package.version.clone());
+            let kind = match &installed_version {
+                None => ChangeKind::Install,
+                Some(installed_version) if *installed_version < package.version => {
+                    ChangeKind::Upgrade
+                }
+                Some(installed_version) if *installed_version > package.version => {
+                    ChangeKind::Downgrade
+                }
+                Some(_) => ChangeKind::Reinstall,
+            };
+            let replaced_size = installed_package
+                .map(|installed_package| installed_package.installed_size())
+                .unwrap_or_default();
+            changes.push(PlannedChange {
+                kind,
+                name: package.name.clone(),
+                installed_version,
+                installed_explicit: installed_package
+                    .map(|installed_package| installed_package.explicit),
+                version: Some(package.version.clone()),
+                download_size: download_size(package, &installation.source),
+                installed_size_delta: package.installed_size.unwrap_or_default() as i64
+                    - replaced_size as i64,
+            });
+        }
+        for name in &removals {
+            if let Some(installed_package) = database.packages.get(name) {
+                changes.push(PlannedChange {
+                    kind: ChangeKind::Remove,
+                    name: name.clone(),
+                    installed_version: Some(installed_package.package.version.clone()),
+                    installed_explicit: Some(installed_package.explicit),
+                    version: None,
+                    download_size: 0,
+                    installed_size_delta: -(installed_package.installed_size() as i64),
+                });
+            }
+        }
+        changes.sort_by(|a, b| a.name.cmp(&b.name));
+        TransactionPlan {
+            download_size: changes.iter().map(|change| change.download_size).sum(),
+            installed_size_delta: changes
+                .iter()
+                .map(|change| change.installed_size_delta)
+                .sum(),
+            changes,
+            installations,
+            removals,
+        }
+    }
+
+    /// Whether the transaction makes no changes
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+/// Determines how many bytes must be downloaded to obtain the archive of a package
+///
+/// # Arguments
+///
+/// * `package` - A package in a transaction (required)
+///
+/// * `source` - Where the archive of the package comes from (required)
+fn download_size(package: &Package, source: &PackageSource) -> u64 {
+    match source {
+        PackageSource::Local(_) => 0,
+        PackageSource::Repository(_) => {
+            if Path::new(ARCHIVE_CACHE_DIRECTORY)
+                .join(package.archive_name())
+                .is_file()
+            {
+                0
+            } else {
+                package.download_size.unwrap_or_default()
+            }
+        }
+    }
+}
+
+/// Plans the installation of the packages in a solved transaction that are not already installed
+///
+/// # Arguments
+///
+/// * `transaction` - The packages chosen by the solver (required)
+///
+/// * `requested_packages` - The packages requested explicitly (required)
+///
+/// * `local_archives` - The paths of local archives, by the packages they hold (required)
+///
+/// * `repositories` - The repositories on this system (required)
+///
+/// * `database` - The record of installed packages (required)
+fn plan_installations(
+    transaction: Vec<Package>,
+    requested_packages: &HashSet<Package>,
+    local_archives: &HashMap<String, PathBuf>,
+    repositories: &HashSet<Repository>,
+    database: &Database,
+) -> Result<Vec<PlannedInstallation>> {
+    let mut installations: Vec<PlannedInstallation> = Vec::new();
+    for package in transaction {
+        if database.is_installed(&package) {
+            continue;
+        }
+        installations.push(PlannedInstallation {
+            source: find_package_source(&package, local_archives, repositories)?,
+            explicit: requested_packages.contains(&package),
+            package,
+        });
+    }
+    Ok(installations)
+}
+
+/// Plans the addition of packages to this system, along with their dependencies
+///
+/// # Arguments
+///
+/// * `requests` - Names of packages, or paths to package archives (required)
+pub async fn plan_addition(requests: &[String]) -> Result<TransactionPlan> {
+    let repositories = repository::fetch_repositories(false).await?;
+    let mut local_packages: HashSet<Package> = HashSet::new();
+    let mut local_archives: HashMap<String, PathBuf> = HashMap::new();
+    let mut requested_names: Vec<&String> = Vec::new();
+    for request in requests {
+        if is_local_archive(request) {
+            let archive_path = PathBuf::from(request);
+            let package = archive::read_package_file(&archive_path)?;
+            local_archives.insert(package.archive_name(), archive_path);
+            local_packages.insert(package);
+        } else {
+            requested_names.push(request);
+        }
+    }
+    let mut requested_packages: HashSet<Package> = local_packages.clone();
+    for name in requested_names {
+        requested_packages.insert(find_newest_package(name, &local_packages).await?);
+    }
+
+    let database: Database = database::read_database()?;
+    let transaction = package::solve_packages(
+        &requested_packages.iter().collect(),
+        &database.installed_packages(),
+        &local_packages,
+    )
+    .await?;
+    let installations = plan_installations(
+        transaction,
+        &requested_packages,
+        &local_archives,
+        &repositories,
+        &database,
+    )?;
+    Ok(TransactionPlan::new(installations, Vec::new(), &database))
+}
+
+/// Finds the newest versions of installed packages held in the repositories, where they are newer than the installed versions
+///
+/// # Arguments
+///
+/// * `database` - The record of installed packages (required)
+///
+/// * `repositories` - The repositories on this system (required)
+fn upgradable_packages(
+    database: &Database,
+    repositories: &HashSet<Repository>,
+) -> HashSet<Package> {
+    database
+        .packages
+        .values()
+        .filter_map(|installed_package| {
+            repositories
+                .iter()
+                .flat_map(|repository| repository.packages.iter().flatten())
+                .filter(|package| {
+                    package.arch == *ARCH && package.name == installed_package.package.name
+                })
+                .max_by(|a, b| a.version.cmp(&b.version))
+                .filter(|newest_package| newest_package.version > installed_package.package.version)
+                .cloned()
+        })
+        .collect()
+}
+
+/// Plans the upgrade of installed packages to the newest versions held in the repositories
+///
+/// Installed packages not held in any repository are left as they are.
+pub async fn plan_upgrade() -> Result<TransactionPlan> {
+    let repositories = repository::fetch_repositories(false).await?;
+    let database: Database = database::read_database()?;
+    let local_packages: HashSet<Package> = HashSet::new();
+    let requested_packages = upgradable_packages(&database, &repositories);
+    if requested_packages.is_empty() {
+        return Ok(TransactionPlan::default());
+    }
+
+    let transaction = package::solve_packages(
+        &requested_packages.iter().collect(),
+        &database.installed_packages(),
+        &local_packages,
+    )
+    .await?;
+    // Upgrading a package does not make it explicitly installed
+    let installations = plan_installations(
+        transaction,
+        &HashSet::new(),
+        &HashMap::new(),
+        &repositories,
+        &database,
+    )?;
+    Ok(TransactionPlan::new(installations, Vec::new(), &database))
+}
+
+/// Ensures that no remaining installed package depends on a package being removed
+///
+/// # Arguments
+///
+/// * `names` - The names of the installed packages being removed (required)
+///
+/// * `database` - The record of installed packages (required)
+fn check_removal(names: &[String], database: &Database) -> Result<()> {
+    let (removed_packages, remaining_packages): (Vec<&Package>, Vec<&Package>) = database
+        .packages
+        .values()
+        .map(|installed_package| &installed_package.package)
+        .partition(|package| names.contains(&package.name));
+    for remaining_package in &remaining_packages {
+        for dependency in remaining_package.dependencies.iter().flatten() {
+            let still_fulfilled = remaining_packages
+                .iter()
+                .any(|package| dependency.is_fulfilled_by(package));
+            if still_fulfilled {
+                continue;
+            }
+            if let Some(removed_package) = removed_packages
+                .iter()
+                .find(|package| dependency.is_fulfilled_by(package))
+            {
+                return Err(TransactionError::RequiredByInstalled(
+                    removed_package.name.clone(),
+                    remaining_package.name.clone(),
+                )
+                .into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Plans the removal of installed packages
+///
+/// Packages still required by other installed packages cannot be removed.
+///
+/// # Arguments
+///
+/// * `names` - The names of installed packages (required)
+pub fn plan_removal(names: &[String]) -> Result<TransactionPlan> {
+    removal_plan(names, &database::read_database()?)
+}
+
+/// Plans the removal of installed packages from a record of installed packages
+///
+/// # Arguments
+///
+/// * `names` - The names of installed packages (required)
+///
+/// * `database` - The record of installed packages (required)
+fn removal_plan(names: &[String], database: &Database) -> Result<TransactionPlan> {
+    for name in names {
+        if !database.packages.contains_key(name) {
+            return Err(TransactionError::PackageNotInstalled(name.clone()).into());
+        }
+    }
+    check_removal(names, database)?;
+    Ok(TransactionPlan::new(Vec::new(), names.to_vec(), database))
+}
+
+/// Finds a version of a package that was previously installed, falling back to the archive cache
+///
+/// Cached archives remain available once repositories no longer hold the version.
+///
+/// # Arguments
+///
+/// * `name` - The name of the package (required)
+///
+/// * `version` - The version of the package (required)
+///
+/// * `repositories` - The repositories on this system (required)
+fn find_previous_package(
+    name: &str,
+    version: &Version,
+    repositories: &HashSet<Repository>,
+) -> Result<(Package, PackageSource)> {
+    let is_previous_package = |package: &Package| {
+        package.name == name && package.version == *version && package.arch == *ARCH
+    };
+    let repository_package = repositories.iter().find_map(|repository| {
+        repository
+            .packages
+            .iter()
+            .flatten()
+            .find(|package| is_previous_package(package))
+            .map(|package| (package, repository))
+    });
+    // A repository source still prefers the cached archive, which is then checked against the repository's hash
+    if let Some((package, repository)) = repository_package {
+        return Ok((
+            package.clone(),
+            PackageSource::Repository(repository.clone()),
+        ));
+    }
+    // Without a repository listing the version, the cached archives of the package are searched for it
+    let mut cached_paths: Vec<PathBuf> = fs::read_dir(ARCHIVE_CACHE_DIRECTORY)
+        .map(|cached_archives| {
+            cached_archives
+                .filter_map(|cached_archive| cached_archive.ok())
+                .filter(|cached_archive| {
+                    cached_archive
+                        .file_name()
+                        .to_string_lossy()
+                        .starts_with(name)
+                })
+                .map(|cached_archive| cached_archive.path())
+                .collect()
+        })
+        .unwrap_or_default();
+    cached_paths.sort();
+    for cached_path in cached_paths {
+        if let Ok(package) = archive::read_package_file(&cached_path) {
+            if is_previous_package(&package)
+                && cached_path.file_name() == Some(package.archive_name().as_ref())
+            {
+                return Ok((package, PackageSource::Local(cached_path)));
+            }
+        }
+    }
+    Err(TransactionError::PreviousVersionUnavailable(name.to_owned(), version.clone()).into())
+}
+
+/// Plans the reversal of a committed transaction
+///
+/// Packages the transaction installed are removed, and packages it replaced or removed are reinstalled at their previous versions.
+///
+/// # Arguments
+///
+/// * `id` - The identifier of a committed transaction (required)
+pub async fn plan_undo(id: u64) -> Result<TransactionPlan> {
+    let entry = history::find_transaction(id)?;
+    let repositories = repository::fetch_repositories(false).await?;
+    let database: Database = database::read_database()?;
+    let mut installations: Vec<PlannedInstallation> = Vec::new();
+    let mut removals: Vec<String> = Vec::new();
+    for change in &entry.changes {
+        match (change.kind, &change.installed_version) {
+            (ChangeKind::Install, _) | (_, None) => {
+                if database.packages.contains_key(&change.name) {
+                    removals.push(change.name.clone());
+                }
+            }
+            (_, Some(installed_version)) => {
+                let (package, source) =
+                    find_previous_package(&change.name, installed_version, &repositories)?;
+                if database.is_installed(&package) {
+                    continue;
+                }
+                // A package is reinstalled as explicitly as it was installed before the transaction
+                installations.push(PlannedInstallation {
+                    package,
+                    source,
+                    explicit: change.installed_explicit.unwrap_or(false),
+                });
+            }
+        }
+    }
+    check_removal(&removals, &database)?;
+    Ok(TransactionPlan::new(installations, removals, &database))
+}
+
+/// Plans the reversal of the most recently committed transaction
+pub async fn plan_rollback() -> Result<TransactionPlan> {
+    let history = history::read_history()?;
+    let entry = history
+        .entries
+        .last()
+        .ok_or(TransactionError::NothingToRollBack)?;
+    plan_undo(entry.id).await
+}
+
+/// What happened around a committed transaction, aside from the changes it made
+#[derive(Eq, PartialEq, Clone, Debug, Default, Serialize)]
+pub struct CommitReport {
+    /// The scripts that failed without stopping the transaction, including any that failed once it was committed
+    pub script_failures: Vec<ScriptFailure>,
+    /// The names of the triggers run at the end of the transaction
+    pub triggers_run: Vec<String>,
+    /// The triggers that failed, each run once the transaction was committed
+    pub trigger_failures: Vec<TriggerFailure>,
+    /// Why the snapshot taken once the transaction was committed could not be taken, if it could not
+    pub snapshot_failure: Option<String>,
+    /// The modified configuration files that were kept, with the new versions written beside them
+    pub preserved_config_files: Vec<PathBuf>,
+}
+
+/// Describes the ghost files of a package that are present on disk, so that they can be removed
+///
+/// # Arguments
+///
+/// * `package` - A package (required)
+///
+/// * `root` - The directory packages are installed into (required)
+fn ghost_entries(package: &Package, root: &Path) -> Vec<FileEntry> {
+    package
+        .ghost_files
+        .iter()
+        .flatten()
+        .filter_map(|path| {
+            let metadata =
+                fs::symlink_metadata(root.join(path.strip_prefix("/").unwrap_or(path))).ok()?;
+            Some(FileEntry {
+                path: path.clone(),
+                kind: if metadata.is_dir() {
+                    EntryKind::Directory
+                } else {
+                    EntryKind::File
+                },
+                size: 0,
+                digest: None,
+                target: None,
+                mode: None,
+                uid: None,
+                gid: None,
+            })
+        })
+        .collect()
+}
+
+/// A package whose scripts are run by a transaction
+struct ScriptedPackage {
+    /// The package being installed, or the installed package being removed
+    package: Package,
+    /// The version of the package installed before the transaction, if any
+    old_version: Option<Version>,
+    /// The version of the package installed by the transaction, if any
+    new_version: Option<Version>,
+    /// Whether this is the installed version of the package, being replaced by another
+    replaced: bool,
+}
+
+impl ScriptedPackage {
+    /// Determines the stage of the package's lifecycle a transaction reaches before or after it is committed
+    ///
+    /// # Arguments
+    ///
+    /// * `committed` - Whether the transaction has been committed (required)
+    fn phase(&self, committed: bool) -> ScriptPhase {
+        // A replaced version is removed, whichever version replaces it
+        let new_version = self.new_version.as_ref().filter(|_| !self.replaced);
+        match (committed, &self.old_version, new_version) {
+            (false, None, Some(_)) => ScriptPhase::PreInstall,
+            (false, Some(_), Some(_)) => ScriptPhase::PreUpgrade,
+            (false, _, None) => ScriptPhase::PreRemove,
+            (true, _, None) => ScriptPhase::PostRemove,
+            (true, None, Some(_)) => ScriptPhase::PostInstall,
+            (true, Some(_), Some(_)) => ScriptPhase::PostUpgrade,
+        }
+    }
+}
+
+/// Runs one script of a package in a transaction, before or after the transaction is committed
+///
+/// # Arguments
+///
+/// * `scripted_package` - The package whose script is run (required)
+///
+/// * `committed` - Whether the transaction has been committed (required)
+///
+/// * `root` - The directory packages are installed into (required)
+fn run_script(
+    scripted_package: &ScriptedPackage,
+    committed: bool,
+    root: &Path,
+) -> Option<ScriptFailure> {
+    scripts::run_script(
+        &scripted_package.package,
+        scripted_package.phase(committed),
+        scripted_package.old_version.as_ref(),
+        scripted_package.new_version.as_ref(),
+        root,
+    )
+}
+
+/// Runs the scripts of the packages in a transaction before it is committed
+///
+/// A failing script stops the transaction, unless its package's failure policy allows it to continue.
+///
+/// # Arguments
+///
+/// * `scripted_packages` - The packages whose scripts are run (required)
+///
+/// * `root` - The directory packages are installed into (required)
+///
+/// * `script_failures` - The failures of scripts whose failure policy allows the transaction to continue (required)
+fn run_pre_commit_scripts(
+    scripted_packages: &[ScriptedPackage],
+    root: &Path,
+    script_failures: &mut Vec<ScriptFailure>,
+) -> Result<()> {
+    for scripted_package in scripted_packages {
+        if let Some(script_failure) = run_script(scripted_package, false, root) {
+            if scripts::failure_policy(&scripted_package.package) == FailurePolicy::Abort {
+                return Err(ScriptError::ScriptFailed(script_failure).into());
+            }
+            script_failures.push(script_failure);
+        }
+    }
+    Ok(())
+}
+
+/// Runs the scripts of the packages in a transaction once it is committed
+///
+/// The transaction can no longer be stopped, so every failing script is only reported.
+///
+/// # Arguments
+///
+/// * `scripted_packages` - The packages whose scripts are run (required)
+///
+/// * `root` - The directory packages are installed into (required)
+///
+/// * `script_failures` - The failures of scripts run so far in the transaction (required)
+fn run_post_commit_scripts(
+    scripted_packages: &[ScriptedPackage],
+    root: &Path,
+    script_failures: &mut Vec<ScriptFailure>,
+) {
+    script_failures.extend(
+        scripted_packages
+            .iter()
+            .filter_map(|scripted_package| run_script(scripted_package, true, root)),
+    );
+}
+
+/// Commits a transaction to this system
+///
+/// Every entry is staged beside its installed path and journaled before anything is replaced, so that an interrupted transaction can be recovered.
+///
+/// # Arguments
+///
+/// * `plan` - The plan of the transaction (required)
+///
+/// * `run_package_scripts` - Whether the scripts of the packages in the transaction are run (required)
+pub async fn commit_plan(
+    plan: &TransactionPlan,
+    run_package_scripts: bool,
+) -> Result<CommitReport> {
+    let mut database: Database = database::read_database()?;
+    let root = Path::new(INSTALL_ROOT);
+    // Obtain every archive before touching the filesystem, so conflicts are found before anything is committed
+    let mut planned_archives: Vec<(Package, PackageArchive)> = Vec::new();
+    for installation in &plan.installations {
+        let archive = obtain_archive(&installation.package, &installation.source).await?;
+        planned_archives.push((installation.package.clone(), archive));
+    }
+    let file_conflicts = find_file_conflicts(&planned_archives, &database, root);
+    if !file_conflicts.is_empty() {
+        return Err(TransactionError::FileConflicts(
+            file_conflicts
+                .iter()
+                .map(|file_conflict| file_conflict.to_string())
+                .collect(),
+        )
+        .into());
+    }
+
+    let mut staged_paths: Vec<PathBuf> = Vec::new();
+    let mut removed_entries: Vec<FileEntry> = Vec::new();
+    let mut scripted_packages: Vec<ScriptedPackage> = Vec::new();
+    let mut config_redirects: Vec<HashMap<PathBuf, PathBuf>> = Vec::new();
+    let mut resolved_entries: Vec<Vec<FileEntry>> = Vec::new();
+    for (installation, (package, archive)) in plan.installations.iter().zip(&planned_archives) {
+        let installed_package = database.packages.get(&package.name);
+        // Configuration files modified on disk are kept, with the new versions written beside them
+        let redirects = config::preserved_config_files(package, archive, installed_package, root);
+        // The replaced version's scripts run before those of the version replacing it
+        if let Some(installed_package) = installed_package {
+            scripted_packages.push(ScriptedPackage {
+                package: installed_package.package.clone(),
+                old_version: Some(installed_package.package.version.clone()),
+                new_version: Some(package.version.clone()),
+                replaced: true,
+            });
+        }
+        scripted_packages.push(ScriptedPackage {
+            package: package.clone(),
+            old_version: installed_package
+                .map(|installed_package| installed_package.package.version.clone()),
+            new_version: Some(package.version.clone()),
+            replaced: false,
+        });
+        staged_paths.extend(
+            archive
+                .header
+                .entries
+                .iter()
+                .filter(|entry| entry.kind != EntryKind::Directory)
+                .map(|entry| redirects.get(&entry.path).unwrap_or(&entry.path).clone()),
+        );
+        if let Some(installed_package) = installed_package {
+            let modified_config_files = config::modified_config_files(installed_package, root);
+            removed_entries.extend(
+                replaced_entries(&installed_package.entries, &archive.header.entries)
+                    .into_iter()
+                    .filter(|entry| !modified_config_files.contains(&entry.path)),
+            );
+            // Ghost files, and new versions of configuration files, the replacement no longer owns are removed
+            removed_entries.extend(ghost_entries(&installed_package.package, root));
+            removed_entries.extend(config::new_config_entries(installed_package, root));
+        }
+        // Owners are resolved before anything is staged, so that a missing user stops the transaction early
+        let entries = attributes::resolve_entries(package, &archive.header.entries, root)?;
+        let recorded_entries =
+            config::kept_config_entries(&entries, &redirects, installed_package, root);
+        let mut new_config_files: Vec<PathBuf> = redirects.values().cloned().collect();
+        new_config_files.sort();
+        database.record_installation(
+            package.clone(),
+            installation.explicit,
+            recorded_entries,
+            new_config_files,
+        );
+        config_redirects.push(redirects);
+        resolved_entries.push(entries);
+    }
+    for name in &plan.removals {
+        if let Some(installed_package) = database.packages.remove(name) {
+            // Configuration files modified on disk are left behind
+            let modified_config_files = config::modified_config_files(&installed_package, root);
+            removed_entries.extend(
+                installed_package
+                    .entries
+                    .iter()
+                    .filter(|entry| !modified_config_files.contains(&entry.path))
+                    .cloned(),
+            );
+            removed_entries.extend(ghost_entries(&installed_package.package, root));
+            removed_entries.extend(config::new_config_entries(&installed_package, root));
+            scripted_packages.push(ScriptedPackage {
+                old_version: Some(installed_package.package.version.clone()),
+                package: installed_package.package,
+                new_version: None,
+                replaced: false,
+            });
+        }
+    }
+    // Entries still owned once the transaction is committed, including ghost files and new versions of configuration files, must not be removed
+    let owned_paths: HashSet<&PathBuf> = database
+        .packages
+        .values()
+        .flat_map(|installed_package| {
+            installed_package
+                .entries
+                .iter()
+                .map(|entry| &entry.path)
+                .chain(installed_package.package.ghost_files.iter().flatten())
+                .chain(&installed_package.new_config_files)
+        })
+        .collect();
+    removed_entries.retain(|entry| !owned_paths.contains(&entry.path));
+
+    // Triggers are read before anything is staged, so that an invalid trigger stops the transaction early
+    let triggers = triggers::read_triggers()?;
+    let snapshot_config = snapshot::read_snapshot_config()?;
+    let snapshot_backend = snapshot_config.backend()?;
+    let affected_paths: Vec<PathBuf> = staged_paths
+        .iter()
+        .chain(removed_entries.iter().map(|entry| &entry.path))
+        .cloned()
+        .collect();
+    let created_directories = journal::missing_directories(
+        planned_archives
+            .iter()
+            .flat_map(|(_, archive)| &archive.header.entries)
+            .filter(|entry| entry.kind == EntryKind::Directory)
+            .map(|entry| entry.path.as_path())
+            .chain(staged_paths.iter().filter_map(|path| path.parent())),
+        root,
+    );
+    let directories: Vec<StagedDirectory> = planned_archives
+        .iter()
+        .zip(&resolved_entries)
+        .flat_map(|((package, _), entries)| {
+            entries
+                .iter()
+                .filter(|entry| entry.kind == EntryKind::Directory)
+                .map(move |entry| StagedDirectory {
+                    entry: entry.clone(),
+                    attributes: attributes::file_attributes(package, &entry.path).cloned(),
+                })
+        })
+        .collect();
+    let mut history_entry = history::new_entry(plan.changes.clone())?;
+    let mut journal = Journal {
+        state: JournalState::Staging,
+        staged_paths,
+        removed_entries,
+        database,
+        history_entry: None,
+        created_directories,
+        directories,
+    };
+    journal::write_journal(&journal, root)?;
+    for (((_, archive), redirects), entries) in planned_archives
+        .iter()
+        .zip(&config_redirects)
+        .zip(&resolved_entries)
+    {
+        if let Err(error) = archive::stage_archive(archive, root, redirects, entries) {
+            journal::roll_back(&journal, root)?;
+            return Err(error);
+        }
+    }
+    if let Some(snapshot_backend) = snapshot_backend.as_ref().filter(|_| snapshot_config.pre) {
+        let label = format!("transaction-{}-pre", history_entry.id);
+        match snapshot_backend.snapshot(&label, &affected_paths, root) {
+            Ok(snapshot) => history_entry.snapshots.push(snapshot),
+            Err(error) => {
+                journal::roll_back(&journal, root)?;
+                return Err(error);
+            }
+        }
+    }
+    let mut script_failures: Vec<ScriptFailure> = Vec::new();
+    if run_package_scripts {
+        if let Err(error) = run_pre_commit_scripts(&scripted_packages, root, &mut script_failures) {
+            journal::roll_back(&journal, root)?;
+            return Err(error);
+        }
+    }
+    journal.history_entry = Some(history_entry.clone());
+    journal::commit(journal, root)?;
+    if run_package_scripts {
+        run_post_commit_scripts(&scripted_packages, root, &mut script_failures);
+    }
+    // Triggers are configured by the administrator rather than packages, so they run regardless
+    let (triggers_run, trigger_failures) = triggers::run_triggers(&triggers, &affected_paths, root);
+    // The transaction is already committed, so a snapshot taken afterwards is recorded separately, and its failure only reported
+    let mut snapshot_failure: Option<String> = None;
+    if let Some(snapshot_backend) = snapshot_backend.as_ref().filter(|_| snapshot_config.post) {
+        let label = format!("transaction-{}-post", history_entry.id);
+        if let Err(error) = snapshot_backend
+            .snapshot(&label, &affected_paths, root)
+            .and_then(|snapshot| history::record_snapshot(history_entry.id, snapshot))
+        {
+            snapshot_failure = Some(error.to_string());
+        }
+    }
+    let mut preserved_config_files: Vec<PathBuf> = config_redirects
+        .iter()
+        .flat_map(|redirects| redirects.keys().cloned())
+        .collect();
+    preserved_config_files.sort();
+    Ok(CommitReport {
+        script_failures,
+        triggers_run,
+        trigger_failures,
+        snapshot_failure,
+        preserved_config_files,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::ArchiveHeader;
+    use crate::database::InstalledPackage;
+
+    fn file_entry(path: &str, contents: &[u8]) -> FileEntry {
+        FileEntry {
+            path: PathBuf::from(path),
+            kind: EntryKind::File,
+            size: contents.len() as u64,
+            digest: Some(archive::hash_data(contents)),
+            target: None,
+            mode: None,
+            uid: None,
+            gid: None,
+        }
+    }
+
+    /// Builds a package with its archive, holding only a header listing the given entries
+    fn package_archive(manifest: &str, entries: Vec<FileEntry>) -> (Package, PackageArchive) {
+        let package: Package = serde_yaml::from_str(manifest).unwrap();
+        let archive = PackageArchive {
+            header: ArchiveHeader {
+                package: package.clone(),
+                entries,
+                payload_keccak: String::new(),
+            },
+            header_data: Vec::new(),
+            signature: None,
+            payload: Vec::new(),
+        };
+        (package, archive)
+    }
+
+    #[test]
+    fn unowned_config_files_are_kept_rather_than_conflicting() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("etc")).unwrap();
+        fs::create_dir_all(root.path().join("usr/bin")).unwrap();
+        fs::write(root.path().join("etc/example.conf"), "administered").unwrap();
+        fs::write(root.path().join("usr/bin/example"), "unowned").unwrap();
+        let planned_archives = vec![package_archive(
+            "{arch: x86_64, name: example, description: '', version: 1.0.0, config_files: [/etc/example.conf]}",
+            vec![
+                file_entry("/etc/example.conf", b"shipped"),
+                file_entry("/usr/bin/example", b"binary"),
+            ],
+        )];
+        let file_conflicts =
+            find_file_conflicts(&planned_archives, &Database::default(), root.path());
+        assert_eq!(
+            file_conflicts,
+            vec![FileConflict {
+                path: PathBuf::from("/usr/bin/example"),
+                package: "example".to_owned(),
+                owner: None,
+            }]
+        );
+        // The existing configuration file is kept, with the shipped version written beside it
+        let (package, archive) = &planned_archives[0];
+        let redirects = config::preserved_config_files(package, archive, None, root.path());
+        assert_eq!(
+            redirects.get(Path::new("/etc/example.conf")),
+            Some(&config::new_config_path(Path::new("/etc/example.conf")))
+        );
+        let recorded_entries =
+            config::kept_config_entries(&archive.header.entries, &redirects, None, root.path());
+        assert_eq!(recorded_entries[0].size, "administered".len() as u64);
+        assert_eq!(recorded_entries[0].digest, None);
+    }
+
+    /// Builds a package for this system's architecture, optionally depending on another package
+    fn package(name: &str, version: &str, dependency: Option<(&str, &str)>) -> Package {
+        let dependencies = dependency
+            .map(|(dependency_name, requirement)| {
+                format!(
+                    "[{{arch: {}, name: {}, version: '{}', dependencies: null, conflicts: null}}]",
+                    *ARCH, dependency_name, requirement
+                )
+            })
+            .unwrap_or_else(|| "null".to_owned());
+        serde_yaml::from_str(&format!(
+            "{{arch: {}, name: {}, description: '', version: {}, dependencies: {}}}",
+            *ARCH, name, version, dependencies
+        ))
+        .unwrap()
+    }
+
+    fn install(database: &mut Database, package: Package, explicit: bool, entries: Vec<FileEntry>) {
+        database.packages.insert(
+            package.name.clone(),
+            InstalledPackage {
+                package,
+                explicit,
+                entries,
+                installed_at: 0,
+                new_config_files: Vec::new(),
+            },
+        );
+    }
+
+    fn repository(packages: Vec<Package>) -> HashSet<Repository> {
+        vec![Repository {
+            name: "main".to_owned(),
+            description: String::new(),
+            address: "/srv/gany".to_owned(),
+            packages: Some(packages.into_iter().collect()),
+        }]
+        .into_iter()
+        .collect()
+    }
+
+    #[test]
+    fn file_conflicts_are_found_between_owners() {
+        let root = tempfile::tempdir().unwrap();
+        let mut database = Database::default();
+        install(
+            &mut database,
+            package("shell", "1.0.0", None),
+            true,
+            vec![file_entry("/usr/bin/sh", b"shell")],
+        );
+        install(
+            &mut database,
+            package("editor", "1.0.0", None),
+            true,
+            vec![file_entry("/usr/bin/editor", b"editor")],
+        );
+        let planned_archives = vec![
+            // Replacing a package may claim the paths it owned
+            package_archive(
+                &format!(
+                    "{{arch: {}, name: editor, description: '', version: 2.0.0}}",
+                    *ARCH
+                ),
+                vec![file_entry("/usr/bin/editor", b"new editor")],
+            ),
+            package_archive(
+                &format!(
+                    "{{arch: {}, name: busybox, description: '', version: 1.0.0}}",
+                    *ARCH
+                ),
+                vec![
+                    file_entry("/usr/bin/sh", b"busybox"),
+                    file_entry("/usr/bin/vi", b"busybox"),
+                ],
+            ),
+            package_archive(
+                &format!(
+                    "{{arch: {}, name: vim, description: '', version: 1.0.0}}",
+                    *ARCH
+                ),
+                vec![file_entry("/usr/bin/vi", b"vim")],
+            ),
+        ];
+        let file_conflicts = find_file_conflicts(&planned_archives, &database, root.path());
+        assert_eq!(
+            file_conflicts,
+            vec![
+                FileConflict {
+                    path: PathBuf::from("/usr/bin/sh"),
+                    package: "busybox".to_owned(),
+                    owner: Some("shell".to_owned()),
+                },
+                FileConflict {
+                    path: PathBuf::from("/usr/bin/vi"),
+                    package: "vim".to_owned(),
+                    owner: Some("busybox".to_owned()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn upgrades_are_planned_to_the_newest_versions() {
+        let mut database = Database::default();
+        install(
+            &mut database,
+            package("app", "1.0.0", None),
+            true,
+            Vec::new(),
+        );
+        install(
+            &mut database,
+            package("library", "1.0.0", None),
+            false,
+            Vec::new(),
+        );
+        install(
+            &mut database,
+            package("foreign", "1.0.0", None),
+            true,
+            Vec::new(),
+        );
+        let repositories = repository(vec![
+            package("app", "1.0.0", None),
+            package("app", "1.1.0", None),
+            package("app", "1.2.0", Some(("library", "^1"))),
+            package("library", "0.9.0", None),
+        ]);
+        let upgradable_packages = upgradable_packages(&database, &repositories);
+        let newest_app = package("app", "1.2.0", Some(("library", "^1")));
+        assert_eq!(
+            upgradable_packages,
+            vec![newest_app.clone()].into_iter().collect()
+        );
+        let installations = plan_installations(
+            vec![newest_app],
+            &HashSet::new(),
+            &HashMap::new(),
+            &repositories,
+            &database,
+        )
+        .unwrap();
+        let plan = TransactionPlan::new(installations, Vec::new(), &database);
+        assert_eq!(plan.changes.len(), 1);
+        assert_eq!(plan.changes[0].kind, ChangeKind::Upgrade);
+        assert_eq!(
+            plan.changes[0].installed_version,
+            Some(Version::new(1, 0, 0))
+        );
+        assert_eq!(plan.changes[0].installed_explicit, Some(true));
+        assert!(matches!(
+            plan.installations[0].source,
+            PackageSource::Repository(_)
+        ));
+    }
+
+    #[test]
+    fn removals_are_refused_while_required() {
+        let mut database = Database::default();
+        install(
+            &mut database,
+            package("app", "1.0.0", Some(("library", "^1"))),
+            true,
+            Vec::new(),
+        );
+        install(
+            &mut database,
+            package("library", "1.0.0", None),
+            false,
+            Vec::new(),
+        );
+        assert!(removal_plan(&["missing".to_owned()], &database).is_err());
+        assert!(removal_plan(&["library".to_owned()], &database).is_err());
+        let plan = removal_plan(&["app".to_owned(), "library".to_owned()], &database).unwrap();
+        assert_eq!(plan.removals, vec!["app".to_owned(), "library".to_owned()]);
+        assert!(plan
+            .changes
+            .iter()
+            .all(|change| change.kind == ChangeKind::Remove && change.version.is_none()));
+    }
+}
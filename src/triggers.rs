@@ -0,0 +1,202 @@
+use crate::error::TriggerError;
+use crate::scripts::{FailurePolicy, SCRIPT_PATH, SCRIPT_SHELL};
+use miette::Result;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// The directory holding the triggers configured on this system, each in its own YAML file
+pub const TRIGGERS_DIRECTORY: &str = "/etc/gany/triggers";
+
+/// A command run once at the end of any transaction touching paths it is interested in
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct Trigger {
+    /// The name of the trigger, taken from the name of its file
+    #[serde(skip)]
+    pub name: String,
+    /// Glob patterns matching the installed paths the trigger is interested in
+    pub paths: Vec<String>,
+    /// The glob patterns, compiled when the trigger is read
+    #[serde(skip)]
+    pub patterns: Vec<glob::Pattern>,
+    /// The shell command run when the trigger is activated
+    pub command: String,
+    /// What happens when the command fails
+    #[serde(default)]
+    pub on_failure: FailurePolicy,
+}
+
+impl Trigger {
+    /// Finds the paths touched by a transaction that this trigger is interested in
+    ///
+    /// # Arguments
+    ///
+    /// * `touched_paths` - The installed paths touched by a transaction (required)
+    pub fn matching_paths<'a>(&self, touched_paths: &'a [PathBuf]) -> Vec<&'a PathBuf> {
+        touched_paths
+            .iter()
+            .filter(|path| {
+                self.patterns
+                    .iter()
+                    .any(|pattern| pattern.matches_path(path))
+            })
+            .collect()
+    }
+}
+
+/// A trigger that failed to run or did not succeed
+#[derive(Eq, PartialEq, Clone, Debug, Serialize)]
+pub struct TriggerFailure {
+    /// The name of the trigger
+    pub trigger: String,
+    /// Whether the command could be started at all
+    pub started: bool,
+    /// The exit code of the command, if it exited rather than being killed
+    pub exit_code: Option<i32>,
+}
+
+impl fmt::Display for TriggerFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.started {
+            return write!(f, "the '{}' trigger could not be run", self.trigger);
+        }
+        match self.exit_code {
+            Some(exit_code) => write!(
+                f,
+                "the '{}' trigger exited with code {}",
+                self.trigger, exit_code
+            ),
+            None => write!(f, "the '{}' trigger was terminated", self.trigger),
+        }
+    }
+}
+
+/// Reads the triggers configured on this system, ordered by name
+///
+/// Every trigger and its path patterns are checked as they are read, so that a transaction can refuse invalid triggers before it changes anything.
+/// If no triggers have been configured, none are returned.
+pub fn read_triggers() -> Result<Vec<Trigger>> {
+    let trigger_pattern = Path::new(TRIGGERS_DIRECTORY).join("*.yaml");
+    let trigger_paths = match glob::glob(&trigger_pattern.to_string_lossy()) {
+        Ok(trigger_paths) => trigger_paths,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let mut triggers: Vec<Trigger> = Vec::new();
+    for trigger_path in trigger_paths.flatten() {
+        let trigger_file = fs::read(&trigger_path)
+            .map_err(|_| TriggerError::UnableToReadTrigger(trigger_path.clone()))?;
+        let mut trigger: Trigger = serde_yaml::from_slice(&trigger_file)
+            .map_err(|_| TriggerError::UnableToDeserialiseTrigger(trigger_path.clone()))?;
+        trigger.name = trigger_path
+            .file_stem()
+            .map(|file_stem| file_stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        trigger.patterns = trigger
+            .paths
+            .iter()
+            .map(|pattern| {
+                glob::Pattern::new(pattern).map_err(|_| {
+                    TriggerError::InvalidPattern(trigger.name.clone(), pattern.clone())
+                })
+            })
+            .collect::<Result<Vec<glob::Pattern>, TriggerError>>()?;
+        triggers.push(trigger);
+    }
+    triggers.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(triggers)
+}
+
+/// Runs a trigger once, passing it the paths it matched
+///
+/// The command is run by `/bin/sh` in the directory packages are installed into, with an empty environment aside from `GANY_TRIGGER` and `GANY_ROOT`.
+/// The matched paths are written to its standard input, one per line.
+///
+/// # Arguments
+///
+/// * `trigger` - The trigger being run (required)
+///
+/// * `matching_paths` - The paths touched by the transaction that the trigger is interested in (required)
+///
+/// * `root` - The directory packages are installed into (required)
+fn run_trigger(
+    trigger: &Trigger,
+    matching_paths: &[&PathBuf],
+    root: &Path,
+) -> Option<TriggerFailure> {
+    let unable_to_run = || TriggerFailure {
+        trigger: trigger.name.clone(),
+        started: false,
+        exit_code: None,
+    };
+    let mut child = match Command::new(SCRIPT_SHELL)
+        .arg("-e")
+        .arg("-c")
+        .arg(&trigger.command)
+        .current_dir(root)
+        .env_clear()
+        .env("PATH", SCRIPT_PATH)
+        .env("GANY_TRIGGER", &trigger.name)
+        .env("GANY_ROOT", root)
+        .stdin(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(_) => return Some(unable_to_run()),
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        // A command that does not read its input should not fail the trigger
+        for path in matching_paths {
+            if writeln!(stdin, "{}", path.display()).is_err() {
+                break;
+            }
+        }
+    }
+    match child.wait() {
+        Ok(status) if status.success() => None,
+        Ok(status) => Some(TriggerFailure {
+            trigger: trigger.name.clone(),
+            started: true,
+            exit_code: status.code(),
+        }),
+        Err(_) => Some(unable_to_run()),
+    }
+}
+
+/// Runs every trigger interested in the paths touched by a committed transaction, each once
+///
+/// The transaction can no longer be stopped, so failing triggers are only reported.
+/// A trigger whose failure policy is to abort stops the triggers after it from running.
+/// Returns the names of the triggers run, and the failures of those that failed.
+///
+/// # Arguments
+///
+/// * `triggers` - The triggers configured on this system, read before the transaction was committed (required)
+///
+/// * `touched_paths` - The installed paths touched by the transaction (required)
+///
+/// * `root` - The directory packages are installed into (required)
+pub fn run_triggers(
+    triggers: &[Trigger],
+    touched_paths: &[PathBuf],
+    root: &Path,
+) -> (Vec<String>, Vec<TriggerFailure>) {
+    let mut triggers_run: Vec<String> = Vec::new();
+    let mut trigger_failures: Vec<TriggerFailure> = Vec::new();
+    for trigger in triggers {
+        let matching_paths = trigger.matching_paths(touched_paths);
+        if matching_paths.is_empty() {
+            continue;
+        }
+        triggers_run.push(trigger.name.clone());
+        if let Some(trigger_failure) = run_trigger(trigger, &matching_paths, root) {
+            trigger_failures.push(trigger_failure);
+            if trigger.on_failure == FailurePolicy::Abort {
+                break;
+            }
+        }
+    }
+    (triggers_run, trigger_failures)
+}
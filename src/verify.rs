@@ -0,0 +1,330 @@
+use crate::archive::{self, EntryKind, FileEntry};
+use crate::attributes::{self, FileAttributes};
+use crate::config;
+use crate::database::{self, InstalledPackage};
+use crate::error::QueryError;
+use crate::journal;
+use miette::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A way in which an installed file differs from what its package recorded
+#[derive(Eq, PartialEq, Clone, Debug, Serialize)]
+pub enum Problem {
+    /// The file is not present
+    Missing,
+    /// The file is a different kind of filesystem entry
+    KindMismatch { expected: EntryKind },
+    /// The contents of the file differ
+    Modified,
+    /// The size of the file differs
+    SizeMismatch { expected: u64, actual: u64 },
+    /// The permission bits of the file differ
+    ModeMismatch { expected: u32, actual: u32 },
+    /// The user owning the file differs
+    OwnerMismatch { expected: u32, actual: u32 },
+    /// The group owning the file differs
+    GroupMismatch { expected: u32, actual: u32 },
+    /// An extended attribute the package specifies is missing or differs
+    XattrMismatch { name: String },
+    /// The file capabilities differ from those the package specifies
+    CapabilitiesMismatch { expected: String },
+    /// The symbolic link points elsewhere
+    TargetMismatch {
+        expected: PathBuf,
+        actual: Option<PathBuf>,
+    },
+    /// The file is in a directory owned by the package, but is not owned by any package
+    Extra,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Missing => write!(f, "missing"),
+            Problem::KindMismatch { expected } => write!(f, "expected a {:?}", expected),
+            Problem::Modified => write!(f, "contents modified"),
+            Problem::SizeMismatch { expected, actual } => {
+                write!(f, "size is {} bytes, expected {}", actual, expected)
+            }
+            Problem::ModeMismatch { expected, actual } => {
+                write!(f, "mode is {:04o}, expected {:04o}", actual, expected)
+            }
+            Problem::OwnerMismatch { expected, actual } => {
+                write!(f, "owned by user {}, expected {}", actual, expected)
+            }
+            Problem::GroupMismatch { expected, actual } => {
+                write!(f, "owned by group {}, expected {}", actual, expected)
+            }
+            Problem::XattrMismatch { name } => write!(f, "extended attribute '{}' differs", name),
+            Problem::CapabilitiesMismatch { expected } => {
+                write!(f, "capabilities differ, expected '{}'", expected)
+            }
+            Problem::TargetMismatch { expected, actual } => match actual {
+                Some(actual) => write!(
+                    f,
+                    "points to {}, expected {}",
+                    actual.display(),
+                    expected.display()
+                ),
+                None => write!(f, "points nowhere, expected {}", expected.display()),
+            },
+            Problem::Extra => write!(f, "not owned by any package"),
+        }
+    }
+}
+
+/// A problem with one installed file
+#[derive(Eq, PartialEq, Clone, Debug, Serialize)]
+pub struct FileProblem {
+    /// The installed path of the file
+    pub path: PathBuf,
+    /// Whether the file is a configuration file, which administrators are expected to modify
+    pub config: bool,
+    /// How the file differs
+    pub problem: Problem,
+}
+
+impl FileProblem {
+    /// Whether the problem is an administrator's change to a configuration file, which does not make a package less than intact
+    pub fn is_config_change(&self) -> bool {
+        self.config
+            && matches!(
+                self.problem,
+                Problem::Modified | Problem::SizeMismatch { .. }
+            )
+    }
+}
+
+/// The result of verifying an installed package
+#[derive(Eq, PartialEq, Clone, Debug, Serialize)]
+pub struct PackageVerification {
+    /// The name of the package
+    pub name: String,
+    /// The installed version of the package
+    pub version: semver::Version,
+    /// The problems found with the package's files
+    pub problems: Vec<FileProblem>,
+}
+
+impl PackageVerification {
+    /// Whether every file of the package is as it was recorded, aside from changes to the contents of configuration files
+    pub fn is_intact(&self) -> bool {
+        self.problems.iter().all(FileProblem::is_config_change)
+    }
+}
+
+/// Determines the permission bits and ownership of a filesystem entry
+#[cfg(unix)]
+fn mode_and_ownership(metadata: &fs::Metadata) -> (Option<u32>, Option<u32>, Option<u32>) {
+    use std::os::unix::fs::MetadataExt;
+    (
+        archive::entry_mode(metadata),
+        Some(metadata.uid()),
+        Some(metadata.gid()),
+    )
+}
+
+/// Determines the permission bits and ownership of a filesystem entry
+#[cfg(not(unix))]
+fn mode_and_ownership(metadata: &fs::Metadata) -> (Option<u32>, Option<u32>, Option<u32>) {
+    (archive::entry_mode(metadata), None, None)
+}
+
+/// Finds the ways an installed filesystem entry differs from what its package recorded
+///
+/// # Arguments
+///
+/// * `entry` - The filesystem entry as recorded by its package (required)
+///
+/// * `file_attributes` - The attributes the package specifies for the entry (optional)
+///
+/// * `root` - The directory packages are installed into (required)
+pub fn verify_entry(
+    entry: &FileEntry,
+    file_attributes: Option<&FileAttributes>,
+    root: &Path,
+) -> Vec<Problem> {
+    let path = root.join(entry.path.strip_prefix("/").unwrap_or(&entry.path));
+    let metadata = match fs::symlink_metadata(&path) {
+        Ok(metadata) => metadata,
+        Err(_) => return vec![Problem::Missing],
+    };
+    let kind = if metadata.file_type().is_symlink() {
+        EntryKind::Symlink
+    } else if metadata.is_dir() {
+        EntryKind::Directory
+    } else {
+        EntryKind::File
+    };
+    if kind != entry.kind {
+        return vec![Problem::KindMismatch {
+            expected: entry.kind,
+        }];
+    }
+    let mut problems: Vec<Problem> = Vec::new();
+    match entry.kind {
+        EntryKind::File => {
+            if metadata.len() != entry.size {
+                problems.push(Problem::SizeMismatch {
+                    expected: entry.size,
+                    actual: metadata.len(),
+                });
+            }
+            if let Some(expected_digest) = &entry.digest {
+                let digest = fs::read(&path)
+                    .ok()
+                    .map(|contents| archive::hash_data(&contents));
+                if digest.as_ref() != Some(expected_digest) {
+                    problems.push(Problem::Modified);
+                }
+            }
+        }
+        EntryKind::Symlink => {
+            if let Some(expected_target) = &entry.target {
+                let target = fs::read_link(&path).ok();
+                if target.as_ref() != Some(expected_target) {
+                    problems.push(Problem::TargetMismatch {
+                        expected: expected_target.clone(),
+                        actual: target,
+                    });
+                }
+            }
+        }
+        EntryKind::Directory => (),
+    }
+    let (mode, uid, gid) = mode_and_ownership(&metadata);
+    if let (Some(expected), Some(actual)) = (entry.mode, mode) {
+        if expected != actual {
+            problems.push(Problem::ModeMismatch { expected, actual });
+        }
+    }
+    if let (Some(expected), Some(actual)) = (entry.uid, uid) {
+        if expected != actual {
+            problems.push(Problem::OwnerMismatch { expected, actual });
+        }
+    }
+    if let (Some(expected), Some(actual)) = (entry.gid, gid) {
+        if expected != actual {
+            problems.push(Problem::GroupMismatch { expected, actual });
+        }
+    }
+    // Links have no attributes of their own
+    if let Some(file_attributes) = file_attributes.filter(|_| entry.kind != EntryKind::Symlink) {
+        for (name, value) in file_attributes.xattrs.iter().flatten() {
+            if attributes::read_xattr(&path, name).as_ref() != Some(&value.0) {
+                problems.push(Problem::XattrMismatch { name: name.clone() });
+            }
+        }
+        if let Some(capabilities) = &file_attributes.capabilities {
+            let expected = attributes::encode_capabilities(capabilities).ok();
+            if attributes::read_xattr(&path, attributes::CAPABILITY_XATTR) != expected {
+                problems.push(Problem::CapabilitiesMismatch {
+                    expected: capabilities.clone(),
+                });
+            }
+        }
+    }
+    problems
+}
+
+/// Determines whether a file left in a package's directory is expected to be there
+///
+/// New versions of configuration files, and files staged by an interrupted transaction, are not reported as extra.
+///
+/// # Arguments
+///
+/// * `path` - The installed path of the file (required)
+fn is_expected_leftover(path: &Path) -> bool {
+    let file_name = path
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    file_name.ends_with(config::CONFIG_NEW_SUFFIX) || file_name.ends_with(journal::STAGING_SUFFIX)
+}
+
+/// Verifies the files of an installed package against what the package recorded
+///
+/// # Arguments
+///
+/// * `installed_package` - An installed package (required)
+///
+/// * `file_index` - The packages owning each installed path (required)
+///
+/// * `root` - The directory packages are installed into (required)
+fn verify_package(
+    installed_package: &InstalledPackage,
+    file_index: &BTreeMap<PathBuf, Vec<&InstalledPackage>>,
+    root: &Path,
+) -> PackageVerification {
+    let config_files = installed_package
+        .package
+        .config_files
+        .clone()
+        .unwrap_or_default();
+    let mut problems: Vec<FileProblem> = Vec::new();
+    for entry in &installed_package.entries {
+        let file_attributes = attributes::file_attributes(&installed_package.package, &entry.path);
+        for problem in verify_entry(entry, file_attributes, root) {
+            problems.push(FileProblem {
+                path: entry.path.clone(),
+                config: config_files.contains(&entry.path),
+                problem,
+            });
+        }
+        if entry.kind != EntryKind::Directory {
+            continue;
+        }
+        let directory = root.join(entry.path.strip_prefix("/").unwrap_or(&entry.path));
+        let mut children: Vec<PathBuf> = match fs::read_dir(&directory) {
+            Ok(children) => children
+                .filter_map(|child| child.ok())
+                .map(|child| entry.path.join(child.file_name()))
+                .collect(),
+            Err(_) => continue,
+        };
+        children.sort();
+        for child in children {
+            if !file_index.contains_key(&child) && !is_expected_leftover(&child) {
+                problems.push(FileProblem {
+                    path: child,
+                    config: false,
+                    problem: Problem::Extra,
+                });
+            }
+        }
+    }
+    PackageVerification {
+        name: installed_package.package.name.clone(),
+        version: installed_package.package.version.clone(),
+        problems,
+    }
+}
+
+/// Verifies the files of installed packages against the digests, sizes, modes, ownership, capabilities and extended attributes their packages recorded
+///
+/// Files found in directories owned by a package, but not owned by any package, are reported as extra.
+///
+/// # Arguments
+///
+/// * `name` - The name of an installed package, to only verify it (optional)
+///
+/// * `root` - The directory packages are installed into (required)
+pub fn verify_installed(name: Option<&str>, root: &Path) -> Result<Vec<PackageVerification>> {
+    let database = database::read_database()?;
+    if let Some(name) = name {
+        if !database.packages.contains_key(name) {
+            return Err(QueryError::NotInstalled(name.to_owned()).into());
+        }
+    }
+    let file_index = database.file_index();
+    Ok(database
+        .packages
+        .values()
+        .filter(|installed_package| name.is_none_or(|name| installed_package.package.name == name))
+        .map(|installed_package| verify_package(installed_package, &file_index, root))
+        .collect())
+}